@@ -1,9 +1,10 @@
 use std::{
+    borrow::Cow,
     collections::HashMap,
     fmt::Debug,
     fs::{self},
     ops,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 /// A single change to a file
@@ -57,8 +58,13 @@ impl FileChangeSet {
 
         // Do in-file ordering for each file
         for (file, patches) in change_sets.iter_mut() {
-            // Do a stable sort so we preserve order if it matters
-            patches.sort_by_key(|patch| patch.location.start);
+            // Do a stable sort so we preserve order if it matters.
+            // Insertions go before the replacements starting at the same position.
+            patches.sort_by_key(|patch| (patch.location.start, patch.location.end));
+
+            // Identical patches can arise when multiple spans map to the same location,
+            // e.g. a macro call site. Applying them once is the intended result.
+            patches.dedup_by(|a, b| a.location == b.location && a.bytes == b.bytes);
 
             // // Correct offsets
             // let mut displacement: isize = 0;
@@ -69,7 +75,8 @@ impl FileChangeSet {
             //     displacement += patch.bytes.len() as isize - patch.location.len() as isize;
             // }
 
-            for [a, b] in patches.array_windows() {
+            for pair in patches.windows(2) {
+                let (a, b) = (&pair[0], &pair[1]);
                 assert!(
                     a.location.end <= b.location.start,
                     "Overlapping patches are not allowed: {:?} {:?} {:?}",
@@ -85,14 +92,133 @@ impl FileChangeSet {
             .collect()
     }
 
-    /// Actually write the changes to the file
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    /// Replacement bytes of the changes, using the line endings of the original file
+    fn replacements(&self, original: &[u8]) -> Vec<Cow<'_, [u8]>> {
+        let crlf = uses_crlf(original);
+        self.changes
+            .iter()
+            .map(|change| match crlf {
+                true => Cow::Owned(to_crlf(&change.bytes)),
+                false => Cow::Borrowed(&change.bytes[..]),
+            })
+            .collect()
+    }
+
+    /// Apply the changes to the original contents of the file.
+    /// The result is assembled from pieces of the original and the replacements
+    /// in a single pass, so the cost doesn't grow with the number of changes.
+    /// Line breaks in the replacements are converted to CRLF if the file mostly uses them.
+    pub fn apply(&self, original: &[u8]) -> Vec<u8> {
+        let replacements = self.replacements(original);
+        let size: isize = self
+            .changes
+            .iter()
+            .zip(&replacements)
+            .map(|(change, bytes)| bytes.len() as isize - change.location.len() as isize)
+            .sum();
+        let mut buffer = Vec::with_capacity((original.len() as isize + size).max(0) as usize);
+        let mut position = 0;
+        for (change, bytes) in self.changes.iter().zip(&replacements) {
+            buffer.extend_from_slice(&original[position..change.location.start]);
+            buffer.extend_from_slice(bytes);
+            position = change.location.end;
+        }
+        buffer.extend_from_slice(&original[position..]);
+        buffer
+    }
+
+    /// Lines touched by the changes after applying them, 1-based and inclusive
+    pub fn changed_lines(&self, original: &[u8]) -> Vec<(usize, usize)> {
+        let new = self.apply(original);
+        let mut displacement: isize = 0;
+        self.changes
+            .iter()
+            .zip(self.replacements(original))
+            .map(|(patch, bytes)| {
+                let start = (patch.location.start as isize + displacement) as usize;
+                let end = start + bytes.len();
+                displacement += bytes.len() as isize - patch.location.len() as isize;
+                let first = new[..start].iter().filter(|b| **b == b'\n').count() + 1;
+                let last = first + new[start..end].iter().filter(|b| **b == b'\n').count();
+                (first, last)
+            })
+            .collect()
+    }
+
+    /// Changes turning the changed contents back into the original ones
+    pub fn inverse(&self, original: &[u8]) -> FileChangeSet {
+        let mut displacement: isize = 0;
+        let changes = self
+            .changes
+            .iter()
+            .zip(self.replacements(original))
+            .map(|(patch, bytes)| {
+                let start = (patch.location.start as isize + displacement) as usize;
+                displacement += bytes.len() as isize - patch.location.len() as isize;
+                Patch {
+                    location: start..start + bytes.len(),
+                    bytes: original[patch.location.clone()].to_vec(),
+                }
+            })
+            .collect();
+        FileChangeSet {
+            file: self.file.clone(),
+            changes,
+        }
+    }
+
+    pub fn patches(&self) -> &[Patch] {
+        &self.changes
+    }
+
+    /// Make sure the changes can be applied to the original contents without corrupting them.
+    /// The offsets given by rustc are for UTF-8 text, so a file that isn't, or that has
+    /// changed so that the changes no longer fall on character boundaries, is refused.
+    pub fn check(&self, original: &[u8]) -> Result<(), String> {
+        let text = std::str::from_utf8(original)
+            .map_err(|err| format!("not valid UTF-8 at byte {}", err.valid_up_to()))?;
+        for change in &self.changes {
+            let ops::Range { start, end } = change.location;
+            if end > text.len() || !text.is_char_boundary(start) || !text.is_char_boundary(end) {
+                return Err(format!(
+                    "change at bytes {}..{} is not inside the text",
+                    start, end
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Actually write the changes to the file, unless they can't be applied cleanly
     pub fn write(self) -> std::io::Result<()> {
-        let mut buffer = fs::read(&self.file)?;
-        for change in self.changes.into_iter().rev() {
-            buffer.splice(change.location, change.bytes);
+        let buffer = fs::read(&self.file)?;
+        self.check(&buffer)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        fs::write(&self.file, self.apply(&buffer))
+    }
+}
+
+/// Whether most line breaks of the text are CRLF
+fn uses_crlf(text: &[u8]) -> bool {
+    let lines = text.iter().filter(|b| **b == b'\n').count();
+    let crlf = text.windows(2).filter(|w| *w == b"\r\n").count();
+    crlf * 2 > lines
+}
+
+/// Convert LF line breaks to CRLF, leaving existing CRLF ones as-is
+fn to_crlf(bytes: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(bytes.len());
+    for (i, b) in bytes.iter().enumerate() {
+        if *b == b'\n' && (i == 0 || bytes[i - 1] != b'\r') {
+            converted.push(b'\r');
         }
-        fs::write(self.file, buffer)
+        converted.push(*b);
     }
+    converted
 }
 
 #[cfg(test)]
@@ -172,4 +298,79 @@ mod tests {
             assert_eq!(fs::read(tmp.path()).unwrap(), b"H??!!o, there!");
         }
     }
+
+    #[test]
+    fn test_changed_lines() {
+        let file = PathBuf::from("test.rs");
+        let change = |location, bytes: &[u8]| Change {
+            file: file.clone(),
+            patch: Patch {
+                location,
+                bytes: bytes.to_vec(),
+            },
+        };
+
+        let grouped = FileChangeSet::group(vec![
+            change(0..1, b"a\nb"),
+            change(4..5, b"c"),
+            change(8..9, b""),
+        ]);
+        assert_eq!(
+            grouped[0].changed_lines(b"x\ny\nz\nw\nv\n"),
+            vec![(1, 2), (4, 4), (6, 6)]
+        );
+
+        let original = b"x\ny\nz\nw\nv\n";
+        let new = grouped[0].apply(original);
+        assert_eq!(grouped[0].inverse(original).apply(&new), original);
+    }
+
+    #[test]
+    fn test_duplicate_changes() {
+        let tmp = NamedTempFile::new().unwrap();
+        fs::write(tmp.path(), b"Hello, world!").unwrap();
+
+        let change = Change {
+            file: tmp.path().to_owned(),
+            patch: Patch {
+                location: 7..12,
+                bytes: b"there".to_vec(),
+            },
+        };
+
+        let grouped = FileChangeSet::group(vec![change.clone(), change]);
+        assert!(grouped.len() == 1);
+        grouped[0].clone().write().expect("Unable to write");
+        assert_eq!(fs::read(tmp.path()).unwrap(), b"Hello, there!");
+    }
+
+    #[test]
+    fn test_crlf() {
+        let file = PathBuf::from("test.rs");
+        let grouped = FileChangeSet::group(vec![Change {
+            file,
+            patch: Patch {
+                location: 3..4,
+                bytes: b"b\nc\r\nd".to_vec(),
+            },
+        }]);
+        assert_eq!(grouped[0].apply(b"x\r\na\r\n"), b"x\r\nb\r\nc\r\nd\r\n");
+        assert_eq!(grouped[0].changed_lines(b"x\r\na\r\n"), vec![(2, 4)]);
+        assert_eq!(grouped[0].apply(b"x\na\n"), b"x\nab\nc\r\nd");
+    }
+
+    #[test]
+    fn test_check() {
+        let grouped = FileChangeSet::group(vec![Change {
+            file: PathBuf::from("test.rs"),
+            patch: Patch {
+                location: 3..4,
+                bytes: b"x".to_vec(),
+            },
+        }]);
+        assert!(grouped[0].check(b"\xef\xbb\xbfab").is_ok());
+        assert!(grouped[0].check(b"ab\xe9cd").is_err());
+        assert!(grouped[0].check("ab\u{e9}".as_bytes()).is_err());
+        assert!(grouped[0].check(b"ab").is_err());
+    }
 }
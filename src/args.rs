@@ -1,30 +1,307 @@
-use std::ffi::OsString;
+use std::{ffi::OsString, path::PathBuf};
 
-use clap::Parser;
+use clap::{ColorChoice, Parser};
+use globset::{Glob, GlobSet, GlobSetBuilder};
 
-use crate::{operation::Operation, selector::Selector};
+use crate::{
+    diff::DiffStyle,
+    git,
+    list::{GroupBy, ListDetail, ListFormat},
+    message::{ShowRendered, TargetKind},
+    operation::Operation,
+    report::ReportTarget,
+    rustfmt::FmtScope,
+    selector::Selector,
+};
 
 /// Automation helper to fix rust errors and warnings
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_override_self = true)]
 pub struct Args {
     /// Allow applying fixes to a uncommitted working tree
     #[arg(short = 'd', long)]
     pub allow_dirty: bool,
 
     /// Stop after first match
-    #[arg(short, long)]
+    #[arg(short, long, conflicts_with = "limit")]
     pub single: bool,
 
+    /// Stop after processing this many matches
+    #[arg(long, value_name = "N")]
+    pub limit: Option<usize>,
+
+    /// Process at most this many matches in each file
+    #[arg(long, value_name = "N")]
+    pub limit_per_file: Option<usize>,
+
+    /// Only process diagnostic codes with at least this many matches,
+    /// e.g. to find the cleanups that fix the most at once
+    #[arg(long, value_name = "N")]
+    pub min_count: Option<usize>,
+
+    /// Process a random sample of this many matches, e.g. to try out the operations
+    /// before processing all of them
+    #[arg(long, value_name = "N")]
+    pub sample: Option<usize>,
+
+    /// Seed for choosing the sample. The same seed picks the same matches
+    #[arg(long, value_name = "SEED", default_value_t = 0, requires = "sample")]
+    pub seed: u64,
+
+    /// Skip this many matches before processing any
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub skip: usize,
+
     /// Actually apply changes instead of just previewing
     #[arg(long)]
     pub write: bool,
 
+    /// Apply changes to the git index instead of the working tree
+    #[arg(long, conflicts_with_all = ["write", "edit"])]
+    pub stage: bool,
+
+    /// Commit the written changes. The message template can use `{count}`, `{code}` and `{selector}`
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = git::DEFAULT_TEMPLATE,
+        requires = "write"
+    )]
+    pub commit: Option<String>,
+
+    /// With `--commit`, make a separate commit for each diagnostic code
+    #[arg(long, requires = "commit")]
+    pub commit_per_code: bool,
+
+    /// Run rustfmt on the written files, or only on the changed lines
+    #[arg(
+        long,
+        value_enum,
+        value_name = "SCOPE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "files",
+        requires = "write"
+    )]
+    pub fmt: Option<FmtScope>,
+
+    /// Check the project again after writing, and restore the files if new errors appear
+    #[arg(long, requires = "write", conflicts_with = "commit")]
+    pub verify: bool,
+
+    /// Check that the span of each change matches the file, and skip the changes that
+    /// would land in the wrong place. Always done with `--write` and `--stage`
+    #[arg(long)]
+    pub verify_patches: bool,
+
+    /// Run this shell command after writing, and restore the files if it fails
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        requires = "write",
+        conflicts_with = "commit"
+    )]
+    pub test_cmd: Option<String>,
+
+    /// For diagnostics about missing crates, add them to the dependencies with `cargo add`
+    /// instead of applying operations. Only done with `--write`.
+    #[arg(long)]
+    pub add_deps: bool,
+
+    /// Show more output, can be repeated
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Only show the final summary
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// When to use colors. `auto` disables them if `NO_COLOR` is set or output is not a terminal
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Print how many lines the changes insert and delete in each file, like `git diff --stat`.
+    /// With `--quiet`, instead of the previews
+    #[arg(long)]
+    pub stat: bool,
+
+    /// Show the previews grouped by file after all changes are computed, instead of in the
+    /// order of the diagnostics. On a terminal, each file can be shown or collapsed
+    #[arg(long)]
+    pub preview_by_file: bool,
+
+    /// How to show the changes in previews
+    #[arg(long, value_enum, default_value_t = DiffStyle::Inline)]
+    pub diff_style: DiffStyle,
+
+    /// Show the compiler diagnostic along with each proposed change, as the compiler renders it
+    #[arg(long, value_enum, value_name = "STYLE", default_value_t = ShowRendered::Off)]
+    pub show_rendered: ShowRendered,
+
+    /// Same as `--show-rendered plain`
+    #[arg(long, conflicts_with = "show_rendered")]
+    pub show_diagnostic: bool,
+
+    /// Review the changes in a full-screen interface instead of previewing them
+    #[cfg(feature = "tui")]
+    #[arg(long)]
+    pub tui: bool,
+
+    /// Open each match in `$EDITOR` to fix it by hand, instead of applying operations
+    #[arg(long, conflicts_with = "write")]
+    pub edit: bool,
+
+    /// Don't take the lock that keeps concurrent runs in the workspace from interleaving
+    /// their writes
+    #[arg(long)]
+    pub no_lock: bool,
+
+    /// Run again whenever files in the current directory change. Also available as `watch` subcommand
+    #[arg(long, conflicts_with = "input")]
+    pub watch: bool,
+
+    /// Do not show previews in a pager
+    #[arg(long)]
+    pub no_pager: bool,
+
     /// Run clippy in addition to check
     #[arg(short, long)]
     pub clippy: bool,
 
-    /// Selector for issue category to fix
+    /// Only fix issues in the given workspace member, as `name` or `name@version`.
+    /// Also forwarded to cargo
+    #[arg(
+        short,
+        long = "package",
+        value_name = "SPEC",
+        conflicts_with = "workspace"
+    )]
+    pub packages: Vec<String>,
+
+    /// Fix issues in all workspace members. Also forwarded to cargo
+    #[arg(long)]
+    pub workspace: bool,
+
+    /// Also fix issues in packages outside the workspace, such as path dependencies
+    #[arg(long)]
+    pub include_deps: bool,
+
+    /// Only fix issues in targets of this kind. Can be repeated.
+    /// Unit tests count as the target they are in, e.g. `lib`
+    #[arg(long = "target-kind", value_enum, value_name = "KIND")]
+    pub target_kinds: Vec<TargetKind>,
+
+    /// Features to activate, space or comma separated. Forwarded to cargo
+    #[arg(short = 'F', long, value_name = "FEATURES")]
+    pub features: Vec<String>,
+
+    /// Activate all features. Forwarded to cargo
+    #[arg(long)]
+    pub all_features: bool,
+
+    /// Don't activate the default features. Forwarded to cargo
+    #[arg(long)]
+    pub no_default_features: bool,
+
+    /// Check test targets too. Forwarded to cargo
+    #[arg(long)]
+    pub tests: bool,
+
+    /// Check examples too. Forwarded to cargo
+    #[arg(long)]
+    pub examples: bool,
+
+    /// Check benchmarks too. Forwarded to cargo
+    #[arg(long)]
+    pub benches: bool,
+
+    /// Check with the release profile. Forwarded to cargo
+    #[arg(long)]
+    pub release: bool,
+
+    /// Change to the directory before doing anything, like `cargo -C`
+    #[arg(short = 'C', long = "directory", value_name = "DIR")]
+    pub directory: Option<PathBuf>,
+
+    /// Path to the Cargo.toml of the package to check. Also forwarded to cargo.
+    /// Files are changed relative to its workspace root, as if refix was run there
+    #[arg(long, value_name = "PATH")]
+    pub manifest_path: Option<PathBuf>,
+
+    /// Directory for the build artifacts of the check, given as `--target-dir=DIR`,
+    /// or `target/refix` with just `--target-dir`.
+    /// Keeps the normal target directory from being rebuilt when lint levels or clippy change
+    #[arg(
+        long,
+        value_name = "DIR",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "target/refix"
+    )]
+    pub target_dir: Option<PathBuf>,
+
+    /// Toolchain to run cargo with, e.g. `nightly` or `nightly-2024-06-01`, using rustup
+    #[arg(long, value_name = "NAME")]
+    pub toolchain: Option<String>,
+
+    /// Warn about the lint or lint group, e.g. `clippy::pedantic`. Can be repeated.
+    /// Lint levels are passed to the compiler in `RUSTFLAGS`, so changing them rebuilds the crates
+    #[arg(short = 'W', long = "warn", value_name = "LINT")]
+    pub warn: Vec<String>,
+
+    /// Deny the lint or lint group. Can be repeated
+    #[arg(short = 'D', long = "deny", value_name = "LINT")]
+    pub deny: Vec<String>,
+
+    /// Allow the lint or lint group. Can be repeated.
+    /// Allows are applied after warns and denies, so they can make exceptions to lint groups
+    #[arg(short = 'A', long = "allow", value_name = "LINT")]
+    pub allow: Vec<String>,
+
+    /// Never modify files matching this glob, e.g. `vendor/**`. Can be repeated.
+    /// Single lines are skipped with a `// refix:ignore` comment on them, or
+    /// `// refix:ignore-next-line` on the line before
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    pub exclude: Vec<Glob>,
+
+    /// Read diagnostics from a file (or `-` for stdin) instead of running cargo.
+    /// Accepts both cargo `--message-format=json` and rustc `--error-format=json` output
+    #[arg(long, value_name = "FILE|-")]
+    pub input: Option<PathBuf>,
+
+    /// Output format for list mode
+    #[arg(long, value_enum, default_value_t = ListFormat::Text)]
+    pub format: ListFormat,
+
+    /// What to group the summary by in list mode
+    #[arg(long, value_enum, default_value_t = GroupBy::Code)]
+    pub group_by: GroupBy,
+
+    /// Extra information to show per group in list mode
+    #[arg(long, value_enum)]
+    pub list_detail: Option<ListDetail>,
+
+    /// Apply the operations of a rule from the config file `refix.toml` instead of giving them.
+    /// See `cargo refix test` for testing the rules
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["recipe", "ops", "auto_only"])]
+    pub rule: Option<String>,
+
+    /// Explain each code in list mode, with the first line of rustc's explanation, the lint
+    /// description, or a link to the clippy documentation. See also `cargo refix explain`
+    #[arg(long)]
+    pub explain: bool,
+
+    /// Also write the proposed changes to a report, e.g. `html:refix.html`.
+    /// Without a path, the report is printed
+    #[arg(long, value_name = "FORMAT[:PATH]")]
+    pub report: Option<ReportTarget>,
+
+    /// Selector for issue category to fix: a lint name, an error code like `E0308`, `all`,
+    /// `uncoded`, `really-all`, `list`, or a preset: `preset:unused`, `preset:idioms`
+    /// or `preset:clippy-style` (with `--clippy`). Can be narrowed down with `:message=REGEX`,
+    /// and to the spans with a matching label with `:label=REGEX`
     pub selector: Selector,
 
     /// Operation to apply to the selected issues
@@ -35,3 +312,43 @@ pub struct Args {
     #[clap(last = true)]
     pub passthrough: Vec<OsString>,
 }
+
+impl Args {
+    /// How to show the diagnostics in previews, also given by `--show-diagnostic`
+    pub fn show_rendered(&self) -> ShowRendered {
+        match self.show_diagnostic {
+            true => ShowRendered::Plain,
+            false => self.show_rendered,
+        }
+    }
+
+    /// Changes are reviewed interactively instead of previewed
+    pub fn review(&self) -> bool {
+        #[cfg(feature = "tui")]
+        return self.tui;
+        #[cfg(not(feature = "tui"))]
+        false
+    }
+
+    /// Compiler flags setting the lint levels given with `-W`, `-D` and `-A`
+    pub fn lint_flags(&self) -> Vec<String> {
+        let levels = [("-W", &self.warn), ("-D", &self.deny), ("-A", &self.allow)];
+        levels
+            .into_iter()
+            .flat_map(|(flag, lints)| lints.iter().map(move |lint| format!("{} {}", flag, lint)))
+            .collect()
+    }
+
+    /// Matcher for files that must not be modified
+    pub fn excluded(&self) -> GlobSet {
+        let mut builder = GlobSetBuilder::new();
+        for glob in &self.exclude {
+            builder.add(glob.clone());
+        }
+        builder.build().expect("Unable to build exclude globs")
+    }
+}
+
+fn parse_glob(s: &str) -> Result<Glob, String> {
+    Glob::new(s).map_err(|err| err.to_string())
+}
@@ -0,0 +1,200 @@
+//! Running cargo check or clippy
+
+use std::{
+    collections::HashSet,
+    env,
+    ffi::OsString,
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command, Stdio},
+    thread::{self, JoinHandle},
+};
+
+use crate::{args::Args, log};
+
+/// JSON messages printed by a running cargo process, read as they arrive
+pub struct Lines {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+    /// Collects stderr in the background, so cargo doesn't block on a full pipe
+    stderr: Option<JoinHandle<String>>,
+    /// Whether cargo has printed anything, i.e. got to building the crates
+    printed: bool,
+}
+
+impl Iterator for Lines {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let mut line = Vec::new();
+        let read = self
+            .stdout
+            .read_until(b'\n', &mut line)
+            .expect("Unable to read cargo output");
+        if read == 0 {
+            if let Some(stderr) = self.stderr.take() {
+                let stderr = stderr.join().expect("Unable to read cargo errors");
+                let status = self.child.wait().expect("Unable to wait for cargo");
+                if !status.success() && !self.printed {
+                    // Failed before building anything, e.g. due to a missing toolchain
+                    eprintln!("cargo failed with {}:\n{}", status, stderr.trim_end());
+                } else {
+                    log::verbose!("{}", stderr);
+                }
+            }
+            return None;
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+        self.printed = true;
+        Some(line)
+    }
+}
+
+/// Cargo of the selected toolchain
+fn cargo(args: &Args) -> Command {
+    match &args.toolchain {
+        // The rustup proxy is needed for selecting the toolchain, while `CARGO` would point
+        // to the binary of the current toolchain
+        Some(toolchain) => {
+            let mut cmd = Command::new("cargo");
+            cmd.arg(format!("+{}", toolchain));
+            cmd
+        }
+        None => {
+            // Get path to the cargo binary
+            let cargo_bin = env::var_os("CARGO").unwrap_or(OsString::from("cargo"));
+            Command::new(cargo_bin)
+        }
+    }
+}
+
+fn command(args: &Args) -> Command {
+    let mut cmd = cargo(args);
+    if args.clippy {
+        cmd.arg("clippy");
+    } else {
+        cmd.arg("check");
+    }
+    // Colored for `--show-rendered ansi`, other uses of the rendered text strip the colors
+    cmd.arg("--message-format=json-diagnostic-rendered-ansi");
+    if let Some(path) = &args.manifest_path {
+        cmd.arg("--manifest-path").arg(path);
+    }
+    if let Some(dir) = &args.target_dir {
+        cmd.arg("--target-dir").arg(dir);
+    }
+    for spec in &args.packages {
+        cmd.arg("--package").arg(spec);
+    }
+    if args.workspace {
+        cmd.arg("--workspace");
+    }
+    for features in &args.features {
+        cmd.arg("--features").arg(features);
+    }
+    let flags = [
+        (args.all_features, "--all-features"),
+        (args.no_default_features, "--no-default-features"),
+        (args.tests, "--tests"),
+        (args.examples, "--examples"),
+        (args.benches, "--benches"),
+        (args.release, "--release"),
+    ];
+    for (_, flag) in flags.into_iter().filter(|(set, _)| *set) {
+        cmd.arg(flag);
+    }
+    cmd.args(&args.passthrough);
+
+    let lint_flags = args.lint_flags();
+    if !lint_flags.is_empty() {
+        // Keep the flags the user has set already, which the lint levels then override
+        let mut rustflags = env::var("RUSTFLAGS").unwrap_or_default();
+        for flag in lint_flags {
+            if !rustflags.is_empty() {
+                rustflags.push(' ');
+            }
+            rustflags.push_str(&flag);
+        }
+        cmd.env("RUSTFLAGS", rustflags);
+    }
+    cmd
+}
+
+/// Root directory of the workspace the manifest belongs to, which file names of spans are relative to
+pub fn workspace_root(manifest_path: &Path) -> io::Result<PathBuf> {
+    let cargo_bin = env::var_os("CARGO").unwrap_or(OsString::from("cargo"));
+    let output = Command::new(cargo_bin)
+        .args(["locate-project", "--workspace", "--message-format=plain"])
+        .arg("--manifest-path")
+        .arg(manifest_path)
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "cargo locate-project failed with {}",
+            output.status
+        )));
+    }
+    let manifest = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim_end());
+    Ok(manifest.parent().map(Path::to_path_buf).unwrap_or_default())
+}
+
+/// Package IDs of the workspace members, for telling them apart from dependencies
+pub fn workspace_members(args: &Args) -> io::Result<HashSet<String>> {
+    let mut cmd = cargo(args);
+    cmd.args(["metadata", "--no-deps", "--format-version=1"]);
+    if let Some(path) = &args.manifest_path {
+        cmd.arg("--manifest-path").arg(path);
+    }
+    let output = cmd.stderr(Stdio::piped()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "cargo metadata failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )));
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Metadata {
+        workspace_members: HashSet<String>,
+    }
+    let metadata: Metadata = serde_json::from_slice(&output.stdout)?;
+    Ok(metadata.workspace_members)
+}
+
+/// Start cargo check or clippy, streaming the JSON messages it prints
+pub fn spawn(args: &Args) -> Lines {
+    let mut child = command(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Unable to run cargo");
+
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut stderr_pipe = child.stderr.take().unwrap();
+    let stderr = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = stderr_pipe.read_to_end(&mut buffer);
+        String::from_utf8_lossy(&buffer).into_owned()
+    });
+
+    Lines {
+        child,
+        stdout,
+        stderr: Some(stderr),
+        printed: false,
+    }
+}
+
+/// Run cargo check or clippy, returning the JSON messages it printed
+pub fn run(args: &Args) -> Vec<u8> {
+    let mut output = Vec::new();
+    for line in spawn(args) {
+        output.extend_from_slice(&line);
+        output.push(b'\n');
+    }
+    output
+}
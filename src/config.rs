@@ -0,0 +1,42 @@
+//! Rules and settings from the config file
+
+use std::{fs, io, path::Path};
+
+/// Config file used by default, relative to the current directory
+pub const DEFAULT_PATH: &str = "refix.toml";
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+    /// Defaults for the command line options, see [`crate::settings`]
+    #[serde(default)]
+    pub settings: toml::Table,
+}
+
+/// A named operation sequence
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    pub ops: Vec<String>,
+    /// Examples of what the operations should do
+    #[serde(default, rename = "test")]
+    pub tests: Vec<RuleTest>,
+}
+
+/// Expected result of running a rule on some text
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleTest {
+    pub input: String,
+    /// Byte range selected at the start, e.g. `4..9`. Defaults to the whole input
+    pub highlight: Option<String>,
+    pub expected: String,
+}
+
+pub fn load(path: &Path) -> io::Result<Config> {
+    let text = fs::read_to_string(path)?;
+    toml::from_str(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
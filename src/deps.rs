@@ -0,0 +1,137 @@
+//! Adding dependencies for crates that rustc can't find
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    path::PathBuf,
+    process::Command,
+};
+
+use regex::Regex;
+
+use crate::message::CompilerMessage;
+
+/// Unresolved import, failed to resolve a path, and can't find crate
+const CODES: [&str; 3] = ["E0432", "E0433", "E0463"];
+
+/// Crates that come with the toolchain instead of being dependencies
+const BUILTIN: [&str; 5] = ["std", "core", "alloc", "proc_macro", "test"];
+
+/// Name of the crate the diagnostic says is missing, if any.
+/// Only explicit mentions of a crate count, as unresolved imports can also be typos of modules.
+fn missing_crate(message: &CompilerMessage) -> Option<String> {
+    if !CODES.contains(&message.code()?) {
+        return None;
+    }
+
+    let re = Regex::new(
+        r"(?:crate for|missing crate|unlinked crate|undeclared crate or module|undeclared crate) `([\w-]+)`",
+    )
+    .unwrap();
+    let texts = std::iter::once(&message.message)
+        .chain(message.children.iter().map(|child| &child.message))
+        .chain(message.spans.iter().filter_map(|span| span.label.as_ref()));
+    for text in texts {
+        if let Some(name) = re.captures(text).map(|c| c[1].to_owned()) {
+            return (!BUILTIN.contains(&name.as_str())).then_some(name);
+        }
+    }
+    None
+}
+
+/// Missing dependencies of each package, by manifest path
+#[derive(Debug, Default)]
+pub struct MissingDeps(BTreeMap<PathBuf, BTreeSet<String>>);
+
+impl MissingDeps {
+    /// Record the crate if the diagnostic is about a missing one, returning whether it was
+    pub fn add(&mut self, message: &CompilerMessage, manifest_path: &str) -> bool {
+        if manifest_path.is_empty() {
+            return false;
+        }
+        let Some(name) = missing_crate(message) else {
+            return false;
+        };
+        self.0
+            .entry(PathBuf::from(manifest_path))
+            .or_default()
+            .insert(name);
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn print(&self) {
+        println!("missing dependencies:");
+        for (manifest, crates) in &self.0 {
+            let crates: Vec<_> = crates.iter().map(String::as_str).collect();
+            println!(" {}: {}", manifest.display(), crates.join(", "));
+        }
+    }
+
+    /// Add the dependencies with `cargo add`
+    pub fn write(&self) -> io::Result<()> {
+        for (manifest, crates) in &self.0 {
+            let status = Command::new("cargo")
+                .arg("add")
+                .arg("--manifest-path")
+                .arg(manifest)
+                .args(crates)
+                .status()?;
+            if !status.success() {
+                println!("cargo add failed with {}", status);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::missing_crate;
+    use crate::message::CompilerMessage;
+
+    fn message(code: &str, text: &str, help: &str) -> CompilerMessage {
+        serde_json::from_value(serde_json::json!({
+            "code": { "code": code },
+            "level": "error",
+            "message": text,
+            "spans": [],
+            "children": [{
+                "code": null,
+                "level": "help",
+                "message": help,
+                "spans": [],
+                "children": [],
+                "rendered": null,
+            }],
+            "rendered": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_missing_crate() {
+        let missing = |code, text, help| missing_crate(&message(code, text, help));
+        assert_eq!(
+            missing(
+                "E0432",
+                "unresolved import `rand`",
+                "you might be missing crate `rand`"
+            ),
+            Some("rand".to_owned())
+        );
+        assert_eq!(
+            missing("E0463", "can't find crate for `serde_json`", ""),
+            Some("serde_json".to_owned())
+        );
+        assert_eq!(missing("E0432", "unresolved import `crate::foo`", ""), None);
+        assert_eq!(missing("E0463", "can't find crate for `std`", ""), None);
+        assert_eq!(
+            missing("E0425", "", "you might be missing crate `rand`"),
+            None
+        );
+    }
+}
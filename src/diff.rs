@@ -0,0 +1,114 @@
+use clap::ValueEnum;
+use colored::Colorize;
+use similar::{ChangeTag, TextDiff};
+
+/// How to render the difference between old and new text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DiffStyle {
+    /// Old and new text on consecutive lines, with changed characters highlighted
+    Inline,
+    /// Changed lines prefixed with `-` and `+`, like `diff -u`
+    Unified,
+    /// Old and new text in two columns, with changed words highlighted
+    SideBySide,
+}
+
+/// Render the difference between old and new text, ending with a newline
+pub fn render(old: &str, new: &str, style: DiffStyle) -> String {
+    match style {
+        DiffStyle::Inline => inline(old, new),
+        DiffStyle::Unified => unified(old, new),
+        DiffStyle::SideBySide => side_by_side(old, new),
+    }
+}
+
+/// Highlight deleted and inserted parts of the diff, returning (old, new)
+fn highlight<'a>(diff: &TextDiff<'a, 'a, '_, str>) -> (String, String) {
+    let before: String = diff
+        .iter_all_changes()
+        .filter_map(|c| match c.tag() {
+            ChangeTag::Equal => Some(c.value().to_string()),
+            ChangeTag::Delete => Some(c.value().white().on_red().to_string()),
+            ChangeTag::Insert => None,
+        })
+        .collect();
+
+    let after: String = diff
+        .iter_all_changes()
+        .filter_map(|c| match c.tag() {
+            ChangeTag::Equal => Some(c.value().to_string()),
+            ChangeTag::Insert => Some(c.value().black().on_green().to_string()),
+            ChangeTag::Delete => None,
+        })
+        .collect();
+
+    (before, after)
+}
+
+fn inline(old: &str, new: &str) -> String {
+    let (before, after) = highlight(&TextDiff::from_graphemes(old, new));
+    format!("{}{}\n{}{}\n", "-".red(), before, "+".green(), after)
+}
+
+fn unified(old: &str, new: &str) -> String {
+    let diff = TextDiff::from_lines(old, new);
+    let mut result = String::new();
+    for change in diff.iter_all_changes() {
+        let line = change.value().trim_end_matches(['\r', '\n']);
+        let line = match change.tag() {
+            ChangeTag::Equal => format!(" {}", line),
+            ChangeTag::Delete => format!("-{}", line).red().to_string(),
+            ChangeTag::Insert => format!("+{}", line).green().to_string(),
+        };
+        result.push_str(&line);
+        result.push('\n');
+    }
+    result
+}
+
+fn side_by_side(old: &str, new: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let width = old_lines
+        .iter()
+        .map(|line| line.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    let mut result = String::new();
+    for i in 0..old_lines.len().max(new_lines.len()) {
+        let old_line = old_lines.get(i).copied().unwrap_or_default();
+        let new_line = new_lines.get(i).copied().unwrap_or_default();
+        let (before, after) = highlight(&TextDiff::from_words(old_line, new_line));
+        // Padding is computed from the plain text, as highlighting adds escape codes
+        let padding = " ".repeat(width - old_line.chars().count());
+        result.push_str(&format!(
+            "{}{} {} {}\n",
+            before,
+            padding,
+            "│".dimmed(),
+            after
+        ));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, DiffStyle};
+
+    #[test]
+    fn test_render_plain() {
+        colored::control::set_override(false);
+
+        assert_eq!(
+            render("let x = 1;", "let _x = 1;", DiffStyle::Inline),
+            "-let x = 1;\n+let _x = 1;\n"
+        );
+        assert_eq!(render("a\nb", "a\nc", DiffStyle::Unified), " a\n-b\n+c\n");
+        assert_eq!(
+            render("let x = 1;\nfoo", "let _x = 1;\nfoo", DiffStyle::SideBySide),
+            "let x = 1; │ let _x = 1;\nfoo        │ foo\n"
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! Running operation sequences on a given text, without invoking cargo
+
+use std::{
+    io::{self, Read},
+    ops::Range,
+    process,
+};
+
+use clap::Parser;
+
+#[cfg(feature = "wasm")]
+use crate::plugin::Plugin;
+use crate::{
+    diff::{self, DiffStyle},
+    operation::{self, ExecError, Operation, RegexFlags, State},
+    pattern::RegexEngine,
+};
+
+/// Run an operation sequence on the given text and show the changes
+#[derive(Parser, Debug)]
+#[command(bin_name = "cargo refix eval")]
+pub struct EvalArgs {
+    /// Text to operate on, read from stdin if not given
+    #[arg(long)]
+    pub text: Option<String>,
+
+    /// Byte range selected at the start, e.g. `4..9`. Defaults to the whole text
+    #[arg(long, value_name = "RANGE", value_parser = parse_range)]
+    pub highlight: Option<Range<usize>>,
+
+    /// How to show the changes
+    #[arg(long, value_enum, default_value_t = DiffStyle::Inline)]
+    pub diff_style: DiffStyle,
+
+    /// Print the selection and the stack after each operation
+    #[arg(long)]
+    pub trace_ops: bool,
+
+    /// Allow `${cmd:...}` templates, which run the command with `sh` and use its output
+    #[arg(long)]
+    pub allow_shell: bool,
+
+    /// Flags for all regexes of the operations, e.g. `i,m`. See `cargo refix --help`
+    #[arg(long, value_name = "FLAGS", default_value = "")]
+    pub regex_flags: RegexFlags,
+
+    /// Regex engine for the operations. The fancy engine supports lookaround and backreferences
+    #[arg(long, value_enum, default_value_t = RegexEngine::Default)]
+    pub regex_engine: RegexEngine,
+
+    /// WebAssembly module implementing the operation `x-<name>`. See `cargo refix --help`
+    #[cfg(feature = "wasm")]
+    #[arg(long = "plugin", value_name = "PATH")]
+    pub plugins: Vec<Plugin>,
+
+    /// Sequence of operations to apply. See `cargo refix ops` for the available ones
+    #[arg(allow_negative_numbers = true)]
+    pub ops: Vec<String>,
+}
+
+/// Parse a byte range like `4..9`
+pub fn parse_range(value: &str) -> Result<Range<usize>, String> {
+    let (start, end) = value
+        .split_once("..")
+        .ok_or("expected a range like `4..9`")?;
+    let start = start
+        .parse()
+        .map_err(|_| format!("invalid start {:?}", start))?;
+    let end = end.parse().map_err(|_| format!("invalid end {:?}", end))?;
+    if start > end {
+        return Err("range ends before it starts".to_owned());
+    }
+    Ok(start..end)
+}
+
+pub fn eval(args: &EvalArgs) {
+    let text = match &args.text {
+        Some(text) => text.clone(),
+        None => {
+            let mut text = String::new();
+            io::stdin()
+                .read_to_string(&mut text)
+                .expect("Unable to read stdin");
+            text
+        }
+    };
+
+    let span = args.highlight.clone().unwrap_or(0..text.len());
+    if let Err(err) = check_span(&text, &span) {
+        eprintln!("error: {}", err);
+        process::exit(2);
+    }
+
+    let mut new = text.clone();
+    let flags = RegexFlags {
+        engine: args.regex_engine,
+        ..args.regex_flags
+    };
+    let state = State {
+        #[cfg(feature = "wasm")]
+        plugins: args.plugins.clone(),
+        ..State::new(args.allow_shell, flags)
+    };
+    if let Err(err) = run(&args.ops, &mut new, span, args.trace_ops, state) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+    print!("{}", diff::render(&text, &new, args.diff_style));
+}
+
+/// Make sure that the range can be selected in the text
+pub fn check_span(text: &str, span: &Range<usize>) -> Result<(), String> {
+    if span.end > text.len()
+        || !text.is_char_boundary(span.start)
+        || !text.is_char_boundary(span.end)
+    {
+        return Err(format!("range {:?} is not inside the text", span));
+    }
+    Ok(())
+}
+
+/// Run an operation sequence on the text, which can't use goto or file operations without a diagnostic
+pub fn run(
+    ops: &[String],
+    text: &mut String,
+    span: Range<usize>,
+    trace: bool,
+    mut state: State,
+) -> Result<(), ExecError> {
+    let ops = operation::parse_ops(ops)?;
+    operation::validate_ops(&ops, state.allow_shell, state.regex_flags)?;
+    if let Some((op, _)) = ops.iter().find(|(op, _)| op.is_goto() || op.is_file_edit()) {
+        return Err(ExecError::NeedsDiagnostic(*op));
+    }
+    Operation::run(&ops, &mut state, text, span, trace).map(|_| ())
+}
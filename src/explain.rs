@@ -0,0 +1,116 @@
+//! Explanations of diagnostic codes: rustc's error index, its lint descriptions,
+//! and links to the clippy documentation
+
+use std::{env, ffi::OsString, process, process::Command, sync::OnceLock};
+
+use clap::Parser;
+
+/// Show what a diagnostic code means
+#[derive(Parser, Debug)]
+#[command(bin_name = "cargo refix explain")]
+pub struct ExplainArgs {
+    /// Error code like `E0308`, rustc lint like `unused_variables`, or clippy lint
+    /// like `clippy::needless_return`
+    #[arg(value_name = "CODE")]
+    pub code: String,
+}
+
+/// Documentation page of the clippy lints
+const CLIPPY_DOCS: &str = "https://rust-lang.github.io/rust-clippy/master/index.html";
+
+fn rustc() -> Command {
+    Command::new(env::var_os("RUSTC").unwrap_or(OsString::from("rustc")))
+}
+
+fn is_error_code(code: &str) -> bool {
+    code.len() == 5 && code.starts_with('E') && code[1..].bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Output of `rustc --explain`, the error index entry of the code
+fn error_index(code: &str) -> Option<String> {
+    let output = rustc().arg("--explain").arg(code).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Default level and description of a rustc lint, from `rustc -W help`
+fn lint_description(lint: &str) -> Option<String> {
+    static HELP: OnceLock<String> = OnceLock::new();
+    let help = HELP.get_or_init(|| {
+        rustc()
+            .args(["-W", "help"])
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+            .unwrap_or_default()
+    });
+    // Lints are listed with dashes, as `name  default  meaning`, followed by the lint groups
+    let name = lint.replace('_', "-");
+    let lints = help
+        .split("Lint groups provided")
+        .next()
+        .unwrap_or_default();
+    lints.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        if words.next() != Some(&name) {
+            return None;
+        }
+        let level = words.next()?;
+        let meaning: Vec<&str> = words.collect();
+        Some(format!("{} (default: {})", meaning.join(" "), level))
+    })
+}
+
+fn clippy_url(lint: &str) -> String {
+    format!("{}#{}", CLIPPY_DOCS, lint)
+}
+
+/// Single line explaining the code, if one is known
+pub fn summary(code: &str) -> Option<String> {
+    if let Some(lint) = code.strip_prefix("clippy::") {
+        return Some(clippy_url(lint));
+    }
+    if is_error_code(code) {
+        let explanation = error_index(code)?;
+        return explanation
+            .lines()
+            .find(|line| !line.trim().is_empty())
+            .map(str::to_owned);
+    }
+    lint_description(code)
+}
+
+pub fn explain(args: &ExplainArgs) {
+    let code = args.code.as_str();
+    let explanation = if let Some(lint) = code.strip_prefix("clippy::") {
+        Some(clippy_url(lint))
+    } else if is_error_code(code) {
+        error_index(code).map(|text| text.trim_end().to_owned())
+    } else {
+        lint_description(code)
+    };
+    match explanation {
+        Some(explanation) => println!("{}", explanation),
+        None => {
+            eprintln!("error: no explanation found for {:?}", code);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clippy_url, is_error_code};
+
+    #[test]
+    fn test_codes() {
+        assert!(is_error_code("E0308"));
+        assert!(!is_error_code("E030"));
+        assert!(!is_error_code("unused_variables"));
+        assert_eq!(
+            clippy_url("needless_return"),
+            "https://rust-lang.github.io/rust-clippy/master/index.html#needless_return"
+        );
+    }
+}
@@ -0,0 +1,183 @@
+//! Committing the applied changes
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use similar::TextDiff;
+
+use crate::{
+    apply::{Change, FileChangeSet},
+    log,
+    rustfmt::Rustfmt,
+};
+
+/// Message template used by `--commit` when none is given
+pub const DEFAULT_TEMPLATE: &str = "refix: fix {count}x {code}";
+
+/// Write the changes, labeled with their diagnostic codes, and commit them.
+/// Makes either a single commit or one per code, formatting the files before each.
+pub fn write_and_commit(
+    changeset: Vec<(String, Change)>,
+    template: &str,
+    selector: &str,
+    per_code: bool,
+    rustfmt: Option<&Rustfmt>,
+) -> io::Result<()> {
+    let batches: Vec<(Vec<String>, Vec<Change>)> = if per_code {
+        let mut by_code: BTreeMap<String, Vec<Change>> = BTreeMap::new();
+        for (code, change) in changeset {
+            by_code.entry(code).or_default().push(change);
+        }
+        by_code
+            .into_iter()
+            .map(|(code, changes)| (vec![code], changes))
+            .collect()
+    } else {
+        let codes: BTreeSet<String> = changeset.iter().map(|(code, _)| code.clone()).collect();
+        let changes = changeset.into_iter().map(|(_, change)| change).collect();
+        vec![(codes.into_iter().collect(), changes)]
+    };
+
+    // Patches refer to the original contents, so every commit rewrites
+    // the files with all changes so far applied on top of them
+    let mut originals = HashMap::new();
+    for change in batches.iter().flat_map(|(_, changes)| changes) {
+        if !originals.contains_key(&change.file) {
+            originals.insert(change.file.clone(), fs::read(&change.file)?);
+        }
+    }
+
+    // Leave out files the changes can't be applied to cleanly
+    let all: Vec<Change> = batches.iter().flat_map(|(_, c)| c.clone()).collect();
+    let mut skipped = BTreeSet::new();
+    for fc in FileChangeSet::group(all) {
+        if let Err(err) = fc.check(&originals[fc.file()]) {
+            log::info!("skipping {}: {}", fc.file().display(), err);
+            skipped.insert(fc.file().to_owned());
+        }
+    }
+
+    let mut applied = Vec::new();
+    for (codes, mut changes) in batches {
+        changes.retain(|change| !skipped.contains(&change.file));
+        if changes.is_empty() {
+            continue;
+        }
+        let message = template
+            .replace("{count}", &changes.len().to_string())
+            .replace("{code}", &codes.join(", "))
+            .replace("{selector}", selector);
+        let files: BTreeSet<PathBuf> = changes.iter().map(|c| c.file.clone()).collect();
+
+        applied.extend(changes);
+        let mut changed = Vec::new();
+        for fc in FileChangeSet::group(applied.clone()) {
+            if files.contains(fc.file()) {
+                let original = &originals[fc.file()];
+                fs::write(fc.file(), fc.apply(original))?;
+                changed.push((fc.file().to_owned(), fc.changed_lines(original)));
+            }
+        }
+        if let Some(rustfmt) = rustfmt {
+            rustfmt.format(&changed)?;
+        }
+
+        git(Command::new("git").arg("add").arg("--").args(&files))?;
+        let unchanged = Command::new("git")
+            .args(["diff", "--cached", "--quiet", "--"])
+            .args(&files)
+            .status()?
+            .success();
+        if unchanged {
+            log::info!("nothing to commit for: {}", message);
+            continue;
+        }
+        git(Command::new("git")
+            .args(["commit", "--quiet", "--message", &message, "--"])
+            .args(&files))?;
+        log::info!("committed: {}", message);
+    }
+    Ok(())
+}
+
+/// Apply the changes to the git index, leaving the working tree untouched.
+/// The spans refer to the working tree, so files whose staged contents differ from it are skipped.
+pub fn stage(fcs: &[FileChangeSet]) -> io::Result<()> {
+    // Paths in the patch are relative to the repository root, which the workspace may be under
+    let prefix = String::from_utf8(output(
+        Command::new("git").args(["rev-parse", "--show-prefix"]),
+    )?)
+    .map_err(io::Error::other)?;
+    let prefix = prefix.trim_end_matches('\n');
+    let mut patch = String::new();
+    for fc in fcs {
+        let path = format!("{}{}", prefix, fc.file().to_string_lossy());
+        let Ok(original) = output(Command::new("git").arg("show").arg(format!(":{}", path))) else {
+            log::info!("skipping {}: not in the index", fc.file().display());
+            continue;
+        };
+        if fs::read(fc.file())? != original {
+            log::info!("skipping {}: has unstaged changes", fc.file().display());
+            continue;
+        }
+        if let Err(err) = fc.check(&original) {
+            log::info!("skipping {}: {}", fc.file().display(), err);
+            continue;
+        }
+        let new = String::from_utf8(fc.apply(&original)).map_err(io::Error::other)?;
+        let old = String::from_utf8(original).map_err(io::Error::other)?;
+        let diff = TextDiff::from_lines(&old, &new);
+        patch.push_str(
+            &diff
+                .unified_diff()
+                .header(&format!("a/{}", path), &format!("b/{}", path))
+                .to_string(),
+        );
+    }
+    if patch.is_empty() {
+        return Ok(());
+    }
+
+    let mut child = Command::new("git")
+        .args(["apply", "--cached", "-"])
+        .stdin(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(patch.as_bytes())?;
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "git apply failed with {}",
+            status
+        )))
+    }
+}
+
+/// Run a git command and return what it printed, failing if it does not succeed
+fn output(cmd: &mut Command) -> io::Result<Vec<u8>> {
+    let output = cmd.stderr(Stdio::null()).output()?;
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(io::Error::other(format!(
+            "git failed with {}",
+            output.status
+        )))
+    }
+}
+
+/// Run a git command, failing if it does not succeed
+fn git(cmd: &mut Command) -> io::Result<()> {
+    let status = cmd.status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("git failed with {}", status)))
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    env,
+    io::{self, BufRead, Write},
+    path::Path,
+    process::Command,
+};
+
+/// Read a single trimmed line from stdin after showing a prompt
+pub fn prompt(prompt: &str) -> io::Result<String> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim().to_owned())
+}
+
+/// Ask the user to pick one of the options, returning its index, or `None` to skip
+pub fn choose(title: &str, options: &[String]) -> io::Result<Option<usize>> {
+    println!("{}", title);
+    for (i, option) in options.iter().enumerate() {
+        println!(" [{}] {}", i, option);
+    }
+
+    loop {
+        let answer = prompt("Choose [number, empty to skip]: ")?;
+        if answer.is_empty() {
+            return Ok(None);
+        }
+        match answer.parse::<usize>() {
+            Ok(index) if index < options.len() => return Ok(Some(index)),
+            _ => println!("Invalid choice: {}", answer),
+        }
+    }
+}
+
+/// Open a file in `$VISUAL` or `$EDITOR` with the cursor at the given location, and wait for it to exit
+pub fn open_in_editor(file: &str, line: usize, column: usize) -> io::Result<()> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+
+    let mut cmd = Command::new(program);
+    cmd.args(parts);
+    let name = Path::new(program)
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    match name {
+        "code" | "code-insiders" | "codium" => {
+            cmd.args(["--wait", "--goto", &format!("{}:{}:{}", file, line, column)]);
+        }
+        "subl" | "zed" | "hx" | "helix" => {
+            cmd.arg(format!("{}:{}:{}", file, line, column));
+        }
+        "emacs" | "emacsclient" => {
+            cmd.arg(format!("+{}:{}", line, column)).arg(file);
+        }
+        "nano" => {
+            cmd.arg(format!("+{},{}", line, column)).arg(file);
+        }
+        _ => {
+            cmd.arg(format!("+{}", line)).arg(file);
+        }
+    }
+
+    let status = cmd.status()?;
+    if !status.success() {
+        println!("Editor exited with {}", status);
+    }
+    Ok(())
+}
+
+/// Let the user edit text in `$VISUAL` or `$EDITOR` through a temporary file
+#[cfg(feature = "tui")]
+pub fn edit_text(text: &str) -> io::Result<String> {
+    // Not a predictable name, so that other users can't swap the file in the shared directory
+    let mut file = tempfile::Builder::new().suffix(".rs").tempfile()?;
+    file.write_all(text.as_bytes())?;
+    let path = file.into_temp_path();
+    let result = open_in_editor(&path.to_string_lossy(), 1, 1).and_then(|_| std::fs::read(&path));
+
+    let mut edited = String::from_utf8_lossy(&result?).into_owned();
+    // Editors usually add a final newline
+    if !text.ends_with('\n') && edited.ends_with('\n') {
+        edited.pop();
+        if edited.ends_with('\r') {
+            edited.pop();
+        }
+    }
+    Ok(edited)
+}
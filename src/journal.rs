@@ -0,0 +1,281 @@
+//! Journal of the written changes, for undoing them without relying on git
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+
+use cargo_refix::apply::{Change, FileChangeSet, Patch};
+
+/// Directory of the files refix keeps in the workspace root
+pub const REFIX_DIR: &str = ".refix";
+
+/// Directory of the journal, relative to the workspace root
+const DIR: &str = ".refix/journal";
+
+/// Revert changes written by earlier runs, if the changed regions haven't been edited since
+#[derive(Parser, Debug)]
+#[command(bin_name = "cargo refix undo")]
+pub struct UndoArgs {
+    /// Revert the latest run that hasn't been reverted yet. This is the default
+    #[arg(long, conflicts_with_all = ["id", "list"])]
+    pub last: bool,
+
+    /// Revert the run with this ID
+    #[arg(long, conflicts_with = "list")]
+    pub id: Option<String>,
+
+    /// List the runs that can be reverted, oldest first
+    #[arg(long)]
+    pub list: bool,
+}
+
+/// Changes written to a file. Each edit replaced `old` with `new`,
+/// which is at `start..end` in the changed file.
+#[derive(Debug, Serialize, Deserialize)]
+struct FileEdits {
+    file: PathBuf,
+    edits: Vec<Edit>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Edit {
+    start: usize,
+    end: usize,
+    old: String,
+    new: String,
+}
+
+/// Changes written by one run
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Journal {
+    files: Vec<FileEdits>,
+}
+
+impl Journal {
+    /// Record the changes written to a file, from its original and final contents,
+    /// so that changes made after refix's own, like formatting, are included.
+    /// Each group of changed lines is an edit.
+    pub fn add(&mut self, file: &Path, original: &[u8], written: &[u8]) {
+        let lines = |text: &[u8]| -> Vec<usize> {
+            // Offsets of the line starts, and the end of the text
+            std::iter::once(0)
+                .chain(text.split_inclusive(|b| *b == b'\n').scan(0, |end, line| {
+                    *end += line.len();
+                    Some(*end)
+                }))
+                .collect()
+        };
+        let (old_starts, new_starts) = (lines(original), lines(written));
+        let slices = |text: &[u8], starts: &[usize]| -> Vec<Vec<u8>> {
+            starts
+                .windows(2)
+                .map(|w| text[w[0]..w[1]].to_vec())
+                .collect()
+        };
+        let ops = similar::capture_diff_slices(
+            similar::Algorithm::Myers,
+            &slices(original, &old_starts),
+            &slices(written, &new_starts),
+        );
+        let edits: Vec<Edit> = similar::group_diff_ops(ops, 0)
+            .iter()
+            .map(|group| {
+                let (first, last) = (group.first().unwrap(), group.last().unwrap());
+                let old = old_starts[first.old_range().start]..old_starts[last.old_range().end];
+                let new = new_starts[first.new_range().start]..new_starts[last.new_range().end];
+                Edit {
+                    start: new.start,
+                    end: new.end,
+                    old: String::from_utf8_lossy(&original[old]).into_owned(),
+                    new: String::from_utf8_lossy(&written[new]).into_owned(),
+                }
+            })
+            .collect();
+        if !edits.is_empty() {
+            self.files.push(FileEdits {
+                file: file.to_owned(),
+                edits,
+            });
+        }
+    }
+
+    /// Save the journal under the current directory, returning its ID
+    pub fn save(&self) -> io::Result<String> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let id = millis.to_string();
+        create_refix_dir()?;
+        fs::create_dir_all(DIR)?;
+        fs::write(path(Path::new(""), &id), serde_json::to_vec_pretty(self)?)?;
+        Ok(id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Changes reverting the journal, relative to `root`,
+    /// or the files edited since the journal was recorded
+    fn reverse(&self, root: &Path) -> Result<Vec<Change>, Vec<PathBuf>> {
+        let mut changes = Vec::new();
+        let mut edited = Vec::new();
+        for FileEdits { file, edits } in &self.files {
+            let path = root.join(file);
+            let contents = fs::read(&path).unwrap_or_default();
+            let unchanged = edits
+                .iter()
+                .all(|edit| contents.get(edit.start..edit.end) == Some(edit.new.as_bytes()));
+            if !unchanged || !path.is_file() {
+                edited.push(file.clone());
+                continue;
+            }
+            changes.extend(edits.iter().map(|edit| Change {
+                file: path.clone(),
+                patch: Patch {
+                    location: edit.start..edit.end,
+                    bytes: edit.old.clone().into_bytes(),
+                },
+            }));
+        }
+        match edited.is_empty() {
+            true => Ok(changes),
+            false => Err(edited),
+        }
+    }
+}
+
+/// Create the directory of refix's files unless it exists
+pub fn create_refix_dir() -> io::Result<()> {
+    fs::create_dir_all(REFIX_DIR)?;
+    // Keep it out of version control, like cargo does with the target directory
+    let gitignore = Path::new(REFIX_DIR).join(".gitignore");
+    if !gitignore.exists() {
+        fs::write(gitignore, "*\n")?;
+    }
+    Ok(())
+}
+
+fn path(root: &Path, id: &str) -> PathBuf {
+    root.join(DIR).join(format!("{}.json", id))
+}
+
+/// Nearest directory with a journal, starting from the current one
+fn find_root() -> Option<PathBuf> {
+    let cwd = env::current_dir().ok()?;
+    cwd.ancestors()
+        .find(|dir| dir.join(DIR).is_dir())
+        .map(Path::to_path_buf)
+}
+
+/// IDs of the saved journals, oldest first
+fn ids(root: &Path) -> io::Result<Vec<String>> {
+    let mut ids: Vec<String> = fs::read_dir(root.join(DIR))?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            Some(name.strip_suffix(".json")?.to_owned())
+        })
+        .collect();
+    // IDs are timestamps, so sort them numerically
+    ids.sort_by_key(|id| (id.len(), id.clone()));
+    Ok(ids)
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
+}
+
+pub fn undo(args: &UndoArgs) {
+    let Some(root) = find_root() else {
+        fail(format!("no {} directory found", DIR));
+    };
+    let ids = ids(&root).unwrap_or_else(|err| fail(err.to_string()));
+
+    if args.list {
+        for id in ids {
+            println!("{}", id);
+        }
+        return;
+    }
+
+    let id = match &args.id {
+        Some(id) => id.clone(),
+        None => match ids.last() {
+            Some(id) => id.clone(),
+            None => fail("nothing to undo".to_owned()),
+        },
+    };
+    let path = path(&root, &id);
+    let journal: Journal = fs::read(&path)
+        .map_err(|err| err.to_string())
+        .and_then(|bytes| serde_json::from_slice(&bytes).map_err(|err| err.to_string()))
+        .unwrap_or_else(|err| fail(format!("unable to read {}: {}", path.display(), err)));
+
+    let changes = journal.reverse(&root).unwrap_or_else(|edited| {
+        let files: Vec<String> = edited.iter().map(|f| f.display().to_string()).collect();
+        fail(format!(
+            "changed since {}, revert them manually or with git: {}",
+            id,
+            files.join(", ")
+        ))
+    });
+    let amount = changes.len();
+    let fcs = FileChangeSet::group(changes);
+    let files = fcs.len();
+    for fc in fcs {
+        let file = fc.file().to_owned();
+        fc.write()
+            .unwrap_or_else(|err| fail(format!("unable to write {}: {}", file.display(), err)));
+    }
+    fs::remove_file(&path).unwrap_or_else(|err| fail(err.to_string()));
+    println!("reverted {} changes in {} files", amount, files);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use cargo_refix::apply::{Change, FileChangeSet, Patch};
+
+    use super::Journal;
+
+    #[test]
+    fn test_reverse() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = b"let x = 1;\nlet y = 2;\n";
+        fs::write(dir.path().join("a.rs"), original).unwrap();
+        let change = |location, bytes: &[u8]| Change {
+            file: PathBuf::from("a.rs"),
+            patch: Patch {
+                location,
+                bytes: bytes.to_vec(),
+            },
+        };
+        let fcs = FileChangeSet::group(vec![change(4..5, b"_x"), change(15..16, b"")]);
+
+        // Formatting after the changes is recorded too
+        let written = [fcs[0].apply(original), b"\n".to_vec()].concat();
+        let mut journal = Journal::default();
+        journal.add(&PathBuf::from("a.rs"), original, &written);
+        fs::write(dir.path().join("a.rs"), written).unwrap();
+
+        let changes = journal.reverse(dir.path()).unwrap();
+        let reverted = FileChangeSet::group(changes);
+        let written = fs::read(dir.path().join("a.rs")).unwrap();
+        assert_eq!(reverted[0].apply(&written), original);
+
+        fs::write(dir.path().join("a.rs"), b"let _z = 1;\nlet  = 2;\n").unwrap();
+        assert_eq!(
+            journal.reverse(dir.path()).unwrap_err(),
+            [PathBuf::from("a.rs")]
+        );
+    }
+}
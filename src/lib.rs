@@ -0,0 +1,56 @@
+#![deny(unused_must_use)]
+
+//! Engine of `cargo refix`: parsing compiler messages, selecting them,
+//! running operation sequences on their spans, and applying the resulting patches.
+//!
+//! ```
+//! let ops = cargo_refix::parse_ops(&["substitute", "x", "y"]).unwrap();
+//! let mut text = "let x = 1;".to_owned();
+//! cargo_refix::run_ops(&mut text, 0..10, &ops).unwrap();
+//! assert_eq!(text, "let y = 1;");
+//! ```
+
+use std::ops;
+
+pub mod apply;
+pub mod diff;
+pub mod message;
+pub mod operation;
+pub mod pattern;
+#[cfg(feature = "wasm")]
+pub mod plugin;
+pub mod recipe;
+#[cfg(feature = "rhai")]
+pub mod script;
+pub mod selector;
+pub mod ssr;
+pub mod syntax;
+pub mod text;
+#[cfg(feature = "tree-sitter")]
+pub mod ts;
+
+#[doc(hidden)]
+pub mod interactive;
+#[doc(hidden)]
+pub mod log;
+#[doc(hidden)]
+pub mod pager;
+#[doc(hidden)]
+pub mod progress;
+
+pub use apply::FileChangeSet;
+pub use operation::{parse_ops, ExecError, ParsedOp, TextOperation};
+pub use selector::Selector;
+
+/// Run an operation sequence on `text`, starting with `span` selected.
+/// Goto and file operations need a diagnostic to know the file, so they are rejected.
+pub fn run_ops(
+    text: &mut String,
+    span: ops::Range<usize>,
+    ops: &[ParsedOp],
+) -> Result<(), ExecError> {
+    if let Some((op, _)) = ops.iter().find(|(op, _)| op.is_goto() || op.is_file_edit()) {
+        return Err(ExecError::NeedsDiagnostic(*op));
+    }
+    operation::Operation::run(ops, &mut operation::State::default(), text, span, false).map(|_| ())
+}
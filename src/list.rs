@@ -0,0 +1,249 @@
+use std::collections::{BTreeMap, HashMap};
+
+use clap::ValueEnum;
+
+use crate::{
+    explain,
+    message::{CompilerMessage, Target},
+};
+
+/// Extra information to show for each code in list mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ListDetail {
+    /// Occurrence counts per file
+    Files,
+    /// Location of each occurrence
+    Lines,
+    /// Distinct message texts per code, with an example location of each
+    Messages,
+}
+
+/// What to group the list summary by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GroupBy {
+    /// Diagnostic code, listing files for each
+    Code,
+    /// File, listing diagnostic codes for each
+    File,
+    /// Target the diagnostic came from, like `lib foo` or `test integration`,
+    /// listing diagnostic codes for each
+    Target,
+}
+
+/// Output format for list mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ListFormat {
+    /// Human-readable summary
+    Text,
+    /// JSON array with an object per occurrence
+    Json,
+    /// CSV with a header row and a row per occurrence
+    Csv,
+}
+
+/// A single diagnostic in the list summary
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Occurrence {
+    pub code: String,
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub level: String,
+    pub message: String,
+    /// Targets the diagnostic was emitted for. The same diagnostic can come from multiple
+    /// targets, e.g. a library and its unit tests, and is listed only once.
+    pub targets: Vec<String>,
+}
+
+/// Collected diagnostics for list mode
+#[derive(Debug, Default)]
+pub struct ListSummary {
+    occurrences: Vec<Occurrence>,
+    /// Index of the occurrence of each diagnostic, by its rendered form
+    seen: HashMap<String, usize>,
+}
+
+impl ListSummary {
+    pub fn add(&mut self, message: &CompilerMessage, target: Option<&Target>) {
+        let target = target.map(Target::label);
+        let key = message.render();
+        if let Some(&index) = self.seen.get(&key) {
+            let targets = &mut self.occurrences[index].targets;
+            if let Some(target) = target.filter(|target| !targets.contains(target)) {
+                targets.push(target);
+            }
+            return;
+        }
+        self.seen.insert(key, self.occurrences.len());
+
+        let span = message
+            .primary_spans()
+            .next()
+            .or_else(|| message.spans.first());
+        self.occurrences.push(Occurrence {
+            code: message.code().unwrap_or("<uncoded>").to_owned(),
+            file: span.map(|span| span.file_name.clone()),
+            line: span.map(|span| span.line_start),
+            level: message.level.clone(),
+            message: message.message.clone(),
+            targets: target.into_iter().collect(),
+        });
+    }
+
+    /// Occurrences grouped by a key, the most common keys first
+    fn grouped<'a, F>(&'a self, key: F) -> Vec<(&'a str, Vec<&'a Occurrence>)>
+    where
+        F: Fn(&'a Occurrence) -> &'a str,
+    {
+        group(self.occurrences.iter(), key)
+    }
+
+    pub fn print(
+        &self,
+        format: ListFormat,
+        group_by: GroupBy,
+        detail: Option<ListDetail>,
+        explain: bool,
+    ) {
+        match format {
+            ListFormat::Text => self.print_text(group_by, detail, explain),
+            ListFormat::Json => {
+                let json = serde_json::to_string_pretty(&self.occurrences)
+                    .expect("Unable to serialize list");
+                println!("{}", json);
+            }
+            ListFormat::Csv => {
+                println!("code,file,line,level,message,targets");
+                for occurrence in &self.occurrences {
+                    println!(
+                        "{},{},{},{},{},{}",
+                        csv_field(&occurrence.code),
+                        csv_field(occurrence.file.as_deref().unwrap_or_default()),
+                        occurrence.line.map(|l| l.to_string()).unwrap_or_default(),
+                        csv_field(&occurrence.level),
+                        csv_field(&occurrence.message),
+                        csv_field(&occurrence.targets.join(";")),
+                    );
+                }
+            }
+        }
+    }
+
+    fn print_text(&self, group_by: GroupBy, detail: Option<ListDetail>, explain: bool) {
+        match group_by {
+            GroupBy::Code => {
+                for (code, occurrences) in self.grouped(|o| &o.code) {
+                    println!("{}: {}", code, occurrences.len());
+                    if let Some(summary) = explain.then(|| explain::summary(code)).flatten() {
+                        println!("  {}", summary);
+                    }
+                    match detail {
+                        None => {}
+                        Some(ListDetail::Files) => {
+                            for (file, group) in group(occurrences.into_iter(), location_file) {
+                                println!("  {}: {}", file, group.len());
+                            }
+                        }
+                        Some(ListDetail::Lines) => {
+                            let mut locations: Vec<_> = occurrences
+                                .iter()
+                                .map(|occurrence| (location_file(occurrence), occurrence.line))
+                                .collect();
+                            locations.sort();
+                            for (file, line) in locations {
+                                match line {
+                                    Some(line) => println!("  {}:{}", file, line),
+                                    None => println!("  {}", file),
+                                }
+                            }
+                        }
+                        Some(ListDetail::Messages) => {
+                            for (message, group) in group(occurrences.into_iter(), |o| &o.message) {
+                                println!("  {}: {}", group.len(), message);
+                                let example = group[0];
+                                match example.line {
+                                    Some(line) => {
+                                        println!("    e.g. {}:{}", location_file(example), line)
+                                    }
+                                    None => println!("    e.g. {}", location_file(example)),
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            GroupBy::File => {
+                for (file, occurrences) in self.grouped(location_file) {
+                    println!("{}: {}", file, occurrences.len());
+                    if detail == Some(ListDetail::Lines) {
+                        let mut lines: Vec<_> = occurrences
+                            .iter()
+                            .map(|occurrence| (occurrence.line, &occurrence.code))
+                            .collect();
+                        lines.sort();
+                        for (line, code) in lines {
+                            match line {
+                                Some(line) => println!("  {}: {}", line, code),
+                                None => println!("  {}", code),
+                            }
+                        }
+                    } else {
+                        for (code, group) in group(occurrences.into_iter(), |o| &o.code) {
+                            println!("  {}: {}", code, group.len());
+                        }
+                    }
+                }
+            }
+            GroupBy::Target => {
+                // A diagnostic is counted for each target it came from
+                let per_target = self.occurrences.iter().flat_map(|occurrence| {
+                    let targets = match occurrence.targets.is_empty() {
+                        true => vec!["<unknown>"],
+                        false => occurrence.targets.iter().map(String::as_str).collect(),
+                    };
+                    targets.into_iter().map(move |target| (target, occurrence))
+                });
+                let mut targets: BTreeMap<&str, Vec<&Occurrence>> = BTreeMap::new();
+                for (target, occurrence) in per_target {
+                    targets.entry(target).or_default().push(occurrence);
+                }
+                let mut targets: Vec<_> = targets.into_iter().collect();
+                targets.sort_by_key(|(_, occurrences)| std::cmp::Reverse(occurrences.len()));
+                for (target, occurrences) in targets {
+                    println!("{}: {}", target, occurrences.len());
+                    for (code, group) in group(occurrences.into_iter(), |o| &o.code) {
+                        println!("  {}: {}", code, group.len());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Group occurrences by a key, the most common keys first
+fn group<'a, I, F>(occurrences: I, key: F) -> Vec<(&'a str, Vec<&'a Occurrence>)>
+where
+    I: Iterator<Item = &'a Occurrence>,
+    F: Fn(&'a Occurrence) -> &'a str,
+{
+    let mut groups: BTreeMap<&str, Vec<&Occurrence>> = BTreeMap::new();
+    for occurrence in occurrences {
+        groups.entry(key(occurrence)).or_default().push(occurrence);
+    }
+    let mut groups: Vec<_> = groups.into_iter().collect();
+    // Stable sort keeps keys with equal counts in alphabetical order
+    groups.sort_by_key(|(_, occurrences)| std::cmp::Reverse(occurrences.len()));
+    groups
+}
+
+fn location_file(occurrence: &Occurrence) -> &str {
+    occurrence.file.as_deref().unwrap_or("<unknown>")
+}
+
+/// Quote a CSV field if required
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
@@ -0,0 +1,31 @@
+//! Advisory lock keeping concurrent runs in the same workspace from interleaving their writes
+
+use std::{
+    fs::{File, TryLockError},
+    io,
+    path::Path,
+};
+
+use crate::journal;
+
+/// Lock file, relative to the workspace root
+pub const PATH: &str = ".refix/lock";
+
+/// Take the lock of the workspace in the current directory, waiting for other runs to release it.
+/// The lock is held until the returned file is dropped.
+pub fn acquire() -> io::Result<File> {
+    journal::create_refix_dir()?;
+    let file = File::create(Path::new(PATH))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            eprintln!(
+                "waiting for another cargo refix run to finish (lock at {})",
+                PATH
+            );
+            file.lock()?;
+        }
+        Err(TryLockError::Error(err)) => return Err(err),
+    }
+    Ok(file)
+}
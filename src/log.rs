@@ -0,0 +1,122 @@
+//! Verbosity-controlled output
+
+use std::{
+    cell::RefCell,
+    fmt::{self, Write},
+    sync::{
+        atomic::{AtomicI8, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{pager, progress};
+
+/// Only the final summary is shown
+pub const QUIET: i8 = -1;
+/// Previews and per-item messages
+pub const NORMAL: i8 = 0;
+/// Compiler output and other details
+pub const VERBOSE: i8 = 1;
+/// Raw diagnostic messages
+pub const DEBUG: i8 = 2;
+
+static LEVEL: AtomicI8 = AtomicI8::new(NORMAL);
+
+/// Output collected for the pager, if paging
+static BUFFER: Mutex<Option<String>> = Mutex::new(None);
+
+thread_local! {
+    /// Output collected by `capture` on this thread
+    static CAPTURE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub fn set_level(level: i8) {
+    LEVEL.store(level, Ordering::Relaxed);
+}
+
+pub fn enabled(level: i8) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level
+}
+
+/// Collect the following output to be shown in a pager
+pub fn start_paging() {
+    *BUFFER.lock().unwrap() = Some(String::new());
+}
+
+/// Show the collected output in a pager, and stop collecting
+pub fn finish_paging() {
+    if let Some(buffer) = BUFFER.lock().unwrap().take() {
+        pager::page(&buffer);
+    }
+}
+
+/// Run `f`, collecting the output it prints on this thread instead of showing it
+pub fn capture<T>(f: impl FnOnce() -> T) -> (T, String) {
+    CAPTURE.with(|capture| *capture.borrow_mut() = Some(String::new()));
+    let result = f();
+    let output = CAPTURE.with(|capture| capture.borrow_mut().take());
+    (result, output.unwrap_or_default())
+}
+
+/// Show output collected by `capture`
+pub fn write_captured(output: &str) {
+    for line in output.lines() {
+        write_line(format_args!("{}", line));
+    }
+}
+
+pub fn write_line(args: fmt::Arguments) {
+    let captured = CAPTURE.with(|capture| {
+        capture
+            .borrow_mut()
+            .as_mut()
+            .map(|buffer| writeln!(buffer, "{}", args).expect("Unable to write to buffer"))
+            .is_some()
+    });
+    if captured {
+        return;
+    }
+
+    match BUFFER.lock().unwrap().as_mut() {
+        Some(buffer) => writeln!(buffer, "{}", args).expect("Unable to write to buffer"),
+        None => {
+            progress::clear();
+            println!("{}", args)
+        }
+    }
+}
+
+/// Print a line unless in quiet mode
+#[doc(hidden)]
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::NORMAL) {
+            $crate::log::write_line(format_args!($($arg)*));
+        }
+    };
+}
+
+/// Print a line in verbose mode
+#[doc(hidden)]
+#[macro_export]
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::VERBOSE) {
+            $crate::log::write_line(format_args!($($arg)*));
+        }
+    };
+}
+
+/// Print a line in debug mode
+#[doc(hidden)]
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::DEBUG) {
+            $crate::log::write_line(format_args!($($arg)*));
+        }
+    };
+}
+
+pub use crate::{debug, info, verbose};
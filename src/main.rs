@@ -1,25 +1,53 @@
-#![feature(byte_slice_trim_ascii, array_windows)]
 #![deny(unused_must_use)]
 
-mod apply;
 mod args;
-mod message;
-mod operation;
-mod selector;
-mod text;
+mod cargo;
+mod config;
+mod deps;
+mod eval;
+mod explain;
+mod git;
+mod journal;
+mod list;
+mod lock;
+mod markers;
+mod previews;
+mod reference;
+mod report;
+mod rule_test;
+mod rustfmt;
+mod settings;
+mod stale;
+mod stat;
+mod summary;
+#[cfg(feature = "tui")]
+mod tui;
+mod verify;
+mod watch;
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap},
     env,
     ffi::{OsStr, OsString},
+    fs,
+    io::{self, IsTerminal, Read},
     iter,
-    path::PathBuf,
-    process::Command,
+    path::{Path, PathBuf},
 };
 
-use clap::Parser;
+#[cfg(feature = "wasm")]
+use cargo_refix::plugin;
+use cargo_refix::{
+    apply, diff, interactive, log, message, operation, pattern, progress, recipe, selector, text,
+};
+use clap::{error::ErrorKind, ColorChoice, CommandFactory, Parser};
+use rayon::prelude::*;
 
-use crate::apply::FileChangeSet;
+use crate::{
+    apply::{FileChangeSet, Patch},
+    message::CompilerMessage,
+    operation::{Aborted, ExecError, Failure, Operation, SpanChange},
+};
 
 fn main() {
     let mut args = env::args_os().peekable();
@@ -34,89 +62,736 @@ fn main() {
         }
     }
 
-    let args = args::Args::parse_from(iter::once(bin_path_osstr).chain(args));
+    if args.peek() == Some(&OsString::from("ops")) {
+        let _ = args.next();
+        let ops_args = reference::OpsArgs::parse_from(iter::once(bin_path_osstr).chain(args));
+        colored::control::set_override(use_color(ColorChoice::Auto));
+        reference::print(&ops_args);
+        return;
+    }
+
+    if args.peek() == Some(&OsString::from("eval")) {
+        let _ = args.next();
+        let eval_args = eval::EvalArgs::parse_from(iter::once(bin_path_osstr).chain(args));
+        colored::control::set_override(use_color(ColorChoice::Auto));
+        eval::eval(&eval_args);
+        return;
+    }
+
+    if args.peek() == Some(&OsString::from("test")) {
+        let _ = args.next();
+        let test_args = rule_test::TestArgs::parse_from(iter::once(bin_path_osstr).chain(args));
+        colored::control::set_override(use_color(ColorChoice::Auto));
+        rule_test::test(&test_args);
+        return;
+    }
 
-    // Get path to the cargo binary
-    let cargo_bin = env::var_os("CARGO").unwrap_or(OsString::from("cargo"));
+    if args.peek() == Some(&OsString::from("explain")) {
+        let _ = args.next();
+        let explain_args = explain::ExplainArgs::parse_from(iter::once(bin_path_osstr).chain(args));
+        explain::explain(&explain_args);
+        return;
+    }
+
+    if args.peek() == Some(&OsString::from("undo")) {
+        let _ = args.next();
+        let undo_args = journal::UndoArgs::parse_from(iter::once(bin_path_osstr).chain(args));
+        journal::undo(&undo_args);
+        return;
+    }
 
-    let mut cmd = Command::new(cargo_bin);
-    if args.clippy {
-        cmd.arg("clippy");
+    if args.peek() == Some(&OsString::from("config")) {
+        let _ = args.next();
+        let config_args = settings::ConfigArgs::parse_from(iter::once(bin_path_osstr).chain(args));
+        settings::show(&config_args);
+        return;
+    }
+
+    // `watch` subcommand is a shorthand for `--watch`
+    let watch_flag = if args.peek() == Some(&OsString::from("watch")) {
+        let _ = args.next();
+        Some(OsString::from("--watch"))
     } else {
-        cmd.arg("check");
+        None
+    };
+
+    // Settings from config files and the environment go first, so that the command line overrides them
+    let command_line: Vec<OsString> = watch_flag.into_iter().chain(args).collect();
+    let mut args = settings::load_or_exit(false).parse(&bin_path_osstr, &command_line);
+    if let Err(err) = change_directory(&mut args) {
+        args::Args::command().error(ErrorKind::Io, err).exit();
+    }
+    // The project's settings can only be read in its directory, keeping the paths moved there
+    let project_args = settings::load_or_exit(true).parse(&bin_path_osstr, &command_line);
+    args = args::Args {
+        directory: args.directory,
+        manifest_path: args.manifest_path,
+        input: args.input,
+        ..project_args
+    };
+    colored::control::set_override(use_color(args.color));
+    if let Some(name) = &args.rule {
+        match rule_ops(name) {
+            Ok(ops) => args.operation.set_ops(ops),
+            Err(err) => args::Args::command()
+                .error(ErrorKind::InvalidValue, err)
+                .exit(),
+        }
     }
-    cmd.arg("--message-format=json");
-    cmd.args(args.passthrough);
+    log::set_level(if args.quiet {
+        log::QUIET
+    } else {
+        args.verbose.min(log::DEBUG as u8) as i8
+    });
 
-    let output = cmd.output().unwrap();
+    if let Some(recipe) = args.operation.recipe() {
+        let restriction = recipe.selector.parse().unwrap();
+        if let Err(err) = args.selector.restrict_to(&restriction) {
+            let err = format!(
+                "recipe {} is for {}, but {}",
+                recipe.name, recipe.selector, err
+            );
+            args::Args::command()
+                .error(ErrorKind::ArgumentConflict, err)
+                .exit();
+        }
+    }
 
-    let stderr = String::from_utf8_lossy(&output.stderr);
-    dbg!(stderr);
+    // Mistakes in the operations would otherwise only show up after cargo finishes
+    if let Err(err) = args.operation.validate() {
+        args::Args::command()
+            .error(ErrorKind::InvalidValue, err)
+            .exit();
+    }
 
-    let mut list_summary: HashMap<String, HashSet<String>> = HashMap::new();
+    if args.watch {
+        watch::watch(|| run(&args)).expect("Unable to watch files");
+    } else {
+        run(&args);
+    }
+}
+
+/// Move to the directory given with `-C`, and then to the workspace root of `--manifest-path`,
+/// as span file names are relative to it. Paths given as arguments are kept pointing to the same files.
+fn change_directory(args: &mut args::Args) -> io::Result<()> {
+    if let Some(dir) = &args.directory {
+        env::set_current_dir(dir)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", dir.display(), err)))?;
+    }
+    let Some(manifest_path) = &args.manifest_path else {
+        return Ok(());
+    };
+    let manifest_path = std::path::absolute(manifest_path)?;
+    if let Some(input) = args.input.as_mut().filter(|path| *path != Path::new("-")) {
+        *input = std::path::absolute(&input)?;
+    }
+    env::set_current_dir(cargo::workspace_root(&manifest_path)?)?;
+    args.manifest_path = Some(manifest_path);
+    Ok(())
+}
+
+/// Operations of the rule with the name in the config file
+fn rule_ops(name: &str) -> Result<Vec<String>, String> {
+    let config = config::load(Path::new(config::DEFAULT_PATH))
+        .map_err(|err| format!("unable to load {}: {}", config::DEFAULT_PATH, err))?;
+    config
+        .rules
+        .into_iter()
+        .find(|rule| rule.name == name)
+        .map(|rule| rule.ops)
+        .ok_or_else(|| format!("no rule named {:?} in {}", name, config::DEFAULT_PATH))
+}
+
+/// Process the diagnostics once
+fn run(args: &args::Args) {
+    // Files can only be collapsed when the previews are shown without a pager
+    let collapsible = args.preview_by_file
+        && !args.quiet
+        && io::stdin().is_terminal()
+        && io::stdout().is_terminal();
+    let paging = !args.no_pager
+        && !args.watch
+        && !args.edit
+        && !args.operation.is_interactive()
+        && !args.review()
+        && !collapsible
+        && io::stdout().is_terminal();
+    let show_progress =
+        !args.quiet && !args.edit && !args.operation.is_interactive() && io::stderr().is_terminal();
+
+    // Reading diagnostics from a file without writing anything can't interfere with other runs
+    let needs_lock = !args.no_lock && (args.input.is_none() || args.write || args.stage);
+    let _lock = needs_lock
+        .then(|| {
+            lock::acquire()
+                .inspect_err(|err| eprintln!("warning: unable to lock {}: {}", lock::PATH, err))
+                .ok()
+        })
+        .flatten();
+
+    let mut lines: Box<dyn Iterator<Item = Vec<u8>>> = match args.input.as_deref() {
+        Some(path) => {
+            let input = read_input(path).unwrap_or_else(|err| {
+                let err = format!("{}: {}", path.display(), err);
+                args::Args::command().error(ErrorKind::Io, err).exit()
+            });
+            let lines: Vec<_> = input.split(|c| *c == b'\n').map(<[u8]>::to_vec).collect();
+            Box::new(lines.into_iter())
+        }
+        None => Box::new(cargo::spawn(args)),
+    };
+    // All messages are kept for verifying the changes later
+    let mut output = Vec::new();
+
+    let excluded = args.excluded();
+    let members = match args.include_deps {
+        true => None,
+        false => cargo::workspace_members(args)
+            .inspect_err(|err| log::verbose!("not skipping dependencies: {}", err))
+            .ok(),
+    };
+
+    if paging {
+        log::start_paging();
+    }
+
+    let mut list_summary = list::ListSummary::default();
     let mut changeset = Vec::new();
+    let mut reviewed = Vec::new();
+    let mut report = report::Report::default();
+    let mut snapshots = stale::Snapshots::default();
+    let mut ignore_markers = markers::IgnoreMarkers::default();
+    let mut file_previews = previews::FilePreviews::default();
+    let verify_patches = args.verify_patches || args.write || args.stage;
+    let mut sources = HashMap::new();
+    let mut accepted = HashMap::new();
+    let mut summary = summary::Summary::default();
+    let mut selection = Selection::default();
+    // With --min-count and --sample, matches are selected only after all of them are known
+    let defer = args.min_count.is_some() || args.sample.is_some();
+    let mut deferred = Vec::new();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut editions = HashMap::new();
+    let mut missing_deps = deps::MissingDeps::default();
 
-    for line in output.stdout.split(|c| *c == b'\n') {
+    for line in lines.by_ref() {
+        output.extend_from_slice(&line);
+        output.push(b'\n');
         if line.trim_ascii().is_empty() {
             continue;
         }
 
-        // println!("###\n{}\n###", String::from_utf8_lossy(&line));
-        let msg: message::Msg = serde_json::from_slice(line).unwrap();
+        log::debug!("{}", String::from_utf8_lossy(&line));
+        // Captured logs can have cargo's plain text output mixed in
+        let msg = match message::Msg::parse(&line) {
+            Ok(msg) => msg,
+            Err(err) => {
+                log::verbose!("skipping a line that isn't a JSON message: {}", err);
+                continue;
+            }
+        };
+        if show_progress
+            && matches!(
+                msg.reason.as_str(),
+                "compiler-artifact" | "build-script-executed"
+            )
+        {
+            if let Some((name, _)) = msg.package() {
+                progress::update(name);
+            }
+        }
+        if !args.packages.is_empty() && !args.packages.iter().any(|spec| msg.matches_package(spec))
+        {
+            continue;
+        }
+        // Messages without a package, such as ones from bare rustc, are always kept
+        let dependency = !msg.package_id.is_empty()
+            && members
+                .as_ref()
+                .is_some_and(|members| !members.contains(&msg.package_id));
+        if dependency && msg.reason == "compiler-message" {
+            continue;
+        }
+        if !args.target_kinds.is_empty()
+            && msg.reason == "compiler-message"
+            && !msg
+                .target
+                .as_ref()
+                .is_some_and(|target| args.target_kinds.contains(&target.kind()))
+        {
+            continue;
+        }
+
         if msg.reason == "compiler-message" && msg.message.as_ref().unwrap().is_singular() {
-            let message = msg.message.unwrap();
+            let manifest_path = msg.manifest_path;
+            let target = msg.target;
+            let edition = target.as_ref().and_then(|target| target.edition.clone());
+            let mut message = msg.message.unwrap();
+
+            let all_excluded = {
+                let mut primary_spans = message.primary_spans().peekable();
+                primary_spans.peek().is_some()
+                    && primary_spans.all(|span| excluded.is_match(&span.file_name))
+            };
+            if all_excluded || ignore_markers.is_ignored(&message) {
+                continue;
+            }
 
             // Apply selector
             if args.selector.matches(&message) {
+                args.selector.narrow(&mut message);
                 if matches!(args.selector.top, selector::TopLevelSelector::List) {
-                    let entry = list_summary
-                        .entry(message.code().unwrap().to_owned())
-                        .or_default();
-                    for span in &message.spans {
-                        entry.insert(span.file_name.clone());
-                    }
+                    list_summary.add(&message, target.as_ref());
+                    continue;
+                }
+                // As early as possible, so that edits made while cargo is still running are noticed
+                snapshots.record(&message);
+
+                if args.add_deps && missing_deps.add(&message, &manifest_path) {
+                    continue;
+                }
+
+                if defer {
+                    let code = message.code().unwrap_or_default().to_owned();
+                    *counts.entry(code).or_default() += 1;
+                    deferred.push((message, edition));
                     continue;
                 }
 
-                match args.operation.compute_diffs(&message) {
-                    Ok(changes) => {
-                        args.operation.preview(&message, &changes);
-                        changeset.extend(changes.into_iter());
+                if selection.add(args, message, edition) {
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(min_count) = args.min_count {
+        deferred.retain(|(message, _)| counts[message.code().unwrap_or_default()] >= min_count);
+    }
+    if let Some(amount) = args.sample {
+        deferred = sample(deferred, amount, args.seed);
+    }
+    for (message, edition) in deferred {
+        if selection.add(args, message, edition) {
+            break;
+        }
+    }
+    let selected = selection.selected;
+
+    progress::clear();
+    // Let cargo finish, so that all of its messages are available
+    for line in lines {
+        output.extend_from_slice(&line);
+        output.push(b'\n');
+    }
+
+    if args.edit {
+        for (message, _) in &selected {
+            summary.add_diagnostic(message.code());
+            if let Some(span) = message.primary_spans().next() {
+                interactive::open_in_editor(&span.file_name, span.line_start, span.column_start)
+                    .expect("Unable to start editor");
+            }
+        }
+    } else {
+        for ((message, edition), (result, failures)) in
+            selected.iter().zip(compute_all(&args.operation, &selected))
+        {
+            summary.add_diagnostic(message.code());
+            summary.failures.extend(failures);
+            match result {
+                Ok(mut changes) => {
+                    changes.retain(|c| !excluded.is_match(&c.change.file));
+                    if verify_patches {
+                        check_patches(&mut changes, &mut sources, &mut summary.failures);
+                    }
+                    check_conflicts(&mut changes, &mut accepted, &mut summary.failures);
+                    summary.changes += changes.len();
+                    report.add(message, &changes);
+                    for c in &changes {
+                        snapshots.record_file(&c.change.file);
+                    }
+                    if let Some(edition) = edition {
+                        for c in &changes {
+                            editions.insert(c.change.file.clone(), edition.clone());
+                        }
                     }
-                    Err(()) => {
-                        break;
+                    let code = message.code().unwrap_or_default().to_owned();
+                    if args.review() {
+                        let title = match message.code() {
+                            Some(code) => format!("{}: {}", code, message.message),
+                            None => message.message.clone(),
+                        };
+                        reviewed.extend(
+                            changes
+                                .into_iter()
+                                .map(|c| (code.clone(), title.clone(), c)),
+                        );
+                    } else {
+                        if args.preview_by_file {
+                            file_previews.add(
+                                &args.operation,
+                                message,
+                                &changes,
+                                args.show_rendered(),
+                                args.diff_style,
+                            );
+                        } else {
+                            if let Some(rendered) = message.show(args.show_rendered()) {
+                                if !changes.is_empty() {
+                                    log::info!("{}\n", rendered);
+                                }
+                            }
+                            args.operation.preview(message, &changes, args.diff_style);
+                        }
+                        changeset.extend(changes.into_iter().map(|c| (code.clone(), c.change)));
                     }
                 }
-
-                if args.single {
+                Err(Aborted) => {
                     break;
                 }
             }
         }
     }
 
+    file_previews.print(collapsible);
+    log::finish_paging();
+
+    if let Some(target) = &args.report {
+        report.write(target).expect("Unable to write the report");
+        if let Some(path) = &target.path {
+            log::info!("report written to {}", path.display());
+        }
+    }
+
     if matches!(args.selector.top, selector::TopLevelSelector::List) {
-        for (code, files) in list_summary {
-            print!("{}:", code);
-            for file in files {
-                print!(" {}", file);
+        list_summary.print(args.format, args.group_by, args.list_detail, args.explain);
+        return;
+    }
+
+    #[cfg(feature = "tui")]
+    if args.review() {
+        let items = reviewed
+            .into_iter()
+            .map(|(code, title, change)| tui::Item::new(code, title, change))
+            .collect();
+        changeset = tui::review(items).expect("Unable to run the review interface");
+    }
+
+    if !missing_deps.is_empty() {
+        missing_deps.print();
+        if args.write {
+            missing_deps.write().expect("Unable to run cargo add");
+        }
+    }
+
+    if args.write || args.stage {
+        let mut stale = BTreeSet::new();
+        for (_, change) in &changeset {
+            if !stale.contains(&change.file) && snapshots.is_stale(&change.file) {
+                log::info!(
+                    "skipping {}: changed since its diagnostics were read",
+                    change.file.display()
+                );
+                stale.insert(change.file.clone());
             }
-            println!();
         }
+        changeset.retain(|(_, change)| !stale.contains(&change.file));
     }
 
     let amount = changeset.len();
-    let fcs = FileChangeSet::group(changeset);
-    if args.write {
-        print!("writing ");
+    let fcs = FileChangeSet::group(changeset.iter().map(|(_, c)| c.clone()).collect());
+    if args.stat {
+        stat::print(&fcs);
+    }
+    summary.print(fcs.len());
+    let action = if args.write {
+        "writing"
+    } else if args.stage {
+        "staging"
     } else {
-        print!("dry-run: would write ");
+        "dry-run: would write"
+    };
+    log::info!("{} {} to {} files", action, amount, fcs.len());
+    if args.stage {
+        git::stage(&fcs).expect("Unable to stage changes");
     }
-    println!("{} to {} files", amount, fcs.len());
     if args.write {
         // TODO: dirty check
-        for fc in fcs {
-            fc.write().unwrap();
+        let rustfmt = args.fmt.map(|scope| rustfmt::Rustfmt { scope, editions });
+        let mut journal = journal::Journal::default();
+        if let Some(template) = &args.commit {
+            let mut originals = Vec::new();
+            for fc in &fcs {
+                let original = fs::read(fc.file()).unwrap();
+                if fc.check(&original).is_ok() {
+                    originals.push((fc.file().to_owned(), original));
+                }
+            }
+            let selector = args.selector.to_string();
+            git::write_and_commit(
+                changeset,
+                template,
+                &selector,
+                args.commit_per_code,
+                rustfmt.as_ref(),
+            )
+            .expect("Unable to commit changes");
+            // Record the final contents, so that undo also reverts the formatting
+            for (file, original) in originals {
+                journal.add(&file, &original, &fs::read(&file).unwrap_or_default());
+            }
+            save_journal(&journal);
+        } else {
+            let mut changed = Vec::new();
+            let mut originals = Vec::new();
+            for fc in fcs {
+                let original = fs::read(fc.file()).unwrap();
+                if let Err(err) = fc.check(&original) {
+                    log::info!("skipping {}: {}", fc.file().display(), err);
+                    continue;
+                }
+                changed.push((fc.file().to_owned(), fc.changed_lines(&original)));
+                originals.push((fc.file().to_owned(), original));
+                fc.write().unwrap();
+            }
+            if let Some(rustfmt) = rustfmt {
+                rustfmt.format(&changed).expect("Unable to run rustfmt");
+            }
+
+            if check_written(args, &output) {
+                for (file, original) in &originals {
+                    journal.add(file, original, &fs::read(file).unwrap_or_default());
+                }
+                save_journal(&journal);
+            } else {
+                log::info!("restoring {} files", originals.len());
+                for (file, original) in originals {
+                    fs::write(file, original).unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// Skip the changes whose spans don't match the files, recording them as failures,
+/// before they could corrupt the files
+fn check_patches(
+    changes: &mut Vec<SpanChange>,
+    sources: &mut HashMap<String, Vec<u8>>,
+    failures: &mut Vec<Failure>,
+) {
+    changes.retain(|SpanChange { span, .. }| {
+        let source = sources
+            .entry(span.file_name.clone())
+            .or_insert_with(|| fs::read(&span.file_name).unwrap_or_default());
+        let Err(range) = span.check_source(source) else {
+            return true;
+        };
+        log::info!(
+            "{}:{}: skipped, span doesn't match the file at bytes {}..{}",
+            span.file_name,
+            span.line_start,
+            range.start,
+            range.end
+        );
+        failures.push(Failure {
+            file: Some(span.file_name.clone()),
+            line: Some(span.line_start),
+            error: ExecError::PatchMismatch,
+        });
+        false
+    });
+}
+
+/// Skip the changes overlapping different changes accepted before them, recording them
+/// as failures, as the changes of a file can only be applied together if they don't overlap.
+/// Changes identical to an accepted one are dropped, as they are already made.
+fn check_conflicts(
+    changes: &mut Vec<SpanChange>,
+    accepted: &mut HashMap<PathBuf, Vec<Patch>>,
+    failures: &mut Vec<Failure>,
+) {
+    changes.retain(|SpanChange { span, change, .. }| {
+        let patches = accepted.entry(change.file.clone()).or_default();
+        let location = &change.patch.location;
+        let same = |patch: &Patch| patch.location == *location && patch.bytes == change.patch.bytes;
+        if patches.iter().any(same) {
+            return false;
         }
+        let conflicts = patches.iter().any(|patch| {
+            patch.location.start < location.end && location.start < patch.location.end
+        });
+        if !conflicts {
+            patches.push(change.patch.clone());
+            return true;
+        }
+        log::info!(
+            "{}:{}: skipped, overlaps another change",
+            span.file_name,
+            span.line_start
+        );
+        failures.push(Failure {
+            file: Some(span.file_name.clone()),
+            line: Some(span.line_start),
+            error: ExecError::Conflict,
+        });
+        false
+    });
+}
+
+/// Record the written changes, so that `cargo refix undo` can revert them
+fn save_journal(journal: &journal::Journal) {
+    if journal.is_empty() {
+        return;
+    }
+    match journal.save() {
+        Ok(id) => log::info!("recorded as {}, revert with `cargo refix undo`", id),
+        Err(err) => eprintln!("warning: unable to record the changes for undo: {}", err),
+    }
+}
+
+/// Messages selected for processing, limited by `--skip`, `--limit` and `--limit-per-file`
+#[derive(Default)]
+struct Selection {
+    matched: usize,
+    processed: usize,
+    processed_per_file: HashMap<String, usize>,
+    selected: Vec<(CompilerMessage, Option<String>)>,
+}
+
+impl Selection {
+    /// Select the matched message unless limited, returning whether to stop selecting more
+    fn add(
+        &mut self,
+        args: &args::Args,
+        message: CompilerMessage,
+        edition: Option<String>,
+    ) -> bool {
+        self.matched += 1;
+        if self.matched <= args.skip {
+            return false;
+        }
+
+        if let Some(limit) = args.limit_per_file {
+            let file = message
+                .primary_spans()
+                .next()
+                .map(|span| span.file_name.clone())
+                .unwrap_or_default();
+            let count = self.processed_per_file.entry(file).or_default();
+            if *count >= limit {
+                return false;
+            }
+            *count += 1;
+        }
+
+        self.processed += 1;
+        if args.limit.is_some_and(|limit| self.processed > limit) {
+            return true;
+        }
+
+        self.selected.push((message, edition));
+        args.single || args.operation.is_stepping()
+    }
+}
+
+/// Randomly chosen items, in their original order. The same seed always picks the same items.
+fn sample<T>(items: Vec<T>, amount: usize, seed: u64) -> Vec<T> {
+    let mut rng = fastrand::Rng::with_seed(seed);
+    let mut indices: Vec<usize> = (0..items.len()).collect();
+    rng.shuffle(&mut indices);
+    indices.truncate(amount);
+    indices.sort_unstable();
+    let mut indices = indices.into_iter().peekable();
+    items
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| indices.next_if_eq(i).is_some())
+        .map(|(_, item)| item)
+        .collect()
+}
+
+/// Changes computed for a message, with the failures recorded while computing them
+type Computed = (Result<Vec<SpanChange>, Aborted>, Vec<Failure>);
+
+/// Compute the changes for each message. Unless the operation asks the user for input,
+/// they are computed in parallel, and output printed meanwhile is shown in order when
+/// iterating the results.
+fn compute_all<'a>(
+    operation: &'a Operation,
+    selected: &'a [(CompilerMessage, Option<String>)],
+) -> Box<dyn Iterator<Item = Computed> + 'a> {
+    let compute = |message: &CompilerMessage| {
+        let mut failures = Vec::new();
+        (operation.compute_diffs(message, &mut failures), failures)
+    };
+
+    if operation.is_interactive() {
+        return Box::new(selected.iter().map(move |(message, _)| compute(message)));
+    }
+
+    let computed: Vec<_> = selected
+        .par_iter()
+        .map(|(message, _)| log::capture(|| compute(message)))
+        .collect();
+    Box::new(computed.into_iter().map(|(computed, output)| {
+        log::write_captured(&output);
+        computed
+    }))
+}
+
+/// Run the `--verify` and `--test-cmd` checks on the written files, returning whether they passed
+fn check_written(args: &args::Args, output: &[u8]) -> bool {
+    if args.verify {
+        let broken = verify::new_errors(output, &cargo::run(args));
+        if !broken.is_empty() {
+            println!("verify: {} new errors", broken.len());
+            for error in &broken {
+                match error.primary_spans().next() {
+                    Some(span) => println!(
+                        "  {}:{}: {}",
+                        span.file_name, span.line_start, error.message
+                    ),
+                    None => println!("  {}", error.message),
+                }
+            }
+            return false;
+        }
+        println!("verify: no new errors");
+    }
+
+    if let Some(command) = &args.test_cmd {
+        let status = verify::run_shell(command).expect("Unable to run test command");
+        if !status.success() {
+            println!("test-cmd: `{}` failed with {}", command, status);
+            return false;
+        }
+        println!("test-cmd: passed");
+    }
+
+    true
+}
+
+/// Whether to color the output
+fn use_color(choice: ColorChoice) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+            !no_color && io::stdout().is_terminal()
+        }
+    }
+}
+
+/// Read previously captured JSON messages from a file, or stdin if path is `-`
+fn read_input(path: &Path) -> io::Result<Vec<u8>> {
+    if path == Path::new("-") {
+        let mut buffer = Vec::new();
+        io::stdin().read_to_end(&mut buffer)?;
+        Ok(buffer)
+    } else {
+        fs::read(path)
     }
 }
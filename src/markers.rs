@@ -0,0 +1,86 @@
+//! `// refix:ignore` comments, which keep refix from touching the lines they mark
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+};
+
+use cargo_refix::message::CompilerMessage;
+
+/// Marks the line it is on
+const IGNORE: &str = "refix:ignore";
+/// Marks the line after it
+const IGNORE_NEXT_LINE: &str = "refix:ignore-next-line";
+
+/// Ignored line numbers of the files, read when first needed
+#[derive(Default)]
+pub struct IgnoreMarkers(HashMap<String, BTreeSet<usize>>);
+
+/// Numbers of the lines marked by comments in the source, counting from one
+fn ignored_lines(source: &str) -> BTreeSet<usize> {
+    let mut lines = BTreeSet::new();
+    for (i, line) in source.lines().enumerate() {
+        // Any `//` can start the comment, as earlier ones can be in strings like "http://x"
+        let comments = line
+            .match_indices("//")
+            .filter(|(start, _)| !line[..*start].ends_with('/'))
+            .map(|(start, _)| &line[start + 2..]);
+        // The marker can be followed by a reason, but not be part of a longer word.
+        // Doc comments are not markers.
+        let marker = |marker: &str| {
+            comments.clone().any(|comment| {
+                !comment.starts_with('/')
+                    && comment
+                        .trim_start()
+                        .strip_prefix(marker)
+                        .is_some_and(|rest| {
+                            !rest.starts_with(|c: char| c.is_alphanumeric() || c == '-' || c == '_')
+                        })
+            })
+        };
+        if marker(IGNORE_NEXT_LINE) {
+            lines.insert(i + 2);
+        } else if marker(IGNORE) {
+            lines.insert(i + 1);
+        }
+    }
+    lines
+}
+
+impl IgnoreMarkers {
+    /// Whether a primary span of the message is on a marked line
+    pub fn is_ignored(&mut self, message: &CompilerMessage) -> bool {
+        message.primary_spans().any(|span| {
+            let lines = self.0.entry(span.file_name.clone()).or_insert_with(|| {
+                match fs::read_to_string(&span.file_name) {
+                    Ok(source) => ignored_lines(&source),
+                    Err(_) => BTreeSet::new(),
+                }
+            });
+            lines
+                .range(span.line_start..=span.line_end)
+                .next()
+                .is_some()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ignored_lines;
+
+    #[test]
+    fn test_ignored_lines() {
+        let source = "let x = 1; // refix:ignore\n\
+                      // refix:ignore-next-line: kept for the macro\n\
+                      let y = 2;\n\
+                      let z = 3; // refix:ignored\n\
+                      /// refix:ignore\n\
+                      let u = \"http://x\"; // refix:ignore\n\
+                      // refix:ignore-next-line: see https://example.com\n";
+        assert_eq!(
+            ignored_lines(source).into_iter().collect::<Vec<_>>(),
+            [1, 3, 6, 8]
+        );
+    }
+}
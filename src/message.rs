@@ -2,22 +2,227 @@
 
 #![allow(dead_code)]
 
-use std::{collections::HashMap, fmt::Display, ops};
+use std::{fmt::Display, ops};
 
-use crate::text::underline_span;
+use clap::ValueEnum;
 
-#[derive(Debug, serde::Deserialize)]
+use crate::{pattern::Pattern, text::underline_span};
+
+/// How to show the diagnostic as the compiler renders it, with the snippet and carets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ShowRendered {
+    /// With the colors of the compiler
+    Ansi,
+    /// Without colors
+    Plain,
+    /// Not at all
+    Off,
+}
+
+#[derive(Debug)]
 pub struct Msg {
     pub reason: String,
-    #[serde(default)]
     pub package_id: String,
-    #[serde(default)]
     pub manifest_path: String,
     pub target: Option<Target>,
     pub message: Option<CompilerMessage>,
+}
+
+impl Msg {
+    /// Parse a single line of either cargo `--message-format=json` output,
+    /// or `rustc --error-format=json` output. The latter lacks the cargo
+    /// wrapper, so bare diagnostics are wrapped as if cargo had emitted them.
+    ///
+    /// The line is read in one pass, skipping unknown keys without buffering them.
+    /// The keys of both formats are collected as they come, as they can only be
+    /// told apart once it's known whether `reason` is there.
+    pub fn parse(line: &[u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(line)
+    }
+}
+
+/// Keys of both cargo messages and bare rustc diagnostics
+#[derive(serde::Deserialize)]
+#[serde(field_identifier, rename_all = "snake_case")]
+enum MsgField {
+    Reason,
+    PackageId,
+    ManifestPath,
+    Target,
+    Message,
+    #[serde(rename = "$message_type")]
+    MessageType,
+    Code,
+    Level,
+    Spans,
+    Children,
+    Rendered,
+    #[serde(other)]
+    Other,
+}
+
+/// The `message` key, a nested diagnostic from cargo or the text of a bare one
+enum MessageField {
+    Nested(CompilerMessage),
+    Text(String),
+}
+
+impl<'de> serde::Deserialize<'de> for MessageField {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = MessageField;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a diagnostic or its text")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, text: &str) -> Result<Self::Value, E> {
+                Ok(MessageField::Text(text.to_owned()))
+            }
+
+            fn visit_string<E: serde::de::Error>(self, text: String) -> Result<Self::Value, E> {
+                Ok(MessageField::Text(text))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let map = serde::de::value::MapAccessDeserializer::new(map);
+                serde::Deserialize::deserialize(map).map(MessageField::Nested)
+            }
+        }
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Msg {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Msg;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a cargo message or a rustc diagnostic")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<Self::Value, A::Error> {
+                use serde::de::Error;
+
+                let mut reason: Option<String> = None;
+                let mut package_id = None;
+                let mut manifest_path = None;
+                let mut target = None;
+                let mut message = None;
+                let mut message_type: Option<String> = None;
+                let mut code = None;
+                let mut level = None;
+                let mut spans = None;
+                let mut children = None;
+                let mut rendered = None;
+                while let Some(key) = map.next_key()? {
+                    match key {
+                        MsgField::Reason => reason = Some(map.next_value()?),
+                        MsgField::PackageId => package_id = Some(map.next_value()?),
+                        MsgField::ManifestPath => manifest_path = Some(map.next_value()?),
+                        MsgField::Target => target = map.next_value()?,
+                        MsgField::Message => message = map.next_value()?,
+                        MsgField::MessageType => message_type = map.next_value()?,
+                        MsgField::Code => code = map.next_value()?,
+                        MsgField::Level => level = Some(map.next_value()?),
+                        MsgField::Spans => spans = Some(map.next_value()?),
+                        MsgField::Children => children = Some(map.next_value()?),
+                        MsgField::Rendered => rendered = map.next_value()?,
+                        MsgField::Other => {
+                            map.next_value::<serde::de::IgnoredAny>()?;
+                        }
+                    }
+                }
+
+                if let Some(reason) = reason {
+                    let message = match message {
+                        Some(MessageField::Nested(message)) => Some(message),
+                        Some(MessageField::Text(_)) => {
+                            return Err(A::Error::custom("expected a nested diagnostic"))
+                        }
+                        None => None,
+                    };
+                    return Ok(Msg {
+                        reason,
+                        package_id: package_id.unwrap_or_default(),
+                        manifest_path: manifest_path.unwrap_or_default(),
+                        target,
+                        message,
+                    });
+                }
+
+                let reason = match message_type.as_deref() {
+                    Some("diagnostic") | None => "compiler-message",
+                    Some(other) => other,
+                }
+                .to_owned();
+                let message = if reason == "compiler-message" {
+                    let Some(MessageField::Text(text)) = message else {
+                        return Err(A::Error::missing_field("message"));
+                    };
+                    Some(CompilerMessage {
+                        code,
+                        level: level.ok_or_else(|| A::Error::missing_field("level"))?,
+                        message: text,
+                        spans: spans.ok_or_else(|| A::Error::missing_field("spans"))?,
+                        children: children.ok_or_else(|| A::Error::missing_field("children"))?,
+                        rendered,
+                    })
+                } else {
+                    None
+                };
+                Ok(Msg {
+                    reason,
+                    package_id: String::new(),
+                    manifest_path: String::new(),
+                    target: None,
+                    message,
+                })
+            }
+        }
+        deserializer.deserialize_map(Visitor)
+    }
+}
 
-    #[serde(flatten)]
-    other: HashMap<String, serde_json::Value>,
+impl Msg {
+    /// Package name and version parsed from `package_id`.
+    /// Supports both the `name version (source)` and `source#name@version` formats.
+    pub fn package(&self) -> Option<(&str, &str)> {
+        if let Some((source, fragment)) = self.package_id.split_once('#') {
+            if let Some((name, version)) = fragment.split_once('@') {
+                return Some((name, version));
+            }
+            // Name is omitted if it matches the last path component of the source
+            let name = source.trim_end_matches('/').rsplit('/').next()?;
+            return Some((name, fragment));
+        }
+
+        let mut parts = self.package_id.split(' ');
+        let name = parts.next().filter(|name| !name.is_empty())?;
+        let version = parts.next()?;
+        Some((name, version))
+    }
+
+    /// Check if the message belongs to a package matching a spec of form `name` or `name@version`.
+    /// Messages without package information, such as ones from bare rustc, always match.
+    pub fn matches_package(&self, spec: &str) -> bool {
+        let Some((name, version)) = self.package() else {
+            return true;
+        };
+        match spec.split_once('@') {
+            Some((spec_name, spec_version)) => name == spec_name && version == spec_version,
+            None => name == spec,
+        }
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -29,8 +234,8 @@ pub struct CompilerMessage {
 
     pub children: Vec<CompilerMessage>,
 
-    #[serde(flatten)]
-    other: HashMap<String, serde_json::Value>,
+    /// The diagnostic as rustc would print it
+    pub rendered: Option<String>,
 }
 
 impl CompilerMessage {
@@ -39,12 +244,46 @@ impl CompilerMessage {
         self.level != "failure-note" && !self.message.starts_with("aborting due")
     }
 
+    /// Human-readable form of the diagnostic without colors,
+    /// reconstructed if rustc did not provide one
+    pub fn render(&self) -> String {
+        if let Some(rendered) = self.rendered.as_ref().filter(|r| !r.trim().is_empty()) {
+            return strip_ansi(rendered).trim_end().to_owned();
+        }
+
+        let mut result = match self.code() {
+            Some(code) => format!("{}[{}]: {}", self.level, code, self.message),
+            None => format!("{}: {}", self.level, self.message),
+        };
+        if let Some(span) = self.primary_spans().next() {
+            result.push_str(&format!(
+                "\n  --> {}:{}:{}",
+                span.file_name, span.line_start, span.column_start
+            ));
+        }
+        for child in &self.children {
+            result.push_str(&format!("\n  = {}: {}", child.level, child.message));
+        }
+        result
+    }
+
+    /// Rendered form of the diagnostic in the given style, if any
+    pub fn show(&self, style: ShowRendered) -> Option<String> {
+        match style {
+            ShowRendered::Ansi => Some(match &self.rendered {
+                Some(rendered) if !rendered.trim().is_empty() => rendered.trim_end().to_owned(),
+                _ => self.render(),
+            }),
+            ShowRendered::Plain => Some(self.render()),
+            ShowRendered::Off => None,
+        }
+    }
+
     /// Error code or lint name as text, if any
     pub fn code(&self) -> Option<&str> {
         self.code
             .as_ref()
-            .map(|code| code.code.as_ref())
-            .flatten()
+            .and_then(|code| code.code.as_ref())
             .map(|code| code.as_str())
     }
 
@@ -65,10 +304,86 @@ impl CompilerMessage {
             })
     }
 
-    pub fn spans_with_suggestions(&self) -> impl Iterator<Item = SpanAndSuggestions> + '_ {
-        self.primary_spans().map(|primary| {
-            let mut suggestions: Vec<_> = self
-                .help_items()
+    /// Span of the n:th help item that has one
+    pub fn help_span(&self, index: usize) -> Option<&Span> {
+        self.children
+            .iter()
+            .filter(|child| child.level == "help")
+            .filter_map(|child| child.spans.first())
+            .nth(index)
+    }
+
+    /// Span of the first note with a message matching the regex
+    pub fn note_span(&self, re: &Pattern) -> Option<&Span> {
+        self.children
+            .iter()
+            .filter(|child| child.level == "note" && re.is_match(&child.message))
+            .find_map(|child| child.spans.first())
+    }
+
+    /// Suggestions grouped into alternatives, each consisting of one or more spans.
+    /// Every help item is an alternative, except that spans of a help item
+    /// replacing the same range are alternatives of each other.
+    pub fn suggestion_alternatives(&self) -> Vec<Vec<&Span>> {
+        let mut alternatives = Vec::new();
+        for child in self.children.iter().filter(|child| child.level == "help") {
+            let spans: Vec<&Span> = child
+                .spans
+                .iter()
+                .filter(|span| span.suggested_replacement.is_some())
+                .collect();
+
+            let same_range = spans
+                .windows(2)
+                .all(|w| (w[0].byte_start, w[0].byte_end) == (w[1].byte_start, w[1].byte_end));
+            if spans.len() > 1 && same_range {
+                alternatives.extend(spans.into_iter().map(|span| vec![span]));
+            } else if !spans.is_empty() {
+                alternatives.push(spans);
+            }
+        }
+        alternatives
+    }
+
+    /// Spans of a suggestion on other lines than the primary spans, like a return type
+    /// for the function of the primary span. Only given when the suggestion is unambiguous:
+    /// the alternative picked, or the only one there is.
+    pub fn detached_suggestions(&self, alternative: Option<usize>) -> Vec<&Span> {
+        let alternatives = self.suggestion_alternatives();
+        let spans = match alternative {
+            Some(index) => alternatives.get(index).cloned().unwrap_or_default(),
+            None if alternatives.len() == 1 => alternatives[0].clone(),
+            None => Vec::new(),
+        };
+        spans
+            .into_iter()
+            .filter(|help| {
+                help.text.len() == 1
+                    && !self
+                        .primary_spans()
+                        .any(|primary| primary.raw_text() == help.raw_text())
+            })
+            .collect()
+    }
+
+    /// Primary spans with the suggestions applying to them.
+    /// If `alternative` is given, only that suggestion alternative is used.
+    pub fn spans_with_suggestions(
+        &self,
+        alternative: Option<usize>,
+    ) -> impl Iterator<Item = SpanAndSuggestions> + '_ {
+        let help_items: Vec<&Span> = match alternative {
+            Some(index) => self
+                .suggestion_alternatives()
+                .get(index)
+                .cloned()
+                .unwrap_or_default(),
+            None => self.help_items().collect(),
+        };
+
+        self.primary_spans().map(move |primary| {
+            let mut suggestions: Vec<_> = help_items
+                .iter()
                 .filter(|help| primary.raw_text() == help.raw_text() && help.text.len() == 1)
                 .map(|s| {
                     let replacement = s.suggested_replacement.as_ref().unwrap();
@@ -111,6 +426,18 @@ pub enum SuggestionApplicability {
     HasPlaceholders,
     Unspecified,
 }
+
+impl Display for SuggestionApplicability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::MachineApplicable => "machine-applicable",
+            Self::MaybeIncorrect => "maybe-incorrect",
+            Self::HasPlaceholders => "has-placeholders",
+            Self::Unspecified => "unspecified",
+        };
+        write!(f, "{}", name)
+    }
+}
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct Span {
     pub file_name: String,
@@ -128,19 +455,130 @@ pub struct Span {
     pub suggested_replacement: Option<String>,
     pub suggestion_applicability: Option<SuggestionApplicability>,
 
-    #[serde(flatten)]
-    other: HashMap<String, serde_json::Value>,
+    /// Set if the span points into code generated by a macro
+    pub expansion: Option<Box<SpanMacroExpansion>>,
 }
 
 impl Span {
-    pub fn outer_byte_range(&self) -> ops::Range<usize> {
-        let len = self.raw_text().len();
+    /// If this span is inside a macro expansion, the outermost invocation
+    /// of the macro, i.e. the one written in the source code.
+    /// Desugarings like that of `?` are written in the source code already.
+    pub fn macro_call_site(&self) -> Option<&SpanMacroExpansion> {
+        let mut expansion = self
+            .expansion
+            .as_deref()
+            .filter(|expansion| !expansion.macro_decl_name.starts_with("desugaring of "))?;
+        while let Some(outer) = expansion.span.expansion.as_deref() {
+            expansion = outer;
+        }
+        Some(expansion)
+    }
+
+    /// Span highlighting a byte range of a file, with the lines it covers as a single text part
+    pub fn from_source(file_name: &str, source: &str, highlight: ops::Range<usize>) -> Span {
+        Self::from_source_in(file_name, source, highlight.clone(), highlight)
+    }
+
+    /// Span highlighting a byte range of a file, with the lines of the enclosing
+    /// context range as a single text part
+    pub fn from_source_in(
+        file_name: &str,
+        source: &str,
+        highlight: ops::Range<usize>,
+        context: ops::Range<usize>,
+    ) -> Span {
+        let start = source[..context.start].rfind('\n').map_or(0, |i| i + 1);
+        let end = source[context.end..]
+            .find('\n')
+            .map_or(source.len(), |i| context.end + i);
+        let line_start = source[..highlight.start].matches('\n').count() + 1;
+        let column = |i: usize| source[..i].rsplit('\n').next().unwrap().chars().count() + 1;
+        let highlight_column = |i: usize| source[start..i].chars().count() + 1;
+        Span {
+            file_name: file_name.to_owned(),
+            byte_start: highlight.start,
+            byte_end: highlight.end,
+            line_start,
+            line_end: line_start + source[highlight.clone()].matches('\n').count(),
+            column_start: column(highlight.start),
+            column_end: column(highlight.end),
+            text: vec![SpanText {
+                highlight_start: highlight_column(highlight.start),
+                highlight_end: highlight_column(highlight.end),
+                text: source[start..end].to_owned(),
+            }],
+            label: None,
+            is_primary: false,
+            suggested_replacement: None,
+            suggestion_applicability: None,
+            expansion: None,
+        }
+    }
+
+    /// Check that the file has the text of the span at its offsets, so that a patch of
+    /// [`Span::outer_byte_range`] replaces what the operations saw. Offsets are off e.g. when
+    /// rustc has skipped a byte order mark, or the file has changed since it was checked.
+    /// Returns the mismatching byte range.
+    pub fn check_source(&self, source: &[u8]) -> Result<(), ops::Range<usize>> {
+        let expected = self.text_with(self.line_break(source));
+        let outer = self
+            .outer_start()
+            .map(|start| start..start + expected.len())
+            .ok_or(self.byte_start..self.byte_end)?;
+        if source.get(outer.clone()) != Some(expected.as_bytes()) {
+            return Err(outer);
+        }
+        let highlight_matches = match &self.text[..] {
+            [text] => {
+                source.get(self.byte_start..self.byte_end) == Some(text.highlighted().as_bytes())
+            }
+            // The highlight must end where the line breaks of the file put it
+            [.., last] => {
+                outer.end - last.text.len() + last.highlighted_span().end == self.byte_end
+            }
+            [] => true,
+        };
+        if !highlight_matches {
+            return Err(self.byte_start..self.byte_end);
+        }
+        Ok(())
+    }
+
+    /// Offset of the start of the first line of the span
+    fn outer_start(&self) -> Option<usize> {
+        let text = self.text.first()?;
+        self.byte_start.checked_sub(text.highlighted_span().start)
+    }
+
+    /// Line break the file has after the first line of the span. Only matters for spans
+    /// of several lines, as the lines are given without them.
+    pub fn line_break(&self, source: &[u8]) -> &'static str {
+        let end = self
+            .outer_start()
+            .zip(self.text.first())
+            .map(|(start, text)| start + text.text.len());
+        match end.and_then(|end| source.get(end..)) {
+            Some([b'\r', b'\n', ..]) => "\r\n",
+            _ => "\n",
+        }
+    }
+
+    /// Byte range of the whole lines of the span, given the line break between them
+    pub fn outer_byte_range(&self, line_break: &str) -> ops::Range<usize> {
+        let len = self.text_with(line_break).len();
         let s = self.byte_start - self.text[0].highlighted_span().start;
         s..s + len
     }
 
+    /// The lines of the span, separated by the line break
+    pub fn text_with(&self, line_break: &str) -> String {
+        let lines: Vec<&str> = self.text.iter().map(|text| text.text.as_str()).collect();
+        lines.join(line_break)
+    }
+
+    /// The lines of the span, as shown in previews
     pub fn raw_text(&self) -> String {
-        self.text.iter().map(|text| text.text.clone()).collect()
+        self.text_with("\n")
     }
 }
 
@@ -153,6 +591,16 @@ impl Display for Span {
     }
 }
 
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct SpanMacroExpansion {
+    /// Span of the macro invocation
+    pub span: Span,
+    /// Name of the macro, such as `println!` or `#[derive(Debug)]`
+    pub macro_decl_name: String,
+    /// Span of the macro definition, if available
+    pub def_site_span: Option<Span>,
+}
+
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct SpanText {
     pub highlight_end: usize,
@@ -160,20 +608,28 @@ pub struct SpanText {
     pub text: String,
 }
 impl SpanText {
+    /// Byte range of the highlight. The JSON has 1-based character columns instead.
     pub fn highlighted_span(&self) -> ops::Range<usize> {
-        (self.highlight_start - 1)..(self.highlight_end - 1)
+        let byte = |column: usize| {
+            self.text
+                .char_indices()
+                .nth(column.saturating_sub(1))
+                .map_or(self.text.len(), |(i, _)| i)
+        };
+        byte(self.highlight_start)..byte(self.highlight_end)
     }
 
     pub fn highlighted(&self) -> &str {
-        &self.text[(self.highlight_start - 1)..(self.highlight_end - 1)]
+        &self.text[self.highlighted_span()]
     }
 
     pub fn replace_highlighted(&self, replacement: &str) -> String {
+        let span = self.highlighted_span();
         format!(
             "{}{}{}",
-            &self.text[..(self.highlight_start - 1)],
+            &self.text[..span.start],
             replacement,
-            &self.text[(self.highlight_end - 1)..]
+            &self.text[span.end..]
         )
     }
 }
@@ -190,5 +646,193 @@ pub struct Target {
     pub name: String,
     pub src_path: String,
     #[serde(default)]
+    pub edition: Option<String>,
+    #[serde(default)]
     features: Vec<String>,
 }
+
+impl Target {
+    /// Kind of the target. Unit tests have the kind of the target they are in,
+    /// as cargo doesn't tell them apart in diagnostics.
+    pub fn kind(&self) -> TargetKind {
+        match self.kind.first().map(String::as_str) {
+            Some("bin") => TargetKind::Bin,
+            Some("test") => TargetKind::Test,
+            Some("example") => TargetKind::Example,
+            Some("bench") => TargetKind::Bench,
+            Some("custom-build") => TargetKind::CustomBuild,
+            _ => TargetKind::Lib,
+        }
+    }
+
+    /// Kind and name of the target, e.g. `lib foo` or `test integration`
+    pub fn label(&self) -> String {
+        format!("{} {}", <&str>::from(self.kind()), self.name)
+    }
+}
+
+/// Kind of a cargo target
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, strum::IntoStaticStr)]
+#[strum(serialize_all = "kebab-case")]
+pub enum TargetKind {
+    /// Library of any crate type, including proc macros
+    Lib,
+    Bin,
+    /// Integration test
+    Test,
+    Example,
+    Bench,
+    /// Build script
+    CustomBuild,
+}
+
+/// Text without ANSI escape sequences, like the colors of `--message-format=json-diagnostic-rendered-ansi`
+fn strip_ansi(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            plain.push(c);
+        } else if chars.next() == Some('[') {
+            // Control sequences end with a letter, e.g. `\x1b[1;31m`
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        }
+    }
+    plain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{strip_ansi, Msg};
+
+    const DIAGNOSTIC: &str = r#"{"$message_type":"diagnostic","message":"unused variable: `x`","code":{"code":"unused_variables","explanation":null},"level":"warning","spans":[{"file_name":"src/main.rs","byte_start":4,"byte_end":5,"line_start":1,"line_end":1,"column_start":5,"column_end":6,"is_primary":true,"text":[{"text":"let x = 1;","highlight_start":5,"highlight_end":6}],"label":null,"suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[],"rendered":""}"#;
+
+    #[test]
+    fn test_parse_rustc_message() {
+        let msg = Msg::parse(DIAGNOSTIC.as_bytes()).unwrap();
+        assert_eq!(msg.reason, "compiler-message");
+        let message = msg.message.unwrap();
+        assert_eq!(message.code(), Some("unused_variables"));
+        assert_eq!(message.spans[0].raw_text(), "let x = 1;");
+        assert!(message.spans[0].check_source(b"let x = 1;\n").is_ok());
+        assert!(message.spans[0]
+            .check_source(b"\xef\xbb\xbflet x = 1;\n")
+            .is_err());
+        assert!(message.spans[0].check_source(b"let").is_err());
+        assert_eq!(
+            message.render(),
+            "warning[unused_variables]: unused variable: `x`\n  --> src/main.rs:1:5"
+        );
+    }
+
+    #[test]
+    fn test_multi_line_span() {
+        let diagnostic = DIAGNOSTIC
+            .replace(
+                r#""line_end":1"#,
+                r#""line_end":3"#,
+            )
+            .replace(
+                r#""byte_start":4,"byte_end":5"#,
+                r#""byte_start":4,"byte_end":19"#,
+            )
+            .replace(
+                r#"[{"text":"let x = 1;","highlight_start":5,"highlight_end":6}]"#,
+                r#"[{"text":"let x = f(","highlight_start":5,"highlight_end":11},{"text":"    1,","highlight_start":1,"highlight_end":7},{"text":");","highlight_start":1,"highlight_end":2}]"#,
+            );
+        let msg = Msg::parse(diagnostic.as_bytes()).unwrap();
+        let span = &msg.message.unwrap().spans[0];
+        assert_eq!(span.raw_text(), "let x = f(\n    1,\n);");
+
+        let source = b"let x = f(\n    1,\n);\n";
+        assert!(span.check_source(source).is_ok());
+        assert_eq!(span.line_break(source), "\n");
+        assert_eq!(span.outer_byte_range("\n"), 0..20);
+        assert!(span.check_source(b"let x = f(    1,);\n").is_err());
+
+        // Offsets are those of the file, so they grow with the longer line breaks
+        let diagnostic = diagnostic.replace(r#""byte_end":19"#, r#""byte_end":21"#);
+        let msg = Msg::parse(diagnostic.as_bytes()).unwrap();
+        let span = &msg.message.unwrap().spans[0];
+        let source = b"let x = f(\r\n    1,\r\n);\r\n";
+        assert!(span.check_source(source).is_ok());
+        assert_eq!(span.line_break(source), "\r\n");
+        assert_eq!(span.outer_byte_range("\r\n"), 0..22);
+        assert!(span.check_source(b"let x = f(\n    1,\n);\n").is_err());
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(
+            strip_ansi("\x1b[0m\x1b[1m\x1b[33mwarning\x1b[0m: unused `é`\x1b[0m\n"),
+            "warning: unused `é`\n"
+        );
+    }
+
+    #[test]
+    fn test_multibyte_highlight() {
+        let diagnostic = DIAGNOSTIC
+            .replace("let x = 1;", "let é = 1; let x = 2;")
+            .replace(
+                "\"highlight_start\":5,\"highlight_end\":6",
+                "\"highlight_start\":16,\"highlight_end\":17",
+            );
+        let msg = Msg::parse(diagnostic.as_bytes()).unwrap();
+        let text = &msg.message.unwrap().spans[0].text[0];
+        assert_eq!(text.highlighted_span(), 16..17);
+        assert_eq!(text.highlighted(), "x");
+        assert_eq!(text.replace_highlighted("_x"), "let é = 1; let _x = 2;");
+
+        let span = super::Span::from_source("m.rs", "é\nlet é = x;", 12..13);
+        assert_eq!(span.text[0].highlighted(), "x");
+        assert_eq!(span.outer_byte_range("\n"), 3..14);
+    }
+
+    #[test]
+    fn test_parse_cargo_message() {
+        let line = format!(
+            r#"{{"reason":"compiler-message","package_id":"demo","manifest_path":"Cargo.toml","target":null,"message":{}}}"#,
+            DIAGNOSTIC
+        );
+        let msg = Msg::parse(line.as_bytes()).unwrap();
+        assert_eq!(msg.package_id, "demo");
+        assert_eq!(msg.message.unwrap().code(), Some("unused_variables"));
+
+        let msg = Msg::parse(br#"{"reason":"build-finished","success":true}"#).unwrap();
+        assert!(msg.message.is_none());
+
+        let msg = Msg::parse(br#"{"$message_type":"artifact","artifact":"a.rmeta"}"#).unwrap();
+        assert_eq!(msg.reason, "artifact");
+        assert!(msg.message.is_none());
+        assert!(Msg::parse(br#"{"$message_type":"diagnostic","level":"error"}"#).is_err());
+        assert!(Msg::parse(br#"{"reason":"compiler-message","message":"x"}"#).is_err());
+    }
+
+    #[test]
+    fn test_package_id() {
+        let with_id = |package_id: &str| {
+            let line =
+                format!(r#"{{"reason":"build-script-executed","package_id":"{package_id}"}}"#);
+            Msg::parse(line.as_bytes()).unwrap()
+        };
+
+        let msg = with_id("path+file:///tmp/demo#0.1.0");
+        assert_eq!(msg.package(), Some(("demo", "0.1.0")));
+        assert!(msg.matches_package("demo"));
+        assert!(msg.matches_package("demo@0.1.0"));
+        assert!(!msg.matches_package("demo@0.2.0"));
+
+        let msg = with_id("registry+https://github.com/rust-lang/crates.io-index#serde@1.0.0");
+        assert_eq!(msg.package(), Some(("serde", "1.0.0")));
+        assert!(!msg.matches_package("demo"));
+
+        let msg = with_id("demo 0.1.0 (path+file:///tmp/demo)");
+        assert_eq!(msg.package(), Some(("demo", "0.1.0")));
+
+        assert!(with_id("").matches_package("demo"));
+    }
+}
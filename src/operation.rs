@@ -1,85 +1,210 @@
-use std::{collections::VecDeque, ops, path::PathBuf, str::FromStr};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    env, fmt, fs,
+    io::Write,
+    mem, ops,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+};
 
-use clap::Args;
+use clap::{Args, ValueEnum};
 use colored::Colorize;
-use regex::Regex;
-use similar::{ChangeTag, TextDiff};
-use strum::EnumProperty;
+use regex::{Regex, RegexBuilder};
+use strum::{EnumMessage, EnumProperty, IntoEnumIterator};
 
+#[cfg(feature = "wasm")]
+use crate::plugin::Plugin;
+#[cfg(feature = "rhai")]
+use crate::script;
+#[cfg(feature = "tree-sitter")]
+use crate::ts;
 use crate::{
     apply::{Change, Patch},
-    message::{self, SpanAndSuggestions},
-    text::{find_matching_paren, template},
+    diff::{self, DiffStyle},
+    interactive,
+    log::{self, info},
+    message::{self, SpanAndSuggestions, SuggestionApplicability},
+    pattern::{Pattern, RegexEngine},
+    recipe::{self, Recipe},
+    ssr, syntax,
+    text::{self, find_matching_paren, template, underline_span},
 };
 
-#[derive(Debug, Clone, Copy, strum::EnumString, strum::EnumProperty)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    strum::EnumString,
+    strum::EnumProperty,
+    strum::EnumMessage,
+    strum::EnumIter,
+    strum::IntoStaticStr,
+)]
 pub enum TextOperation {
     /// Drop topmost stack element
     #[strum(serialize = "stack-drop", serialize = "s-drop")]
-    #[strum(props(argc = "0"))]
     StackDrop,
     /// Duplicate topmost stack element
     #[strum(serialize = "stack-dup", serialize = "s-dup")]
-    #[strum(props(argc = "0"))]
     StackDup,
     /// Push currently selected text to the stack
     #[strum(serialize = "stack-push", serialize = "s-push", serialize = "push")]
-    #[strum(props(argc = "0"))]
+    #[strum(props(example = "push next '\\w+' replace '$pop'"))]
     StackPush,
+    /// Pop two elements and push them concatenated, in the order they were pushed
+    #[strum(serialize = "stack-concat", serialize = "s-concat")]
+    #[strum(props(example = "push next '\\w+' push stack-concat replace '$pop'"))]
+    StackConcat,
+    /// Replace topmost stack element with its first regex match,
+    /// or the first capture group if the regex has one
+    #[strum(serialize = "stack-slice", serialize = "s-slice")]
+    #[strum(props(args = "regex", example = "push stack-slice '\\w+$' replace '$pop'"))]
+    StackSlice,
+    /// Substitute all regex matches in the topmost stack element
+    #[strum(serialize = "stack-apply", serialize = "s-apply")]
+    #[strum(props(
+        args = "regex replacement",
+        example = "push stack-apply '^&' '' replace '$pop'"
+    ))]
+    StackApply,
+    /// Save the currently selected text to a named register, replacing its old value
+    #[strum(serialize = "store")]
+    #[strum(props(
+        args = "register",
+        example = "store name next '\\w+' replace '$reg:name'"
+    ))]
+    Store,
+    /// Push the value of a named register to the stack
+    #[strum(serialize = "load")]
+    #[strum(props(args = "register"))]
+    Load,
+    /// Remember the current selection by name, to be used by `between`
+    #[strum(serialize = "mark")]
+    #[strum(props(args = "mark", example = "mark start next ',' between start delete"))]
+    Mark,
+    /// Select from the named mark to the current selection, including both
+    #[strum(serialize = "between")]
+    #[strum(props(args = "mark"))]
+    Between,
+    /// Run the operations up to the matching `end` on each regex match inside the selection,
+    /// from first to last. Afterwards the selection is the same as before `each`.
+    #[strum(serialize = "each")]
+    #[strum(props(
+        args = "regex",
+        example = "each '\\w+' push replace '${pop|upper}' end"
+    ))]
+    Each,
+    /// End of an `each` block
+    #[strum(serialize = "end")]
+    End,
     /// Apply regex to the entire text instead of just the highlighted span
     #[strum(serialize = "whole")]
-    #[strum(props(argc = "0"))]
     Whole,
     /// Original span highlighted in the compiler message
     #[strum(serialize = "original")]
-    #[strum(props(argc = "0"))]
     Original,
     /// Require that only a single paren char is selected, and select the matching one
     #[strum(serialize = "matching-paren", serialize = "mp")]
-    #[strum(props(argc = "0"))]
     MatchingParen,
     /// Select space between matching parens, including the parens themselves.
     /// If a single paren is selected, selects the are between it and the matching paren.
     /// If a span of parens is already selected, do nothing.
     /// Otherwise, expand the selection backwards until a paren is found and then select.
     #[strum(serialize = "parens")]
-    #[strum(props(argc = "0"))]
+    #[strum(props(example = "first '\\(' parens delete"))]
     Parens,
     /// Extend selection forwards
     #[strum(serialize = "extend", serialize = "e")]
-    #[strum(props(argc = "1"))]
+    #[strum(props(args = "regex"))]
     Extend,
+    /// Extend selection forwards up to the next match, not including it
+    #[strum(serialize = "until", serialize = "u")]
+    #[strum(props(args = "regex", example = "until ',|\\)' delete"))]
+    Until,
     /// Find first match in the whole span
     #[strum(serialize = "first", serialize = "f")]
-    #[strum(props(argc = "1"))]
+    #[strum(props(args = "regex"))]
     First,
     /// Match backwards from the selection, using match of this regex instead
     #[strum(serialize = "previous", serialize = "prev", serialize = "p")]
-    #[strum(props(argc = "1"))]
+    #[strum(props(args = "regex"))]
     Previous,
     /// Match forwards from the selection, using match of this regex instead
     #[strum(serialize = "next", serialize = "n")]
-    #[strum(props(argc = "1"))]
+    #[strum(props(args = "regex"))]
     Next,
+    /// Find first occurrence of the text in the whole span, matching it literally
+    #[strum(serialize = "find-str", serialize = "fs")]
+    #[strum(props(args = "text", example = "find-str 'Vec::new()' replace 'vec![]'"))]
+    FindStr,
+    /// Match backwards from the selection, using the previous occurrence of the text instead
+    #[strum(serialize = "prev-str", serialize = "ps")]
+    #[strum(props(args = "text"))]
+    PrevStr,
+    /// Match forwards from the selection, using the next occurrence of the text instead
+    #[strum(serialize = "next-str", serialize = "ns")]
+    #[strum(props(args = "text"))]
+    NextStr,
+    /// Expand the selection to cover the words it starts and ends in.
+    /// Words consist of identifier characters.
+    #[strum(serialize = "word", serialize = "w")]
+    #[strum(props(example = "zero word push replace '${pop|upper}'"))]
+    Word,
+    /// Select the next word after the selection
+    #[strum(serialize = "next-word", serialize = "nw")]
+    #[strum(props(example = "next-word push replace '$pop.clone()'"))]
+    NextWord,
+    /// Select the previous word before the selection
+    #[strum(serialize = "prev-word", serialize = "pw")]
+    PrevWord,
+    /// Move the start of the selection backwards by n characters
+    #[strum(serialize = "grow-start")]
+    #[strum(props(args = "count", example = "grow-start 1 delete"))]
+    GrowStart,
+    /// Move the end of the selection forwards by n characters
+    #[strum(serialize = "grow-end")]
+    #[strum(props(args = "count"))]
+    GrowEnd,
+    /// Move the start of the selection forwards by n characters, at most to its end
+    #[strum(serialize = "shrink-start")]
+    #[strum(props(args = "count"))]
+    ShrinkStart,
+    /// Move the end of the selection backwards by n characters, at most to its start
+    #[strum(serialize = "shrink-end")]
+    #[strum(props(args = "count"))]
+    ShrinkEnd,
+    /// Move the selection by n characters, backwards if negative
+    #[strum(serialize = "shift")]
+    #[strum(props(args = "amount", example = "zero shift -1 replace ','"))]
+    Shift,
     /// Limit the current selection to zero width, keeping the same start point
     #[strum(serialize = "zero")]
-    #[strum(props(argc = "0"))]
     Zero,
     /// Select first match inside the current selection
     #[strum(serialize = "narrow", serialize = "inner")]
-    #[strum(props(argc = "1"))]
+    #[strum(props(args = "regex"))]
     Narrow,
     /// Delete the current selection
     #[strum(serialize = "delete", serialize = "d")]
-    #[strum(props(argc = "0"))]
     Delete,
+    /// Delete the selected element of a comma-separated list along with its separator,
+    /// e.g. an argument, a derive or a name in a use group
+    #[strum(serialize = "delete-item")]
+    #[strum(props(example = "delete-item"))]
+    DeleteItem,
+    /// Delete the lines of the current selection, along with their line break
+    /// if the change of the span leaves nothing else
+    #[strum(serialize = "delete-line")]
+    #[strum(props(example = "whole each '^\\s*use\\s*;' delete-line end"))]
+    DeleteLine,
     /// Replace the current selection with a string
     #[strum(serialize = "replace")]
-    #[strum(props(argc = "1"))]
+    #[strum(props(args = "text", example = "push replace '_$pop'"))]
     Replace,
     /// Substitute the first regex match in the current selection
     #[strum(serialize = "substitute", serialize = "sub", serialize = "s")]
-    #[strum(props(argc = "2"))]
+    #[strum(props(args = "regex replacement", example = "sub '&' ''"))]
     Substitute,
     /// Substitute all regex matches in the current selection
     #[strum(
@@ -88,37 +213,280 @@ pub enum TextOperation {
         serialize = "suba",
         serialize = "sa"
     )]
-    #[strum(props(argc = "2"))]
+    #[strum(props(args = "regex replacement", example = "sa '\\s+' ' '"))]
     SubstituteAll,
+    /// Add to the integer in the selection
+    #[strum(serialize = "incr")]
+    #[strum(props(args = "amount", example = "incr 1"))]
+    Incr,
+    /// Subtract from the integer in the selection
+    #[strum(serialize = "decr")]
+    #[strum(props(args = "amount", example = "decr 1"))]
+    Decr,
+    /// Delete whitespace at both ends of the selection
+    #[strum(serialize = "trim")]
+    Trim,
+    /// Delete whitespace at the start of the selection
+    #[strum(serialize = "trim-start")]
+    TrimStart,
+    /// Delete whitespace at the end of the selection
+    #[strum(serialize = "trim-end")]
+    TrimEnd,
+    /// Collapse runs of spaces and repeated commas within the selection, and remove spaces
+    /// before commas, semicolons and closing parens. Indentation and line breaks are kept.
+    #[strum(serialize = "collapse-ws")]
+    #[strum(props(example = "parens collapse-ws"))]
+    CollapseWs,
+    /// Comment out the lines of the selection with `//`, keeping their indentation.
+    /// A selection inside a single line is wrapped in `/* */` instead.
+    #[strum(serialize = "comment")]
+    #[strum(props(example = "item comment"))]
+    Comment,
+    /// Remove `//` from the lines of the selection, or unwrap a selected `/* */` comment
+    #[strum(serialize = "uncomment")]
+    Uncomment,
+    /// Rename the selected identifier to the new name, along with its other occurrences in
+    /// the text. Strings and comments are left as-is. Use `scope` to extend the text first.
+    #[strum(serialize = "rename")]
+    #[strum(props(args = "name", example = "push scope block rename '_$pop'"))]
+    Rename,
+    /// Structural search and replace within the selection. The pattern is Rust code where
+    /// `$name` placeholders match expressions, and the template uses them as `$name`.
+    /// Whitespace and line breaks don't affect matching.
+    #[strum(serialize = "ssr")]
+    #[strum(props(args = "pattern template", example = "ssr '$a.unwrap()' '$a?'"))]
+    Ssr,
+    /// Substitute all regex matches in the whole file of the span, as separate changes.
+    /// Lines changed by the rest of the sequence are left out, as the changes would conflict.
+    #[strum(serialize = "file-sub")]
+    #[strum(props(
+        args = "regex replacement",
+        example = "replace Bar file-sub '(?m)^use foo::Foo;$' 'use foo::Bar;'"
+    ))]
+    FileSub,
+    /// Insert lines at the start of the file of the span, as a separate change.
+    /// Nothing is inserted if the file already starts with them.
+    #[strum(serialize = "file-prepend")]
+    #[strum(props(args = "text", example = "file-prepend '#![allow(dead_code)]'"))]
+    FilePrepend,
+    /// Insert lines at the end of the file of the span, as a separate change.
+    /// Nothing is inserted if the file already ends with them.
+    #[strum(serialize = "file-append")]
+    #[strum(props(args = "text"))]
+    FileAppend,
+    /// Continue on the span of n:th help item, counting from zero.
+    /// The rest of the operations are applied there as a separate change.
+    #[strum(serialize = "goto-help")]
+    #[strum(props(args = "index"))]
+    GotoHelp,
+    /// Continue on the span of the first note matching the regex, e.g. "defined here".
+    /// The rest of the operations are applied there as a separate change.
+    #[strum(serialize = "goto-note")]
+    #[strum(props(args = "regex", example = "goto-note 'defined here' replace 'pub fn'"))]
+    GotoNote,
+    /// Continue on n:th span of the diagnostic, counting from zero and including non-primary ones.
+    /// The rest of the operations are applied there as a separate change.
+    /// When used, the sequence runs once per diagnostic instead of once per primary span.
+    #[strum(serialize = "goto-span")]
+    #[strum(props(args = "index"))]
+    GotoSpan,
+    /// Continue on the innermost item (fn, struct, impl block, ...) enclosing the selection,
+    /// including its attributes. The file is parsed with syn to find the item.
+    /// The rest of the operations are applied there as a separate change.
+    #[strum(serialize = "item")]
+    #[strum(props(example = "item first 'fn' replace '#[inline]\\nfn'"))]
+    Item,
+    /// Continue on the innermost expression enclosing the selection.
+    /// The rest of the operations are applied there as a separate change.
+    #[strum(serialize = "expr")]
+    #[strum(props(example = "expr push replace 'Box::new($pop)'"))]
+    Expr,
+    /// Continue on the innermost statement enclosing the selection, including its semicolon.
+    /// The rest of the operations are applied there as a separate change.
+    #[strum(serialize = "stmt")]
+    #[strum(props(example = "stmt delete"))]
+    Stmt,
+    /// Continue on the innermost block enclosing the selection, including its braces.
+    /// The rest of the operations are applied there as a separate change.
+    #[strum(serialize = "block")]
+    Block,
+    /// Continue on the innermost item, expr, stmt or block enclosing the selection, or on the
+    /// whole file, keeping the selection. The rest of the operations are applied there,
+    /// with a separate change for each group of changed words.
+    #[strum(serialize = "scope")]
+    #[strum(props(args = "node", example = "scope file rename new_name"))]
+    Scope,
+    /// Continue on the nearest tree-sitter node of the kind enclosing the selection,
+    /// e.g. `call_expression`. The rest of the operations are applied there as a separate change.
+    #[cfg(feature = "tree-sitter")]
+    #[strum(serialize = "ts-node")]
+    #[strum(props(
+        args = "kind",
+        example = "ts-node call_expression push replace 'Some($pop)'"
+    ))]
+    TsNode,
+    /// Continue on the first capture of the tree-sitter query inside the selection.
+    /// The rest of the operations are applied there as a separate change.
+    #[cfg(feature = "tree-sitter")]
+    #[strum(serialize = "ts-query")]
+    #[strum(props(
+        args = "query",
+        example = "item ts-query '(call_expression function: (identifier) @f)' replace g"
+    ))]
+    TsQuery,
+    /// Run a Rhai script, given inline or as a path to a file. The script sees the selected
+    /// text as `selection`, the whole text as `text`, the selection's byte offsets as `start`
+    /// and `end`, and `stack` as an array it can change. Unless it evaluates to `()`,
+    /// its value replaces the selection.
+    #[cfg(feature = "rhai")]
+    #[strum(serialize = "script")]
+    #[strum(props(
+        args = "script",
+        example = "first '\\d+' script 'parse_int(selection) * 1024'"
+    ))]
+    Script,
+    /// Run the executable `refix-op-<name>` from `PATH`, or the plugin `<name>.wasm` given
+    /// with `--plugin`. It gets a JSON object with the `text`, the `selection` as `start` and
+    /// `end` byte offsets in it, and the `stack` on stdin, and prints an object with any of
+    /// them changed.
+    #[strum(serialize = "x-<name>")]
+    #[strum(props(example = "push x-snake-case replace '$pop'"))]
+    External,
 }
 
 impl TextOperation {
+    /// Name of the operation, as used in the sequence
+    pub fn name(&self) -> &'static str {
+        self.into()
+    }
+
+    /// Word of the operation in a sequence and the arguments following it,
+    /// which differ from the parsed ones for external operations
+    pub fn word<'a, S: AsRef<str>>(&self, args: &'a [S]) -> (String, &'a [S]) {
+        match self {
+            Self::External => (
+                format!("{}{}", EXTERNAL_PREFIX, args[0].as_ref()),
+                &args[1..],
+            ),
+            _ => (self.name().to_owned(), args),
+        }
+    }
+
+    /// Names of the arguments, in order
+    pub fn arg_names(&self) -> Vec<&'static str> {
+        self.get_str("args")
+            .map(|args| args.split(' ').collect())
+            .unwrap_or_default()
+    }
+
+    /// Closest operation name to a misspelled one, if any is close enough
+    pub fn closest(name: &str) -> Option<&'static str> {
+        Self::iter()
+            .flat_map(|op| op.get_serializations().iter().copied())
+            .map(|candidate| (text::edit_distance(name, candidate), candidate))
+            .filter(|(distance, _)| *distance <= name.len() / 3 + 1)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, candidate)| candidate)
+    }
+
+    /// Switches to another span instead of operating on the text
+    pub fn is_goto(&self) -> bool {
+        match self {
+            Self::GotoHelp
+            | Self::GotoNote
+            | Self::GotoSpan
+            | Self::Item
+            | Self::Expr
+            | Self::Stmt
+            | Self::Block
+            | Self::Scope => true,
+            #[cfg(feature = "tree-sitter")]
+            Self::TsNode | Self::TsQuery => true,
+            _ => false,
+        }
+    }
+
+    /// Edits the whole file of the span, which needs a diagnostic to know the file
+    pub fn is_file_edit(&self) -> bool {
+        matches!(self, Self::FileSub | Self::FilePrepend | Self::FileAppend)
+    }
+
     pub fn apply(
         &self,
-        stack: &mut Vec<String>,
+        state: &mut State,
+        haystack: &mut String,
+        original_span: ops::Range<usize>,
+        span: ops::Range<usize>,
+        args: &[&str],
+    ) -> Result<ops::Range<usize>, ExecError> {
+        if state.marks.is_empty() {
+            return self.apply_to_text(state, haystack, original_span, span, args);
+        }
+        let old = haystack.clone();
+        let span = self.apply_to_text(state, haystack, original_span, span, args)?;
+        state.adjust_marks(&old, haystack);
+        Ok(span)
+    }
+
+    fn apply_to_text(
+        &self,
+        state: &mut State,
         haystack: &mut String,
         original_span: ops::Range<usize>,
         span: ops::Range<usize>,
         args: &[&str],
     ) -> Result<ops::Range<usize>, ExecError> {
+        let State {
+            stack,
+            registers,
+            marks,
+            file_edits,
+            delete_lines,
+            allow_shell,
+            regex_flags,
+            #[cfg(feature = "wasm")]
+            plugins,
+        } = state;
+
         macro_rules! regex_arg {
             ($index:literal) => {
-                Regex::new(args[$index])
-                    .map_err(|err| ExecError::InvalidRegex(args[$index].to_owned(), err))?
+                regex_flags.build(args[$index])?
             };
         }
-
         let template_resolver = |name: &str| -> Result<Option<String>, ExecError> {
-            match name {
-                "top" => {
-                    let value = stack.last().ok_or(ExecError::StackUnderflow(*self))?;
-                    Ok(Some(value.clone()))
+            if let Some(command) = name.strip_prefix("cmd:") {
+                if !*allow_shell {
+                    return Err(ExecError::ShellNotAllowed);
+                }
+                return run_command(command).map(Some);
+            }
+
+            let (name, offset) = split_offset(name);
+            let value = match name {
+                _ if name.starts_with("env:") => {
+                    let var = &name["env:".len()..];
+                    env::var(var).map_err(|_| ExecError::EnvVar(var.to_owned()))?
                 }
-                "pop" => {
-                    let value = stack.pop().ok_or(ExecError::StackUnderflow(*self))?;
-                    Ok(Some(value))
+                _ if name.starts_with("reg:") => {
+                    let register = &name["reg:".len()..];
+                    registers
+                        .get(register)
+                        .ok_or_else(|| ExecError::EmptyRegister(register.to_owned()))?
+                        .clone()
                 }
-                _ => Ok(None),
+                "top" => stack
+                    .last()
+                    .ok_or(ExecError::StackUnderflow(*self))?
+                    .clone(),
+                "pop" => stack.pop().ok_or(ExecError::StackUnderflow(*self))?,
+                _ => return Ok(None),
+            };
+            match offset {
+                Some(offset) => parse_integer(&value)?
+                    .checked_add(offset)
+                    .map(|sum| Some(sum.to_string()))
+                    .ok_or(ExecError::InvalidNumber(value)),
+                None => Ok(Some(value)),
             }
         };
 
@@ -129,6 +497,14 @@ impl TextOperation {
             }};
         }
 
+        macro_rules! literal_arg {
+            ($index:literal) => {
+                regex_flags
+                    .literal()
+                    .build(&regex::escape(&string_arg!($index)))?
+            };
+        }
+
         match self {
             TextOperation::StackDrop => {
                 if stack.pop().is_none() {
@@ -148,6 +524,48 @@ impl TextOperation {
                 stack.push(haystack[span.clone()].to_owned());
                 Ok(span)
             }
+            TextOperation::StackConcat => {
+                let (Some(top), Some(below)) = (stack.pop(), stack.pop()) else {
+                    return Err(ExecError::StackUnderflow(*self));
+                };
+                stack.push(below + top.as_str());
+                Ok(span)
+            }
+            TextOperation::StackSlice => {
+                let re = regex_arg!(0);
+                let top = stack.last_mut().ok_or(ExecError::StackUnderflow(*self))?;
+                let m = re.capture(top).ok_or(ExecError::NoMatches(*self))?;
+                *top = top[m].to_owned();
+                Ok(span)
+            }
+            TextOperation::StackApply => {
+                let re = regex_arg!(0);
+                let replacement = string_arg!(1);
+                let top = stack.last_mut().ok_or(ExecError::StackUnderflow(*self))?;
+                *top = re.replace_all(top, &replacement);
+                Ok(span)
+            }
+            TextOperation::Store => {
+                registers.insert(args[0].to_owned(), haystack[span.clone()].to_owned());
+                Ok(span)
+            }
+            TextOperation::Load => {
+                let value = registers
+                    .get(args[0])
+                    .ok_or_else(|| ExecError::EmptyRegister(args[0].to_owned()))?;
+                stack.push(value.clone());
+                Ok(span)
+            }
+            TextOperation::Mark => {
+                marks.insert(args[0].to_owned(), span.clone());
+                Ok(span)
+            }
+            TextOperation::Between => {
+                let mark = marks
+                    .get(args[0])
+                    .ok_or_else(|| ExecError::NoMark(args[0].to_owned()))?;
+                Ok(mark.start.min(span.start)..mark.end.max(span.end))
+            }
             TextOperation::Whole => Ok(0..haystack.len()),
             TextOperation::Original => Ok(original_span),
             TextOperation::MatchingParen => {
@@ -186,203 +604,2423 @@ impl TextOperation {
                 let m = regex_arg!(0)
                     .find_at(haystack, span.end)
                     .ok_or(ExecError::NoMatches(*self))?;
-                if m.start() == span.end {
-                    Ok(span.start..m.end())
+                if m.start == span.end {
+                    Ok(span.start..m.end)
                 } else {
                     Ok(span)
                 }
             }
-            TextOperation::First => Ok(regex_arg!(0)
+            TextOperation::Until => {
+                let m = regex_arg!(0)
+                    .find_at(haystack, span.end)
+                    .ok_or(ExecError::NoMatches(*self))?;
+                Ok(span.start..m.start)
+            }
+            TextOperation::First => regex_arg!(0)
                 .find(haystack)
-                .ok_or(ExecError::NoMatches(*self))?
-                .range()),
-            TextOperation::Previous => Ok(regex_arg!(0)
+                .ok_or(ExecError::NoMatches(*self)),
+            TextOperation::Previous => regex_arg!(0)
                 .find_iter(&haystack[..span.start])
-                .last()
-                .ok_or(ExecError::NoMatches(*self))?
-                .range()),
-            TextOperation::Next => Ok(regex_arg!(0)
+                .pop()
+                .ok_or(ExecError::NoMatches(*self)),
+            TextOperation::Next => regex_arg!(0)
                 .find_at(haystack, span.end)
-                .ok_or(ExecError::NoMatches(*self))?
-                .range()),
-            TextOperation::Narrow => Ok(regex_arg!(0)
+                .ok_or(ExecError::NoMatches(*self)),
+            TextOperation::FindStr => literal_arg!(0)
+                .find(haystack)
+                .ok_or(ExecError::NoMatches(*self)),
+            TextOperation::PrevStr => literal_arg!(0)
+                .find_iter(&haystack[..span.start])
+                .pop()
+                .ok_or(ExecError::NoMatches(*self)),
+            TextOperation::NextStr => literal_arg!(0)
+                .find_at(haystack, span.end)
+                .ok_or(ExecError::NoMatches(*self)),
+            TextOperation::Narrow => regex_arg!(0)
                 .find_at(&haystack[..span.end], span.start)
-                .ok_or(ExecError::NoMatches(*self))?
-                .range()),
+                .ok_or(ExecError::NoMatches(*self)),
+            TextOperation::Word => {
+                let word = text::word_bounds(haystack, span);
+                if word.is_empty() {
+                    return Err(ExecError::NoMatches(*self));
+                }
+                Ok(word)
+            }
+            TextOperation::NextWord => {
+                text::next_word(haystack, span.end).ok_or(ExecError::NoMatches(*self))
+            }
+            TextOperation::PrevWord => {
+                text::prev_word(haystack, span.start).ok_or(ExecError::NoMatches(*self))
+            }
+            TextOperation::GrowStart
+            | TextOperation::GrowEnd
+            | TextOperation::ShrinkStart
+            | TextOperation::ShrinkEnd => {
+                // Non-negative, so negating it can't overflow
+                let count = args[0]
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|count| isize::try_from(count).ok())
+                    .ok_or_else(|| ExecError::InvalidNumber(args[0].to_owned()))?;
+                Ok(match self {
+                    TextOperation::GrowStart => {
+                        text::move_chars(haystack, span.start, -count)..span.end
+                    }
+                    TextOperation::GrowEnd => {
+                        span.start..text::move_chars(haystack, span.end, count)
+                    }
+                    TextOperation::ShrinkStart => {
+                        text::move_chars(haystack, span.start, count).min(span.end)..span.end
+                    }
+                    _ => span.start..text::move_chars(haystack, span.end, -count).max(span.start),
+                })
+            }
+            TextOperation::Shift => {
+                let amount = isize::try_from(parse_integer(args[0])?)
+                    .map_err(|_| ExecError::InvalidNumber(args[0].to_owned()))?;
+                let start = text::move_chars(haystack, span.start, amount);
+                let end = text::move_chars(haystack, span.end, amount);
+                Ok(start..end)
+            }
             TextOperation::Zero => Ok(span.start..span.start),
             TextOperation::Delete => {
                 haystack.replace_range(span.clone(), "");
                 Ok(span.start..span.start)
             }
+            TextOperation::DeleteItem => {
+                let range = text::list_item_range(haystack, span);
+                haystack.replace_range(range.clone(), "");
+                Ok(range.start..range.start)
+            }
+            TextOperation::DeleteLine => {
+                let range = text::line_bounds(haystack, span);
+                haystack.replace_range(range.clone(), "");
+                *delete_lines = true;
+                Ok(range.start..range.start)
+            }
+            #[cfg(feature = "rhai")]
+            TextOperation::Script => {
+                let value = script::run(args[0], haystack, span.clone(), stack)
+                    .map_err(|err| ExecError::Script(args[0].to_owned(), err))?;
+                match value {
+                    Some(value) => {
+                        haystack.replace_range(span.clone(), &value);
+                        Ok(span.start..span.start + value.len())
+                    }
+                    None => Ok(span),
+                }
+            }
+            TextOperation::External => {
+                let input = ExternalState {
+                    text: Some(haystack.clone()),
+                    selection: Some(span.into()),
+                    stack: Some(stack.clone()),
+                };
+                #[cfg(feature = "wasm")]
+                let plugin = plugins.iter().find(|plugin| plugin.name() == args[0]);
+                #[cfg(not(feature = "wasm"))]
+                let plugin = None;
+                let output = match plugin {
+                    Some(plugin) => run_plugin(plugin, &input)?,
+                    None => run_external(args[0], &input)?,
+                };
+                let text = output.text.or(input.text).unwrap();
+                // Keeping the selection is only valid if it still fits the new text
+                let selection = output.selection.or(input.selection).unwrap();
+                let selection = selection.range(&text).ok_or_else(|| {
+                    ExecError::Command(
+                        external_command(args[0]),
+                        "selection is out of bounds or not at a char boundary".to_owned(),
+                    )
+                })?;
+                *haystack = text;
+                if let Some(new_stack) = output.stack {
+                    *stack = new_stack;
+                }
+                Ok(selection)
+            }
             TextOperation::Replace => {
                 let value = string_arg!(0);
                 haystack.replace_range(span.clone(), &value);
                 Ok(span.start..span.start + value.len())
             }
             TextOperation::Substitute => {
-                let replaced = regex_arg!(0)
-                    .replace(&haystack[span.clone()], string_arg!(1))
-                    .into_owned();
+                let replaced = regex_arg!(0).replace(&haystack[span.clone()], &string_arg!(1));
                 haystack.replace_range(span.clone(), &replaced);
                 Ok(span.start..span.start + replaced.len())
             }
             TextOperation::SubstituteAll => {
-                let replaced = regex_arg!(0)
-                    .replace_all(&haystack[span.clone()], string_arg!(1))
+                let replaced = regex_arg!(0).replace_all(&haystack[span.clone()], &string_arg!(1));
+                haystack.replace_range(span.clone(), &replaced);
+                Ok(span.start..span.start + replaced.len())
+            }
+            TextOperation::Incr | TextOperation::Decr => {
+                let amount = parse_integer(args[0])?;
+                let selected = &haystack[span.clone()];
+                let value = parse_integer(selected)?;
+                let value = match self {
+                    TextOperation::Decr => value.checked_sub(amount),
+                    _ => value.checked_add(amount),
+                }
+                .ok_or_else(|| ExecError::InvalidNumber(selected.to_owned()))?
+                .to_string();
+                haystack.replace_range(span.clone(), &value);
+                Ok(span.start..span.start + value.len())
+            }
+            TextOperation::Trim | TextOperation::TrimStart | TextOperation::TrimEnd => {
+                let selected = &haystack[span.clone()];
+                let start = match self {
+                    TextOperation::TrimEnd => span.start,
+                    _ => span.start + selected.len() - selected.trim_start().len(),
+                };
+                let end = match self {
+                    TextOperation::TrimStart => span.end,
+                    _ => span.start + selected.trim_end().len(),
+                };
+                let end = end.max(start);
+                haystack.replace_range(end..span.end, "");
+                haystack.replace_range(span.start..start, "");
+                Ok(span.start..span.start + end - start)
+            }
+            TextOperation::CollapseWs => {
+                let selected = &haystack[span.clone()];
+                let collapsed = Regex::new(r",(\s*,)+").unwrap().replace_all(selected, ",");
+                let collapsed = Regex::new(r"[ \t]+([,;\)\]])")
+                    .unwrap()
+                    .replace_all(&collapsed, "$1");
+                let collapsed = Regex::new(r"(\S)[ \t]{2,}")
+                    .unwrap()
+                    .replace_all(&collapsed, "$1 ")
                     .into_owned();
+                haystack.replace_range(span.clone(), &collapsed);
+                Ok(span.start..span.start + collapsed.len())
+            }
+            TextOperation::Comment => {
+                let lines = text::line_bounds(haystack, span.clone());
+                let trimmed = haystack[lines.clone()].trim();
+                let replaced = if haystack[span.clone()].contains('\n')
+                    || haystack[span.clone()].trim() == trimmed
+                {
+                    let commented = text::comment_lines(&haystack[lines.clone()]);
+                    haystack.replace_range(lines.clone(), &commented);
+                    lines.start..lines.start + commented.len()
+                } else {
+                    let commented = format!("/* {} */", &haystack[span.clone()]);
+                    haystack.replace_range(span.clone(), &commented);
+                    span.start..span.start + commented.len()
+                };
+                Ok(replaced)
+            }
+            TextOperation::Uncomment => {
+                let selected = &haystack[span.clone()];
+                let start = span.start + selected.len() - selected.trim_start().len();
+                let selected = selected.trim();
+                if let Some(inner) = selected
+                    .strip_prefix("/*")
+                    .and_then(|s| s.strip_suffix("*/"))
+                {
+                    let inner = inner.strip_prefix(' ').unwrap_or(inner);
+                    let inner = inner.strip_suffix(' ').unwrap_or(inner).to_owned();
+                    haystack.replace_range(start..start + selected.len(), &inner);
+                    return Ok(start..start + inner.len());
+                }
+
+                let lines = text::line_bounds(haystack, span);
+                let uncommented = text::uncomment_lines(&haystack[lines.clone()])
+                    .ok_or(ExecError::NoMatches(*self))?;
+                haystack.replace_range(lines.clone(), &uncommented);
+                Ok(lines.start..lines.start + uncommented.len())
+            }
+            TextOperation::Rename => {
+                let old = haystack[span.clone()].to_owned();
+                let whole = ssr::identifier_ranges(&old, &old);
+                if whole.len() != 1 || whole[0] != (0..old.len()) {
+                    return Err(ExecError::NoMatches(*self));
+                }
+                let new = string_arg!(0);
+
+                let mut renamed = String::new();
+                let mut copied = 0;
+                let mut selection = span.clone();
+                for range in ssr::identifier_ranges(haystack, &old) {
+                    renamed.push_str(&haystack[copied..range.start]);
+                    if range.start == span.start {
+                        selection = renamed.len()..renamed.len() + new.len();
+                    }
+                    renamed.push_str(&new);
+                    copied = range.end;
+                }
+                renamed.push_str(&haystack[copied..]);
+                *haystack = renamed;
+                Ok(selection)
+            }
+            TextOperation::Ssr => {
+                let pattern = ssr::Pattern::parse(args[0])
+                    .map_err(|err| ExecError::InvalidPattern(args[0].to_owned(), err))?;
+                let replaced = ssr::replace_all(&haystack[span.clone()], &pattern, args[1]);
                 haystack.replace_range(span.clone(), &replaced);
                 Ok(span.start..span.start + replaced.len())
             }
+            TextOperation::FileSub => {
+                let re = regex_arg!(0);
+                file_edits.push(FileEdit::Substitute(re, string_arg!(1)));
+                Ok(span)
+            }
+            TextOperation::FilePrepend => {
+                file_edits.push(FileEdit::Prepend(string_arg!(0)));
+                Ok(span)
+            }
+            TextOperation::FileAppend => {
+                file_edits.push(FileEdit::Append(string_arg!(0)));
+                Ok(span)
+            }
+            TextOperation::Each => Err(ExecError::InvalidBlock("each without end")),
+            TextOperation::End => Err(ExecError::InvalidBlock("end without each")),
+            TextOperation::GotoHelp
+            | TextOperation::GotoNote
+            | TextOperation::GotoSpan
+            | TextOperation::Item
+            | TextOperation::Expr
+            | TextOperation::Stmt
+            | TextOperation::Block
+            | TextOperation::Scope => {
+                unreachable!("goto operations are resolved by Operation::compute_diffs")
+            }
+            #[cfg(feature = "tree-sitter")]
+            TextOperation::TsNode | TextOperation::TsQuery => {
+                unreachable!("goto operations are resolved by Operation::compute_diffs")
+            }
+        }
+    }
+}
+
+/// Values carried from one operation to the next
+#[derive(Debug, Clone, Default)]
+pub struct State {
+    pub stack: Vec<String>,
+    pub registers: BTreeMap<String, String>,
+    /// Selections remembered by `mark`, kept in place when the text around them changes
+    pub marks: BTreeMap<String, ops::Range<usize>>,
+    /// Edits of the whole file, applied as separate changes after the sequence
+    pub file_edits: Vec<FileEdit>,
+    /// Set by `delete-line`, so that the line break is removed if the lines end up empty
+    pub delete_lines: bool,
+    /// Whether `${cmd:...}` templates can run shell commands
+    pub allow_shell: bool,
+    /// Flags used when compiling the regexes of operations
+    pub regex_flags: RegexFlags,
+    /// WebAssembly plugins run by `x-<name>` instead of executables
+    #[cfg(feature = "wasm")]
+    pub plugins: Vec<Plugin>,
+}
+
+impl State {
+    pub fn new(allow_shell: bool, regex_flags: RegexFlags) -> Self {
+        Self {
+            allow_shell,
+            regex_flags,
+            ..Self::default()
+        }
+    }
+
+    /// Move the marks to match the edit between the old and the new text
+    fn adjust_marks(&mut self, old: &str, new: &str) {
+        if old.len() == new.len() {
+            return;
+        }
+        let edit = text::Edit::between(old, new);
+        for mark in self.marks.values_mut() {
+            *mark = edit.adjust_range(mark.clone());
         }
     }
 }
 
+/// Flags applied to every regex of an operation sequence, like the inline `(?ims)`,
+/// and the engine compiling them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegexFlags {
+    /// `i`: letters match both upper and lower case
+    pub case_insensitive: bool,
+    /// `m`: `^` and `$` match at the start and end of lines
+    pub multi_line: bool,
+    /// `s`: `.` matches line breaks too
+    pub dot_matches_new_line: bool,
+    /// `x`: whitespace is ignored and `#` starts a comment
+    pub ignore_whitespace: bool,
+    pub engine: RegexEngine,
+}
+
+impl RegexFlags {
+    /// The flags for matching literal text, where only `i` applies, as e.g. `x` would
+    /// ignore its spaces
+    pub fn literal(&self) -> Self {
+        Self {
+            case_insensitive: self.case_insensitive,
+            ..Self::default()
+        }
+    }
+
+    /// Compile the regex with the flags
+    pub fn build(&self, pattern: &str) -> Result<Pattern, ExecError> {
+        match self.engine {
+            RegexEngine::Default => RegexBuilder::new(pattern)
+                .case_insensitive(self.case_insensitive)
+                .multi_line(self.multi_line)
+                .dot_matches_new_line(self.dot_matches_new_line)
+                .ignore_whitespace(self.ignore_whitespace)
+                .build()
+                .map(Pattern::Default)
+                .map_err(|err| ExecError::InvalidRegex(pattern.to_owned(), err)),
+            #[cfg(feature = "fancy-regex")]
+            RegexEngine::Fancy => {
+                // fancy-regex only takes the flags inline
+                let flags: String = [
+                    (self.case_insensitive, 'i'),
+                    (self.multi_line, 'm'),
+                    (self.dot_matches_new_line, 's'),
+                    (self.ignore_whitespace, 'x'),
+                ]
+                .into_iter()
+                .filter_map(|(set, flag)| set.then_some(flag))
+                .collect();
+                let full = match flags.is_empty() {
+                    true => pattern.to_owned(),
+                    false => format!("(?{}){}", flags, pattern),
+                };
+                fancy_regex::Regex::new(&full)
+                    .map(Pattern::Fancy)
+                    .map_err(|err| ExecError::InvalidPattern(pattern.to_owned(), err.to_string()))
+            }
+        }
+    }
+}
+
+impl FromStr for RegexFlags {
+    type Err = String;
+
+    /// Parse flags like `i,m,s` or `ims`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut flags = Self::default();
+        for flag in value.chars().filter(|c| *c != ',') {
+            match flag {
+                'i' => flags.case_insensitive = true,
+                'm' => flags.multi_line = true,
+                's' => flags.dot_matches_new_line = true,
+                'x' => flags.ignore_whitespace = true,
+                _ => return Err(format!("unknown flag {:?}, expected i, m, s or x", flag)),
+            }
+        }
+        Ok(flags)
+    }
+}
+
+/// Edit of the whole file the span is in, made by file operations
+#[derive(Debug, Clone)]
+pub enum FileEdit {
+    Substitute(Pattern, String),
+    Prepend(String),
+    Append(String),
+}
+
+/// New text of the lines of a span, and the location of their change if they changed
+type ChangedLines = (String, Option<ops::Range<usize>>);
+
+/// Length of the line break at the position of the file, if there is one
+fn line_break_len(file: &str, position: usize) -> usize {
+    let source = fs::read(file).unwrap_or_default();
+    match source.get(position..) {
+        Some([b'\r', b'\n', ..]) => 2,
+        Some([b'\n', ..]) => 1,
+        _ => 0,
+    }
+}
+
+/// Line break between the lines of the span in its file
+fn span_line_break(span: &message::Span) -> &'static str {
+    if span.text.len() < 2 {
+        return "\n";
+    }
+    span.line_break(&fs::read(&span.file_name).unwrap_or_default())
+}
+
+/// Changes replacing the words that differ between the location of the file and the new text.
+/// Like the spans of diagnostics, each change covers whole lines, those of a group of
+/// changed words.
+fn word_changes(
+    file: &str,
+    location: ops::Range<usize>,
+    new: &str,
+) -> Result<Vec<SpanChange>, ExecError> {
+    let source = fs::read_to_string(file)
+        .map_err(|err| ExecError::Source(file.to_owned(), err.to_string()))?;
+    let old = source
+        .get(location.clone())
+        .ok_or_else(|| ExecError::Source(file.to_owned(), "span is outside the file".to_owned()))?;
+
+    let diff = similar::TextDiff::from_words(old, new);
+    let offsets = |words: &[&str]| -> Vec<usize> {
+        std::iter::once(0)
+            .chain(words.iter().scan(0, |end, word| {
+                *end += word.len();
+                Some(*end)
+            }))
+            .collect()
+    };
+    let old_offsets = offsets(diff.old_slices());
+    let new_offsets = offsets(diff.new_slices());
+
+    // Replaced ranges of the file, grouped by the lines they are on
+    type Replaced<'a> = Vec<(ops::Range<usize>, &'a str)>;
+    let mut groups: Vec<(ops::Range<usize>, Replaced)> = Vec::new();
+    for ops in diff.grouped_ops(0) {
+        let (first, last) = (ops.first().unwrap(), ops.last().unwrap());
+        let start = location.start + old_offsets[first.old_range().start];
+        let end = location.start + old_offsets[last.old_range().end];
+        let replacement =
+            &new[new_offsets[first.new_range().start]..new_offsets[last.new_range().end]];
+        let lines = source[..start].rfind('\n').map_or(0, |i| i + 1)
+            ..source[end..].find('\n').map_or(source.len(), |i| end + i);
+        match groups.last_mut() {
+            Some((group_lines, replaced)) if lines.start <= group_lines.end => {
+                group_lines.end = lines.end;
+                replaced.push((start..end, replacement));
+            }
+            _ => groups.push((lines, vec![(start..end, replacement)])),
+        }
+    }
+
+    let mut changes = Vec::new();
+    for (lines, replaced) in groups {
+        let mut bytes = String::new();
+        let mut copied = lines.start;
+        for (range, replacement) in replaced {
+            bytes.push_str(&source[copied..range.start]);
+            bytes.push_str(replacement);
+            copied = range.end;
+        }
+        bytes.push_str(&source[copied..lines.end]);
+        changes.push(SpanChange {
+            applicability: None,
+            span: message::Span::from_source(file, &source, lines.clone()),
+            change: Change {
+                file: PathBuf::from(file),
+                patch: Patch {
+                    location: lines,
+                    bytes: bytes.into_bytes(),
+                },
+            },
+        });
+    }
+    Ok(changes)
+}
+
+/// Changes made to the file by file edits: one for each group of lines changed by the
+/// substitutions, and insertions for the lines prepended and appended to the file.
+/// Lines the file already starts or ends with are not added again.
+fn file_changes(file: &str, edits: &[FileEdit]) -> Result<Vec<SpanChange>, ExecError> {
+    let source = fs::read_to_string(file)
+        .map_err(|err| ExecError::Source(file.to_owned(), err.to_string()))?;
+    let lines = |text: &str| match text.ends_with('\n') {
+        true => text.to_owned(),
+        false => format!("{}\n", text),
+    };
+    let mut new = source.clone();
+    let (mut prefix, mut suffix) = (String::new(), String::new());
+    for edit in edits {
+        match edit {
+            FileEdit::Substitute(re, replacement) => new = re.replace_all(&new, replacement),
+            FileEdit::Prepend(text) if !source.starts_with(&lines(text)) => {
+                prefix.insert_str(0, &lines(text));
+            }
+            FileEdit::Append(text) if !source.ends_with(&lines(text)) => {
+                suffix.push_str(&lines(text));
+            }
+            FileEdit::Prepend(_) | FileEdit::Append(_) => {}
+        }
+    }
+    if !suffix.is_empty() && !source.is_empty() && !source.ends_with('\n') {
+        suffix.insert(0, '\n');
+    }
+
+    let line_starts = |text: &str| -> Vec<usize> {
+        std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .chain((!text.ends_with('\n') && !text.is_empty()).then_some(text.len()))
+            .collect()
+    };
+    let old_starts = line_starts(&source);
+    let new_starts = line_starts(&new);
+
+    let mut changes = Vec::new();
+    let diff = similar::TextDiff::from_lines(&source, &new);
+    for group in diff.grouped_ops(0) {
+        let (first, last) = (group.first().unwrap(), group.last().unwrap());
+        let mut old = first.old_range().start..last.old_range().end;
+        let mut new_lines = first.new_range().start..last.new_range().end;
+        let text = |starts: &[usize], text: &str, lines: &ops::Range<usize>| {
+            text[starts[lines.start]..starts[lines.end]].to_owned()
+        };
+
+        // Spans cover whole lines, so insertions include a neighboring line
+        if old.is_empty() && old.end + 1 < old_starts.len() {
+            old.end += 1;
+            new_lines.end += 1;
+        } else if old.is_empty() && old.start > 0 {
+            old.start -= 1;
+            new_lines.start -= 1;
+        }
+        let mut old_text = text(&old_starts, &source, &old);
+        let mut new_text = text(&new_starts, &new, &new_lines);
+        if old_text.ends_with('\n') {
+            if !new_text.ends_with('\n') {
+                continue;
+            }
+            old_text.pop();
+            new_text.pop();
+        }
+
+        let location = old_starts[old.start]..old_starts[old.start] + old_text.len();
+        changes.push(SpanChange {
+            applicability: None,
+            span: message::Span::from_source(file, &source, location.clone()),
+            change: Change {
+                file: PathBuf::from(file),
+                patch: Patch {
+                    location,
+                    bytes: new_text.into_bytes(),
+                },
+            },
+        });
+    }
+    let insertions = [(0, prefix), (source.len(), suffix)];
+    for (position, text) in insertions.into_iter().filter(|(_, text)| !text.is_empty()) {
+        // The span has no lines, so that previews show the text as added
+        let mut span = message::Span::from_source(file, &source, position..position);
+        span.text[0] = message::SpanText {
+            highlight_start: 1,
+            highlight_end: 1,
+            text: String::new(),
+        };
+        changes.push(SpanChange {
+            applicability: None,
+            span,
+            change: Change {
+                file: PathBuf::from(file),
+                patch: Patch {
+                    location: position..position,
+                    bytes: text.into_bytes(),
+                },
+            },
+        });
+    }
+    Ok(changes)
+}
+
+/// Run a template command with the shell, returning its output without the final newline
+fn run_command(command: &str) -> Result<String, ExecError> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|err| ExecError::Command(command.to_owned(), err.to_string()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let mut err = output.status.to_string();
+        if !stderr.trim().is_empty() {
+            err = format!("{}: {}", err, stderr.trim());
+        }
+        return Err(ExecError::Command(command.to_owned(), err));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.strip_suffix('\n').unwrap_or(&stdout).to_owned())
+}
+
+/// Prefix of the words naming external operations
+pub const EXTERNAL_PREFIX: &str = "x-";
+
+/// Executable of an external operation
+fn external_command(name: &str) -> String {
+    format!("refix-op-{}", name)
+}
+
+/// Byte offsets of the selection, as exchanged with external operations
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+struct Selection {
+    start: usize,
+    end: usize,
+}
+
+impl From<ops::Range<usize>> for Selection {
+    fn from(range: ops::Range<usize>) -> Self {
+        Self {
+            start: range.start,
+            end: range.end,
+        }
+    }
+}
+
+impl Selection {
+    /// The selection as a range, if it is valid in the text
+    fn range(self, text: &str) -> Option<ops::Range<usize>> {
+        let valid = self.start <= self.end
+            && text.is_char_boundary(self.start)
+            && text.is_char_boundary(self.end);
+        valid.then_some(self.start..self.end)
+    }
+}
+
+/// State given to an external operation, and the parts of it the operation changed
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct ExternalState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    selection: Option<Selection>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stack: Option<Vec<String>>,
+}
+
+/// Run an external operation, passing the state as JSON on stdin and reading the changes from stdout
+fn run_external(name: &str, input: &ExternalState) -> Result<ExternalState, ExecError> {
+    let command = external_command(name);
+    let error = |err: &dyn fmt::Display| ExecError::Command(command.clone(), err.to_string());
+    let mut child = Command::new(&command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| error(&err))?;
+    let input = serde_json::to_vec(input).map_err(|err| error(&err))?;
+    // A failing write means the operation exited early, which its status tells about
+    let _ = child.stdin.take().unwrap().write_all(&input);
+    let output = child.wait_with_output().map_err(|err| error(&err))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let mut err = output.status.to_string();
+        if !stderr.trim().is_empty() {
+            err = format!("{}: {}", err, stderr.trim());
+        }
+        return Err(ExecError::Command(command, err));
+    }
+    serde_json::from_slice(&output.stdout).map_err(|err| error(&format!("invalid output: {}", err)))
+}
+
+/// Run an external operation implemented by a WebAssembly plugin
+#[cfg(feature = "wasm")]
+fn run_plugin(plugin: &Plugin, input: &ExternalState) -> Result<ExternalState, ExecError> {
+    let error =
+        |err: &dyn fmt::Display| ExecError::Plugin(plugin.name().to_owned(), err.to_string());
+    let input = serde_json::to_vec(input).map_err(|err| error(&err))?;
+    let output = plugin.call(&input).map_err(|err| error(&err))?;
+    serde_json::from_slice(&output).map_err(|err| error(&format!("invalid output: {}", err)))
+}
+
+#[cfg(not(feature = "wasm"))]
+fn run_plugin(
+    plugin: &std::convert::Infallible,
+    _: &ExternalState,
+) -> Result<ExternalState, ExecError> {
+    match *plugin {}
+}
+
 #[derive(Debug, Clone)]
 pub enum ExecError {
     /// No such operation
-    UnknownOp(String),
+    UnknownOp {
+        name: String,
+        /// Index of the word in the operation sequence
+        position: Option<usize>,
+        /// Closest known operation name
+        suggestion: Option<&'static str>,
+    },
     /// Argument could not be parsed as a regex
     InvalidRegex(String, regex::Error),
+    /// Argument could not be parsed as a search pattern or a query
+    InvalidPattern(String, String),
     /// No regex or other matches by operation
     NoMatches(TextOperation),
     /// Not enough arguments available
     NotEnoughArguments(TextOperation, usize),
     /// Cannot pop from empty stack
     StackUnderflow(TextOperation),
+    /// No template filter with this name
+    UnknownFilter(String),
+    /// Environment variable used in a template is not set
+    EnvVar(String),
+    /// Template command was used without `--allow-shell`
+    ShellNotAllowed,
+    /// Template command or external operation could not be run or failed
+    Command(String, String),
+    /// WebAssembly plugin failed or returned invalid output
+    #[cfg(feature = "wasm")]
+    Plugin(String, String),
+    /// Script of the `script` operation failed
+    #[cfg(feature = "rhai")]
+    Script(String, String),
+    /// Unbalanced or misplaced `each` block
+    InvalidBlock(&'static str),
+    /// No selection was marked with the name
+    NoMark(String),
+    /// Nothing was stored to the named register
+    EmptyRegister(String),
+    /// Argument could not be parsed as a number
+    InvalidNumber(String),
+    /// No suggestion alternative was selected
+    NoSuggestion,
+    /// Suggestions of the diagnostic with this code aren't machine-applicable
+    NotMachineApplicable(String),
+    /// Span of the change doesn't match the file, see `Span::check_source`
+    PatchMismatch,
+    /// Change overlaps a different change made to the file before it
+    Conflict,
+    /// Span is inside an expansion of the named macro
+    InsideMacro(String),
+    /// Operation only works on a compiler diagnostic
+    NeedsDiagnostic(TextOperation),
+    /// Source file could not be read or parsed
+    Source(String, String),
+    /// User stopped the sequence while stepping through it
+    Stopped,
 }
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownOp {
+                name,
+                position,
+                suggestion,
+            } => {
+                write!(f, "unknown operation {:?}", name)?;
+                if let Some(position) = position {
+                    write!(f, " at position {} of the sequence", position + 1)?;
+                }
+                if let Some(suggestion) = suggestion {
+                    write!(f, ", did you mean {:?}?", suggestion)?;
+                }
+                Ok(())
+            }
+            Self::InvalidRegex(re, err) => match err {
+                regex::Error::Syntax(_) => write!(f, "invalid regex {:?}", re),
+                _ => write!(f, "invalid regex {:?}: {}", re, err),
+            },
+            Self::InvalidPattern(pattern, err) => {
+                write!(f, "invalid pattern {:?}: {}", pattern, err)
+            }
+            Self::NoMatches(op) => write!(f, "no matches for {}", op.name()),
+            Self::NotEnoughArguments(op, got) => {
+                let argc = op.arg_names().len();
+                write!(f, "{} expects {} arguments, got {}", op.name(), argc, got)
+            }
+            Self::StackUnderflow(op) => write!(f, "{} on an empty stack", op.name()),
+            Self::UnknownFilter(name) => write!(f, "unknown template filter {:?}", name),
+            Self::EnvVar(name) => write!(f, "environment variable {} is not set", name),
+            Self::ShellNotAllowed => write!(f, "${{cmd:...}} templates require --allow-shell"),
+            Self::Command(command, err) => write!(f, "command {:?} failed: {}", command, err),
+            #[cfg(feature = "wasm")]
+            Self::Plugin(name, err) => write!(f, "plugin {:?} failed: {}", name, err),
+            #[cfg(feature = "rhai")]
+            Self::Script(script, err) => write!(f, "script {:?} failed: {}", script, err),
+            Self::InvalidBlock(reason) => write!(f, "invalid block: {}", reason),
+            Self::NoMark(name) => write!(f, "no mark named {:?}", name),
+            Self::EmptyRegister(name) => write!(f, "nothing stored to register {:?}", name),
+            Self::InvalidNumber(value) => write!(f, "invalid number {:?}", value),
+            Self::NoSuggestion => write!(f, "no matching suggestion"),
+            Self::NotMachineApplicable(code) => {
+                write!(f, "suggestion for {} is not machine-applicable", code)
+            }
+            Self::PatchMismatch => write!(f, "span doesn't match the file"),
+            Self::Conflict => write!(f, "overlaps another change"),
+            Self::InsideMacro(name) => write!(f, "inside macro {}", name),
+            Self::NeedsDiagnostic(op) => write!(f, "{} requires a diagnostic", op.name()),
+            Self::Stopped => write!(f, "stopped while stepping"),
+            Self::Source(file, err) => write!(f, "{}: {}", file, err),
+        }
+    }
+}
+
 impl ExecError {
-    /// Do not attempt to continue to next item after this
+    /// Unknown operation name, suggesting the closest known one
+    pub fn unknown_op(name: &str, position: Option<usize>) -> Self {
+        Self::UnknownOp {
+            name: name.to_owned(),
+            position,
+            suggestion: TextOperation::closest(name),
+        }
+    }
+
+    /// Error is not specific to the item, so continuing to the next item is not useful by default
     pub fn stop_all(&self) -> bool {
-        !matches!(self, Self::NoMatches(_))
+        !matches!(
+            self,
+            Self::NoMatches(_)
+                | Self::NoSuggestion
+                | Self::NotMachineApplicable(_)
+                | Self::InsideMacro(_)
+                | Self::Source(..)
+        )
     }
 }
 
+/// What to do with spans that point into macro expansions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MacroSpans {
+    /// Skip the span with a warning
+    Skip,
+    /// Apply the operations to the outermost macro invocation instead
+    CallSite,
+}
+
+/// What to do when executing operations fails for reasons other than a failed match
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OnError {
+    /// Stop processing any further items
+    Abort,
+    /// Skip the failing item and continue
+    Skip,
+    /// Ask whether to skip or abort
+    Prompt,
+}
+
+/// An item that could not be processed
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub error: ExecError,
+}
+
+/// The whole run should stop, as decided by the error handling policy or the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Aborted;
+
+/// A change along with the span it was computed from
+#[derive(Debug, Clone)]
+pub struct SpanChange {
+    pub span: message::Span,
+    pub change: Change,
+    /// Least certain applicability of the suggestions applied before the operations
+    pub applicability: Option<SuggestionApplicability>,
+}
+
 #[derive(Debug, Clone, Args)]
 pub struct Operation {
     /// Apply suggestion provided by rustc first
     #[arg(short = 'a', long = "auto", alias = "suggestion")]
     suggestion: bool,
 
-    /// Sequence of operations to apply
+    /// Apply only the n:th of alternative suggestions, counting from zero
+    #[arg(long, requires = "suggestion", conflicts_with = "suggestion_match")]
+    suggestion_index: Option<usize>,
+
+    /// Apply only the first alternative suggestion with replacement text matching the regex
+    #[arg(long, requires = "suggestion", conflicts_with = "choose_suggestion")]
+    suggestion_match: Option<String>,
+
+    /// Interactively choose which suggestion to apply when there are alternatives
+    #[arg(long, requires = "suggestion", conflicts_with = "suggestion_index")]
+    choose_suggestion: bool,
+
+    /// Only apply the machine-applicable suggestions, without operations, e.g. with `all`
+    /// to do what `cargo fix` does. Diagnostics with other suggestions are reported as skipped
+    #[arg(long, conflicts_with_all = ["suggestion", "recipe", "ops"])]
+    auto_only: bool,
+
+    /// What to do when executing operations fails
+    #[arg(long, value_enum, default_value_t = OnError::Abort)]
+    on_error: OnError,
+
+    /// How to handle spans inside macro expansions
+    #[arg(long, value_enum, default_value_t = MacroSpans::Skip)]
+    macro_spans: MacroSpans,
+
+    /// Print the selection and the stack after each operation
+    #[arg(long)]
+    trace_ops: bool,
+
+    /// Pause after each operation on the first matched span, to continue, abort,
+    /// or edit the remaining operations. Only the first match is processed.
+    #[arg(long, conflicts_with = "trace_ops")]
+    step: bool,
+
+    /// Allow `${cmd:...}` templates, which run the command with `sh` and use its output
+    #[arg(long)]
+    pub allow_shell: bool,
+
+    /// Flags for all regexes of the operations, e.g. `i,m`: i for case-insensitive,
+    /// m for multi-line `^` and `$`, s for `.` matching line breaks, x for verbose
+    #[arg(long, value_name = "FLAGS", default_value = "")]
+    pub regex_flags: RegexFlags,
+
+    /// Regex engine for the operations. The fancy engine supports lookaround and backreferences
+    #[arg(long, value_enum, default_value_t = RegexEngine::Default)]
+    pub regex_engine: RegexEngine,
+
+    /// WebAssembly module implementing the operation `x-<name>`, where the name is its file name
+    /// without the extension. Can be given multiple times
+    #[cfg(feature = "wasm")]
+    #[arg(long = "plugin", value_name = "PATH")]
+    pub plugins: Vec<Plugin>,
+
+    /// Apply a built-in operation sequence instead of giving the operations.
+    /// Only the diagnostics the recipe is for are selected, e.g. `all` is narrowed to them.
+    /// See `cargo refix ops` for the available recipes
+    #[arg(long, value_name = "NAME", value_parser = recipe::parse, conflicts_with = "ops")]
+    recipe: Option<&'static Recipe>,
+
+    /// Sequence of operations to apply. See `cargo refix ops` for the available ones
+    #[arg(allow_negative_numbers = true)]
     ops: Vec<String>,
 }
 
+/// Operation along with its arguments
+pub type ParsedOp<'a> = (TextOperation, Vec<&'a str>);
+
+/// Split an operation sequence into operations and their arguments
+pub fn parse_ops<S: AsRef<str>>(ops: &[S]) -> Result<Vec<ParsedOp<'_>>, ExecError> {
+    let mut ops: VecDeque<_> = ops.iter().map(AsRef::as_ref).enumerate().collect();
+    let mut parsed = Vec::new();
+
+    while let Some((position, op)) = ops.pop_front() {
+        // The name of an external operation is part of the word
+        if let Some(name) = op.strip_prefix(EXTERNAL_PREFIX) {
+            if name.is_empty() {
+                return Err(ExecError::unknown_op(op, Some(position)));
+            }
+            parsed.push((TextOperation::External, vec![name]));
+            continue;
+        }
+        let op =
+            TextOperation::from_str(op).map_err(|_| ExecError::unknown_op(op, Some(position)))?;
+        let argc = op.arg_names().len();
+        let mut args = Vec::with_capacity(argc);
+        for _ in 0..argc {
+            let (_, arg) = ops
+                .pop_front()
+                .ok_or(ExecError::NotEnoughArguments(op, args.len()))?;
+            args.push(arg);
+        }
+        parsed.push((op, args));
+    }
+
+    Ok(parsed)
+}
+
+/// Check parsed operations for errors that don't depend on the text being edited:
+/// invalid regexes and numbers, and templates or operations popping from an empty stack
+pub fn validate_ops(
+    ops: &[ParsedOp],
+    allow_shell: bool,
+    regex_flags: RegexFlags,
+) -> Result<(), ExecError> {
+    // The stack is shared by all segments of the sequence, so its depth is known statically
+    let mut depth = 0usize;
+    let mut stored = BTreeSet::new();
+    let mut marked = BTreeSet::new();
+    let mut blocks = 0usize;
+    for (op, args) in ops {
+        match op {
+            TextOperation::Each => blocks += 1,
+            TextOperation::End => {
+                blocks = blocks
+                    .checked_sub(1)
+                    .ok_or(ExecError::InvalidBlock("end without each"))?;
+            }
+            _ if op.is_goto() && blocks > 0 => {
+                return Err(ExecError::InvalidBlock(
+                    "goto operations can't be inside each",
+                ));
+            }
+            _ => {}
+        }
+        if op.is_goto() {
+            // Marks are positions in the text of the segment
+            marked.clear();
+        }
+        for (name, arg) in op.arg_names().into_iter().zip(args) {
+            match name {
+                "regex" => {
+                    regex_flags.build(arg)?;
+                }
+                "register" if !text::is_register_name(arg) => {
+                    return Err(ExecError::InvalidPattern(
+                        arg.to_string(),
+                        "register names can only have letters, digits and underscores".to_owned(),
+                    ));
+                }
+                "register" if matches!(op, TextOperation::Load) && !stored.contains(arg) => {
+                    return Err(ExecError::EmptyRegister(arg.to_string()));
+                }
+                "mark" if matches!(op, TextOperation::Between) && !marked.contains(arg) => {
+                    return Err(ExecError::NoMark(arg.to_string()));
+                }
+                "amount" => {
+                    parse_integer(arg)?;
+                }
+                "index" | "count" => {
+                    arg.parse::<usize>()
+                        .map_err(|_| ExecError::InvalidNumber(arg.to_string()))?;
+                }
+                "node" if *arg != "file" && arg.parse::<syntax::Node>().is_err() => {
+                    return Err(ExecError::InvalidPattern(
+                        arg.to_string(),
+                        "expected item, expr, stmt, block or file".to_owned(),
+                    ));
+                }
+                "pattern" => {
+                    ssr::Pattern::parse(arg)
+                        .map_err(|err| ExecError::InvalidPattern(arg.to_string(), err))?;
+                }
+                #[cfg(feature = "tree-sitter")]
+                "kind" if !ts::is_node_kind(arg) => {
+                    return Err(ExecError::InvalidPattern(
+                        arg.to_string(),
+                        "no such tree-sitter node kind".to_owned(),
+                    ));
+                }
+                #[cfg(feature = "tree-sitter")]
+                "query" => {
+                    ts::query(arg).map_err(|err| {
+                        ExecError::InvalidPattern(arg.to_string(), err.to_string())
+                    })?;
+                }
+                #[cfg(feature = "rhai")]
+                "script" => {
+                    script::compile(arg)
+                        .map_err(|err| ExecError::InvalidPattern(arg.to_string(), err))?;
+                }
+                "text" | "replacement" | "name" => {
+                    template(arg, |name| {
+                        if name.starts_with("cmd:") && !allow_shell {
+                            return Err(ExecError::ShellNotAllowed);
+                        }
+                        let name = split_offset(name).0;
+                        if let Some(register) = name.strip_prefix("reg:") {
+                            if !stored.contains(register) {
+                                return Err(ExecError::EmptyRegister(register.to_owned()));
+                            }
+                        }
+                        match name {
+                            "top" if depth == 0 => return Err(ExecError::StackUnderflow(*op)),
+                            "pop" => {
+                                depth =
+                                    depth.checked_sub(1).ok_or(ExecError::StackUnderflow(*op))?
+                            }
+                            _ => {}
+                        }
+                        Ok(None)
+                    })?;
+                }
+                _ => {}
+            }
+        }
+
+        match op {
+            // Blocks are checked as if they ran once
+            TextOperation::StackPush | TextOperation::Load => depth += 1,
+            TextOperation::Store => {
+                stored.insert(args[0]);
+            }
+            TextOperation::Mark => {
+                marked.insert(args[0]);
+            }
+            TextOperation::StackDup
+            | TextOperation::StackDrop
+            | TextOperation::StackSlice
+            | TextOperation::StackApply
+                if depth == 0 =>
+            {
+                return Err(ExecError::StackUnderflow(*op));
+            }
+            TextOperation::StackConcat if depth < 2 => {
+                return Err(ExecError::StackUnderflow(*op));
+            }
+            TextOperation::StackConcat => depth -= 1,
+            TextOperation::StackDup => depth += 1,
+            TextOperation::StackDrop => depth -= 1,
+            _ => {}
+        }
+    }
+    if blocks > 0 {
+        return Err(ExecError::InvalidBlock("each without end"));
+    }
+    Ok(())
+}
+
+/// Index of the `end` closing the `each` block that starts at the index
+fn block_end<I>(ops: I, start: usize) -> Result<usize, ExecError>
+where
+    I: IntoIterator<Item = TextOperation>,
+{
+    let mut depth = 0usize;
+    for (i, op) in ops.into_iter().enumerate().skip(start) {
+        match op {
+            TextOperation::Each => depth += 1,
+            TextOperation::End => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    Err(ExecError::InvalidBlock("each without end"))
+}
+
+/// Parse an integer operand, allowing surrounding whitespace
+fn parse_integer(value: &str) -> Result<i128, ExecError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| ExecError::InvalidNumber(value.to_owned()))
+}
+
+/// Split template name like `top+1` into the name and the amount to add
+fn split_offset(name: &str) -> (&str, Option<i128>) {
+    let Some(i) = name.rfind(['+', '-']) else {
+        return (name, None);
+    };
+    match name[i + 1..].trim().parse::<i128>() {
+        Ok(amount) if name[i..].starts_with('-') => (name[..i].trim(), Some(-amount)),
+        Ok(amount) => (name[..i].trim(), Some(amount)),
+        Err(_) => (name, None),
+    }
+}
+
+/// Operation name and its quoted arguments
+fn trace_label(name: &str, args: &[&str]) -> String {
+    let mut label = name.bold().to_string();
+    for arg in args {
+        label.push_str(&format!(" {:?}", arg));
+    }
+    label
+}
+
+/// Operations and their arguments as words of a sequence
+fn quote_ops<'a, I>(ops: I) -> String
+where
+    I: Iterator<Item = &'a (TextOperation, Vec<String>)>,
+{
+    let mut words = Vec::new();
+    for (op, args) in ops {
+        let (word, args) = op.word(args);
+        words.push(word);
+        words.extend(args.iter().map(|arg| text::quote_word(arg)));
+    }
+    words.join(" ")
+}
+
+/// Print the selection and the stack after an operation, for `--trace-ops`
+fn trace_step(name: &str, args: &[&str], text: &str, span: &ops::Range<usize>, state: &State) {
+    info!(
+        "{} -> {}..{}",
+        trace_label(name, args),
+        span.start,
+        span.end
+    );
+    info!("{}", underline_span(text, span.clone()));
+    if !state.stack.is_empty() {
+        info!("stack: {:?}", state.stack);
+    }
+    if !state.registers.is_empty() {
+        info!("registers: {:?}", state.registers);
+    }
+}
+
 impl Operation {
-    /// Run the operation sequence, mutating the given string
-    pub fn run(&self, haystack: &mut String, mut span: ops::Range<usize>) -> Result<(), ExecError> {
+    /// Flags for the regexes of the operations, including the engine
+    fn regex_flags(&self) -> RegexFlags {
+        RegexFlags {
+            engine: self.regex_engine,
+            ..self.regex_flags
+        }
+    }
+
+    /// State at the start of the sequence
+    fn state(&self) -> State {
+        State {
+            #[cfg(feature = "wasm")]
+            plugins: self.plugins.clone(),
+            ..State::new(self.allow_shell, self.regex_flags())
+        }
+    }
+
+    /// Use the operation sequence instead of the one given on the command line,
+    /// e.g. the one of a rule from the config file
+    pub fn set_ops(&mut self, ops: Vec<String>) {
+        self.ops = ops;
+    }
+
+    /// Built-in recipe given instead of the operations
+    pub fn recipe(&self) -> Option<&'static Recipe> {
+        self.recipe
+    }
+
+    /// Split the operation sequence into operations and their arguments
+    pub fn parse(&self) -> Result<Vec<ParsedOp<'_>>, ExecError> {
+        match self.recipe {
+            Some(recipe) => parse_ops(recipe.ops),
+            None => parse_ops(&self.ops),
+        }
+    }
+
+    /// Suggestions are applied before the operations, with `--auto`, `--auto-only`
+    /// or a recipe using them
+    fn applies_suggestions(&self) -> bool {
+        self.suggestion || self.auto_only || self.recipe.is_some_and(|recipe| recipe.suggestions)
+    }
+
+    /// Parse and validate the operation sequence and options, so that mistakes are reported
+    /// before waiting for cargo
+    pub fn validate(&self) -> Result<(), ExecError> {
+        validate_ops(&self.parse()?, self.allow_shell, self.regex_flags())?;
+        if let Some(pattern) = &self.suggestion_match {
+            Regex::new(pattern).map_err(|err| ExecError::InvalidRegex(pattern.clone(), err))?;
+        }
+        Ok(())
+    }
+
+    /// Run the operation sequence, mutating the given string, and return the final selection.
+    /// With `trace`, the state after each operation is printed.
+    pub fn run(
+        ops: &[ParsedOp],
+        state: &mut State,
+        haystack: &mut String,
+        span: ops::Range<usize>,
+        trace: bool,
+    ) -> Result<ops::Range<usize>, ExecError> {
+        if trace {
+            trace_step("start", &[], haystack, &span, state);
+        }
+        Self::run_block(ops, state, haystack, span.clone(), span, trace)
+    }
+
+    /// Run operations, handling `each` blocks, and return the final selection
+    fn run_block(
+        ops: &[ParsedOp],
+        state: &mut State,
+        haystack: &mut String,
+        mut span: ops::Range<usize>,
+        original_span: ops::Range<usize>,
+        trace: bool,
+    ) -> Result<ops::Range<usize>, ExecError> {
+        let mut i = 0;
+        while i < ops.len() {
+            let (op, args) = &ops[i];
+            if matches!(op, TextOperation::Each) {
+                let end = block_end(ops.iter().map(|(op, _)| *op), i)?;
+                let body = &ops[i + 1..end];
+                span = Self::run_each(args[0], body, state, haystack, span, &original_span, trace)?;
+                if trace {
+                    trace_step("end", &[], haystack, &span, state);
+                }
+                i = end + 1;
+                continue;
+            }
+
+            span = op.apply(state, haystack, original_span.clone(), span, args)?;
+            // Operations computing positions arithmetically could leave them mid-character
+            span = text::snap_range(haystack, span);
+            if trace {
+                let (word, args) = op.word(args);
+                trace_step(&word, args, haystack, &span, state);
+            }
+            i += 1;
+        }
+        Ok(span)
+    }
+
+    /// Run the body of an `each` block on every match inside the span.
+    /// Later matches and the span are moved to follow the edits made by earlier ones.
+    fn run_each(
+        regex: &str,
+        body: &[ParsedOp],
+        state: &mut State,
+        haystack: &mut String,
+        mut span: ops::Range<usize>,
+        original_span: &ops::Range<usize>,
+        trace: bool,
+    ) -> Result<ops::Range<usize>, ExecError> {
+        let re = state.regex_flags.build(regex)?;
+        let mut matches: Vec<_> = re
+            .find_iter(&haystack[span.clone()])
+            .into_iter()
+            .map(|m| span.start + m.start..span.start + m.end)
+            .collect();
+        if trace {
+            info!(
+                "{} -> {} matches",
+                trace_label("each", &[regex]),
+                matches.len()
+            );
+        }
+
+        for i in 0..matches.len() {
+            let old = haystack.clone();
+            let selection = matches[i].clone();
+            Self::run_block(
+                body,
+                state,
+                haystack,
+                selection,
+                original_span.clone(),
+                trace,
+            )?;
+            let edit = text::Edit::between(&old, haystack);
+            for m in &mut matches[i + 1..] {
+                *m = edit.adjust_range(m.clone());
+            }
+            span = edit.adjust_range(span);
+        }
+        Ok(span)
+    }
+
+    /// Run the operation sequence like [`Operation::run`], pausing after each operation.
+    /// The user can edit the operations remaining in the sequence, or stop it.
+    fn run_stepping(
+        ops: &[ParsedOp],
+        state: &mut State,
+        haystack: &mut String,
+        mut span: ops::Range<usize>,
+    ) -> Result<ops::Range<usize>, ExecError> {
+        let owned = |(op, args): &ParsedOp| (*op, args.iter().map(|arg| arg.to_string()).collect());
+        let mut remaining: VecDeque<(TextOperation, Vec<String>)> = ops.iter().map(owned).collect();
+        let mut executed = Vec::new();
+        let mut edited = false;
+
         let orginal_span = span.clone();
-        let mut ops: VecDeque<_> = self.ops.iter().collect();
-        let mut stack = Vec::new();
-
-        while let Some(op) = ops.pop_front() {
-            let op =
-                TextOperation::from_str(op).map_err(|_| ExecError::UnknownOp(op.to_owned()))?;
-            let argc = op.get_str("argc").expect("missing argc property");
-            let argc: usize = argc.parse().expect("invalid argc property");
-            let mut args = Vec::with_capacity(argc);
-            for _ in 0..argc {
-                args.push(
-                    ops.pop_front()
-                        .ok_or(ExecError::NotEnoughArguments(op, args.len()))?
-                        .as_str(),
+        trace_step("start", &[], haystack, &span, state);
+        while let Some((op, args)) = remaining.pop_front() {
+            let arg_refs: Vec<&str> = args.iter().map(String::as_str).collect();
+            if matches!(op, TextOperation::Each) {
+                // Blocks are run as a whole, without pausing inside them
+                let end = block_end(
+                    std::iter::once(op).chain(remaining.iter().map(|(op, _)| *op)),
+                    0,
+                )?;
+                let block: Vec<_> = remaining.drain(..end).collect();
+                let body: Vec<ParsedOp> = block[..end - 1]
+                    .iter()
+                    .map(|(op, args)| (*op, args.iter().map(String::as_str).collect()))
+                    .collect();
+                span = Self::run_each(
+                    arg_refs[0],
+                    &body,
+                    state,
+                    haystack,
+                    span,
+                    &orginal_span,
+                    false,
+                )?;
+                trace_step("end", &[], haystack, &span, state);
+                executed.push((op, args));
+                executed.extend(block);
+            } else {
+                span = op.apply(state, haystack, orginal_span.clone(), span, &arg_refs)?;
+                span = text::snap_range(haystack, span);
+                let (word, arg_refs) = op.word(&arg_refs);
+                trace_step(&word, arg_refs, haystack, &span, state);
+                executed.push((op, args));
+            }
+
+            loop {
+                let answer = interactive::prompt("[c]ontinue, [e]dit the rest, or [s]top? ")
+                    .expect("Unable to read from stdin");
+                match answer.as_str() {
+                    "" | "c" | "continue" => break,
+                    "s" | "stop" => return Err(ExecError::Stopped),
+                    "e" | "edit" => {
+                        println!("rest: {}", quote_ops(remaining.iter()));
+                        let line =
+                            interactive::prompt("new rest: ").expect("Unable to read from stdin");
+                        let Some(words) = text::split_words(&line) else {
+                            println!("Unclosed quote");
+                            continue;
+                        };
+                        match parse_ops(&words) {
+                            Ok(ops) => {
+                                remaining = ops.iter().map(owned).collect();
+                                edited = true;
+                                break;
+                            }
+                            Err(err) => println!("{}", err),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if edited {
+            info!("sequence: {}", quote_ops(executed.iter()));
+        }
+        Ok(span)
+    }
+
+    /// Resolve the span to operate on, taking macro expansions into account.
+    /// Returns `None` if the span should be skipped, recording it into `failures`.
+    fn resolve_span(
+        &self,
+        span: &message::Span,
+        failures: &mut Vec<Failure>,
+    ) -> Option<message::Span> {
+        let Some(expansion) = span.macro_call_site() else {
+            return Some(span.clone());
+        };
+
+        match self.macro_spans {
+            MacroSpans::Skip => {
+                let error = ExecError::InsideMacro(expansion.macro_decl_name.clone());
+                info!("{}:{}:", span.file_name, span.line_start);
+                info!(
+                    " Skipped: {}, invoked at {}:{}",
+                    error, expansion.span.file_name, expansion.span.line_start
                 );
+                failures.push(Failure {
+                    file: Some(span.file_name.clone()),
+                    line: Some(span.line_start),
+                    error,
+                });
+                None
             }
+            MacroSpans::CallSite => Some(expansion.span.clone()),
+        }
+    }
 
-            span = op.apply(&mut stack, haystack, orginal_span.clone(), span, &args)?;
+    /// Resolve the target span of a goto operation, given the span the previous segment used
+    /// and the byte range of the file its operations ended up selecting
+    fn goto_target(
+        target: &message::CompilerMessage,
+        op: TextOperation,
+        args: &[&str],
+        current: &message::Span,
+        selection: ops::Range<usize>,
+        regex_flags: RegexFlags,
+    ) -> Result<message::Span, ExecError> {
+        let index_arg = || -> Result<usize, ExecError> {
+            args[0]
+                .parse()
+                .map_err(|_| ExecError::InvalidNumber(args[0].to_owned()))
+        };
+
+        match op {
+            TextOperation::GotoHelp => target.help_span(index_arg()?),
+            TextOperation::GotoSpan => target.spans.get(index_arg()?),
+            TextOperation::GotoNote => {
+                let re = regex_flags.build(args[0])?;
+                target.note_span(&re)
+            }
+            TextOperation::Item => {
+                return Self::syntax_span(op, syntax::Node::Item, current, selection)
+            }
+            TextOperation::Expr => {
+                return Self::syntax_span(op, syntax::Node::Expr, current, selection)
+            }
+            TextOperation::Stmt => {
+                return Self::syntax_span(op, syntax::Node::Stmt, current, selection)
+            }
+            TextOperation::Block => {
+                return Self::syntax_span(op, syntax::Node::Block, current, selection)
+            }
+            TextOperation::Scope => return Self::scope_span(args[0], current, selection),
+            #[cfg(feature = "tree-sitter")]
+            TextOperation::TsNode | TextOperation::TsQuery => {
+                return Self::tree_sitter_span(op, args[0], current, selection)
+            }
+            _ => unreachable!("not a goto operation"),
         }
+        .cloned()
+        .ok_or(ExecError::NoMatches(op))
+    }
 
-        Ok(())
+    /// The selection in the file of the span, inside the syntax node enclosing it,
+    /// or inside the whole file
+    fn scope_span(
+        scope: &str,
+        current: &message::Span,
+        range: ops::Range<usize>,
+    ) -> Result<message::Span, ExecError> {
+        let source_error =
+            |err: &dyn fmt::Display| ExecError::Source(current.file_name.clone(), err.to_string());
+        let source = fs::read_to_string(&current.file_name).map_err(|err| source_error(&err))?;
+        let context = match scope.parse() {
+            Ok(node) => syntax::enclosing(&source, range.clone(), node)
+                .map_err(|err| source_error(&err))?
+                .ok_or(ExecError::NoMatches(TextOperation::Scope))?,
+            Err(_) => 0..source.len(),
+        };
+        Ok(message::Span::from_source_in(
+            &current.file_name,
+            &source,
+            range,
+            context,
+        ))
+    }
+
+    /// Span of the innermost syntax node enclosing the selection in the file of the span
+    fn syntax_span(
+        op: TextOperation,
+        node: syntax::Node,
+        current: &message::Span,
+        selection: ops::Range<usize>,
+    ) -> Result<message::Span, ExecError> {
+        let source_error =
+            |err: &dyn fmt::Display| ExecError::Source(current.file_name.clone(), err.to_string());
+        let source = fs::read_to_string(&current.file_name).map_err(|err| source_error(&err))?;
+        let range = syntax::enclosing(&source, selection, node)
+            .map_err(|err| source_error(&err))?
+            .ok_or(ExecError::NoMatches(op))?;
+        Ok(message::Span::from_source(
+            &current.file_name,
+            &source,
+            range,
+        ))
+    }
+
+    /// Span of the tree-sitter node or query capture found from the selection
+    /// in the file of the span
+    #[cfg(feature = "tree-sitter")]
+    fn tree_sitter_span(
+        op: TextOperation,
+        arg: &str,
+        current: &message::Span,
+        selection: ops::Range<usize>,
+    ) -> Result<message::Span, ExecError> {
+        let source = fs::read_to_string(&current.file_name)
+            .map_err(|err| ExecError::Source(current.file_name.clone(), err.to_string()))?;
+        let found = if matches!(op, TextOperation::TsNode) {
+            ts::enclosing_node(&source, selection, arg)
+        } else {
+            let query = ts::query(arg)
+                .map_err(|err| ExecError::InvalidPattern(arg.to_owned(), err.to_string()))?;
+            ts::first_capture(&source, selection, &query)
+        };
+        let found = found.ok_or(ExecError::NoMatches(op))?;
+        Ok(message::Span::from_source(
+            &current.file_name,
+            &source,
+            found,
+        ))
+    }
+
+    /// Asks the user for input while running
+    pub fn is_interactive(&self) -> bool {
+        self.choose_suggestion || self.on_error == OnError::Prompt || self.step
+    }
+
+    /// Steps through the sequence on the first match only
+    pub fn is_stepping(&self) -> bool {
+        self.step
+    }
+
+    /// Which suggestion alternative to apply, or `None` for all of them
+    fn pick_suggestion(
+        &self,
+        target: &message::CompilerMessage,
+    ) -> Result<Option<usize>, ExecError> {
+        let alternatives = target.suggestion_alternatives();
+        let describe = |alternative: &Vec<&message::Span>| -> String {
+            alternative
+                .iter()
+                .map(|span| span.suggested_replacement.as_deref().unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(" ... ")
+        };
+
+        if let Some(index) = self.suggestion_index {
+            if index >= alternatives.len() {
+                return Err(ExecError::NoSuggestion);
+            }
+            Ok(Some(index))
+        } else if let Some(pattern) = &self.suggestion_match {
+            let re =
+                Regex::new(pattern).map_err(|err| ExecError::InvalidRegex(pattern.clone(), err))?;
+            alternatives
+                .iter()
+                .position(|alternative| re.is_match(&describe(alternative)))
+                .map(Some)
+                .ok_or(ExecError::NoSuggestion)
+        } else if self.choose_suggestion && alternatives.len() > 1 {
+            let options: Vec<String> = alternatives.iter().map(describe).collect();
+            interactive::choose(&target.message, &options)
+                .expect("Unable to read from stdin")
+                .map(Some)
+                .ok_or(ExecError::NoSuggestion)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Report an execution error, and record it as a failure.
+    /// Returns `Err` if the whole run should be aborted, otherwise the item is skipped.
+    fn handle_error(
+        &self,
+        span: Option<&message::Span>,
+        error: ExecError,
+        failures: &mut Vec<Failure>,
+    ) -> Result<(), Aborted> {
+        if let Some(span) = span {
+            info!("{}:{}:", span.file_name, span.line_start);
+        }
+        info!(" Execution failed: {}", error);
+
+        let abort = error.stop_all()
+            && match self.on_error {
+                OnError::Abort => true,
+                OnError::Skip => false,
+                OnError::Prompt => loop {
+                    let answer = interactive::prompt(" [s]kip or [a]bort? ")
+                        .expect("Unable to read from stdin");
+                    match answer.as_str() {
+                        "s" | "skip" => break false,
+                        "a" | "abort" => break true,
+                        _ => {}
+                    }
+                },
+            };
+
+        failures.push(Failure {
+            file: span.map(|span| span.file_name.clone()),
+            line: span.map(|span| span.line_start),
+            error,
+        });
+
+        if abort {
+            log::write_line(format_args!("Aborting"));
+            Err(Aborted)
+        } else {
+            Ok(())
+        }
     }
 
-    pub fn compute_diffs(&self, target: &message::CompilerMessage) -> Result<Vec<Change>, ()> {
-        let mut changes = Vec::new();
+    /// Compute changes for a diagnostic. Failed items are recorded into `failures`.
+    /// Returns `Err` if the whole run should be aborted.
+    pub fn compute_diffs(
+        &self,
+        target: &message::CompilerMessage,
+        failures: &mut Vec<Failure>,
+    ) -> Result<Vec<SpanChange>, Aborted> {
+        let ops = match self.parse() {
+            Ok(ops) => ops,
+            Err(err) => {
+                self.handle_error(target.primary_spans().next(), err, failures)?;
+                return Ok(Vec::new());
+            }
+        };
+
+        // Goto operations split the sequence into segments, each applied to its own span
+        let mut segments = vec![(None, &ops[..])];
+        let mut rest = &ops[..];
+        while let Some(i) = rest.iter().position(|(op, _)| op.is_goto()) {
+            segments.last_mut().unwrap().1 = &rest[..i];
+            segments.push((Some(&rest[i]), &rest[i + 1..]));
+            rest = &rest[i + 1..];
+        }
+
+        // Spans addressed by index are coordinated edits of the whole diagnostic,
+        // so running the sequence for each primary span would repeat them
+        let once = ops
+            .iter()
+            .any(|(op, _)| matches!(op, TextOperation::GotoSpan));
+        let primary_count = if once { 1 } else { usize::MAX };
+
+        let alternative = if self.applies_suggestions() {
+            match self.pick_suggestion(target) {
+                Ok(alternative) => alternative,
+                Err(err) => {
+                    self.handle_error(target.primary_spans().next(), err, failures)?;
+                    return Ok(Vec::new());
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut changes: Vec<SpanChange> = Vec::new();
+        let mut stepping = self.step;
+        // Text and change location of the lines of each primary span changed so far
+        let mut changed_lines: HashMap<(String, ops::Range<usize>), ChangedLines> = HashMap::new();
         'spans: for SpanAndSuggestions {
-            primary: span,
-            suggestions,
-        } in target.spans_with_suggestions()
+            primary,
+            mut suggestions,
+        } in target
+            .spans_with_suggestions(alternative)
+            .take(primary_count)
         {
-            let mut new = String::new();
-            for part in span.text.iter() {
-                let mut selection = part.highlighted_span();
-
-                let mut new_text = part.text.clone();
-
-                if self.suggestion {
-                    for (s_range, s_text, _) in suggestions.clone().into_iter().rev() {
-                        if s_range.end <= selection.start {
-                            selection.start -= s_text.len();
-                            selection.end -= s_text.len();
-                        } else if s_range.end <= selection.end {
-                            let overlap = selection.end - s_range.end;
-                            selection.start = s_range.start;
-                            selection.end = selection.start + overlap;
-                        } else if s_range.start <= selection.end {
-                            selection.end = s_range.start;
-                            selection.start = selection.start.min(selection.end);
-                        }
+            if self.auto_only {
+                let found = !suggestions.is_empty();
+                suggestions.retain(|(_, _, applicability)| {
+                    *applicability == SuggestionApplicability::MachineApplicable
+                });
+                if suggestions.is_empty() {
+                    if found {
+                        let code = target.code().unwrap_or("<uncoded>").to_owned();
+                        let err = ExecError::NotMachineApplicable(code);
+                        self.handle_error(Some(&primary), err, failures)?;
+                    }
+                    continue;
+                }
+            }
+
+            let mut span_changes = Vec::new();
+            let mut state = self.state();
+            let mut file_edits: BTreeMap<String, Vec<FileEdit>> = BTreeMap::new();
+            let mut current = primary.clone();
+            // Byte range of the file the operations of the last segment ended up selecting
+            let mut file_selection = primary.byte_start..primary.byte_end;
 
-                        new_text.replace_range(s_range, &s_text);
+            for (goto, segment) in segments.iter() {
+                let span = match goto {
+                    None => primary.clone(),
+                    Some((op, args)) => {
+                        // Suggestions only apply to the primary span
+                        suggestions.clear();
+                        let selection = file_selection.clone();
+                        let regex_flags = self.regex_flags();
+                        match Self::goto_target(target, *op, args, &current, selection, regex_flags)
+                        {
+                            Ok(span) => {
+                                if self.trace_ops {
+                                    info!(
+                                        "{} -> {}:{}",
+                                        trace_label(op.name(), args),
+                                        span.file_name,
+                                        span.line_start
+                                    );
+                                }
+                                span
+                            }
+                            Err(err) => {
+                                self.handle_error(Some(&primary), err, failures)?;
+                                continue 'spans;
+                            }
+                        }
                     }
+                };
+
+                if span.macro_call_site().is_some() {
+                    // Suggestions refer to the expanded code, so they don't apply here
+                    suggestions.clear();
                 }
+                let Some(span) = self.resolve_span(&span, failures) else {
+                    continue 'spans;
+                };
+                current = span.clone();
 
-                if let Err(err) = self.run(&mut new_text, selection.clone()) {
-                    println!("{}:{}:", span.file_name, span.line_start);
-                    println!(" Execution failed: {:?}", err);
-                    if err.stop_all() {
-                        return Err(());
+                let line_break = span_line_break(&span);
+                // Primary spans on the same lines, like the unused names of one import, run on
+                // the text the earlier ones left, and their change replaces the earlier one
+                let lines = (span.file_name.clone(), span.outer_byte_range(line_break));
+                let earlier = match goto {
+                    None if suggestions.is_empty() && span.text.len() == 1 => {
+                        changed_lines.get(&lines).cloned()
+                    }
+                    _ => None,
+                };
+                let mut new = String::new();
+                let mut segment_state = state.clone();
+                let mut line_start = span.outer_byte_range(line_break).start;
+                let mut selected: Option<ops::Range<usize>> = None;
+                for (i, part) in span.text.iter().enumerate() {
+                    let mut selection = part.highlighted_span();
+
+                    let mut new_text = part.text.clone();
+                    if let Some((earlier_text, _)) = &earlier {
+                        selection =
+                            text::Edit::between(&part.text, earlier_text).adjust_range(selection);
+                        new_text = earlier_text.clone();
+                    }
+
+                    if self.applies_suggestions() {
+                        for (s_range, s_text, _) in suggestions.clone().into_iter().rev() {
+                            if s_range.end <= selection.start {
+                                let shift = |i: usize| i - s_range.len() + s_text.len();
+                                selection = shift(selection.start)..shift(selection.end);
+                            } else if s_range.end <= selection.end {
+                                let overlap = selection.end - s_range.end;
+                                selection.start = s_range.start;
+                                selection.end = selection.start + overlap;
+                            } else if s_range.start <= selection.end {
+                                selection.end = s_range.start;
+                                selection.start = selection.start.min(selection.end);
+                            }
+
+                            new_text.replace_range(s_range, &s_text);
+                        }
+                        selection = text::snap_range(&new_text, selection);
+                    }
+
+                    segment_state = state.clone();
+                    let result = if stepping {
+                        stepping = false;
+                        Self::run_stepping(
+                            segment,
+                            &mut segment_state,
+                            &mut new_text,
+                            selection.clone(),
+                        )
                     } else {
+                        Self::run(
+                            segment,
+                            &mut segment_state,
+                            &mut new_text,
+                            selection.clone(),
+                            self.trace_ops,
+                        )
+                    };
+                    let part_selection = match result {
+                        Ok(part_selection) => part_selection,
+                        Err(err) => {
+                            self.handle_error(Some(&span), err, failures)?;
+                            continue 'spans;
+                        }
+                    };
+                    // Where the selection is in the file, for a goto operation to start from
+                    let part_selection =
+                        text::Edit::between(&new_text, &part.text).adjust_range(part_selection);
+                    let part_selection =
+                        line_start + part_selection.start..line_start + part_selection.end;
+                    selected = Some(match selected {
+                        Some(range) => {
+                            range.start.min(part_selection.start)..range.end.max(part_selection.end)
+                        }
+                        None => part_selection,
+                    });
+                    line_start += part.text.len() + line_break.len();
+                    if i > 0 {
+                        new.push_str(line_break);
+                    }
+                    new.push_str(&new_text);
+                }
+                state = segment_state;
+                file_selection = selected.unwrap_or(span.byte_start..span.byte_end);
+                // Marks are positions in the text of the segment
+                state.marks.clear();
+                if !state.file_edits.is_empty() {
+                    file_edits
+                        .entry(span.file_name.clone())
+                        .or_default()
+                        .append(&mut state.file_edits);
+                }
+
+                if let Some((_, Some(earlier_location))) = &earlier {
+                    changes.retain(|c| {
+                        c.change.file != Path::new(&span.file_name)
+                            || c.change.patch.location != *earlier_location
+                    });
+                }
+                // Segments only used to find the next span would conflict with its change
+                if new == span.text_with(line_break) {
+                    if goto.is_none() {
+                        changed_lines.insert(lines, (new, None));
+                    }
+                    continue;
+                }
+                let mut location = span.outer_byte_range(line_break);
+                if mem::take(&mut state.delete_lines) && new.is_empty() {
+                    location.end += line_break_len(&span.file_name, location.end);
+                }
+                if goto.is_none() {
+                    changed_lines.insert(lines, (new.clone(), Some(location.clone())));
+                }
+                // Scopes can be as large as the file, so only the words that change are replaced,
+                // instead of the whole scope conflicting with the changes of other diagnostics
+                if matches!(goto, Some((TextOperation::Scope, _))) {
+                    match word_changes(&span.file_name, location, &new) {
+                        Ok(changes) => span_changes.extend(changes),
+                        Err(err) => {
+                            self.handle_error(Some(&span), err, failures)?;
+                            continue 'spans;
+                        }
+                    }
+                    continue;
+                }
+                span_changes.push(SpanChange {
+                    applicability: self
+                        .applies_suggestions()
+                        .then(|| suggestions.iter().map(|(_, _, a)| *a).max())
+                        .flatten(),
+                    change: Change {
+                        file: PathBuf::from(&span.file_name),
+                        patch: Patch {
+                            location,
+                            bytes: new.bytes().collect(),
+                        },
+                    },
+                    span,
+                });
+            }
+
+            for (file, edits) in file_edits {
+                match file_changes(&file, &edits) {
+                    Ok(file_changes) => span_changes.extend(file_changes),
+                    Err(err) => {
+                        self.handle_error(Some(&primary), err, failures)?;
                         continue 'spans;
                     }
                 }
-                new.push_str(&new_text);
             }
 
-            changes.push(Change {
-                file: PathBuf::from(&span.file_name),
-                patch: Patch {
-                    location: span.outer_byte_range(),
-                    bytes: new.bytes().collect(),
+            changes.extend(span_changes);
+        }
+
+        if self.applies_suggestions() {
+            self.detached_suggestion_changes(target, alternative, &mut changes, failures)?;
+        }
+        Ok(changes)
+    }
+
+    /// Changes applying the suggestion spans on other lines than the primary spans,
+    /// one for the lines of each span, like the return type of a function
+    fn detached_suggestion_changes(
+        &self,
+        target: &message::CompilerMessage,
+        alternative: Option<usize>,
+        changes: &mut Vec<SpanChange>,
+        failures: &mut Vec<Failure>,
+    ) -> Result<(), Aborted> {
+        let mut by_lines: BTreeMap<(String, usize, usize), Vec<&message::Span>> = BTreeMap::new();
+        for help in target.detached_suggestions(alternative) {
+            let lines = help.outer_byte_range("\n");
+            let key = (help.file_name.clone(), lines.start, lines.end);
+            by_lines.entry(key).or_default().push(help);
+        }
+        for ((file, start, end), mut helps) in by_lines {
+            let applicability = helps
+                .iter()
+                .map(|help| {
+                    help.suggestion_applicability
+                        .unwrap_or(SuggestionApplicability::Unspecified)
+                })
+                .max()
+                .unwrap();
+            if self.auto_only && applicability != SuggestionApplicability::MachineApplicable {
+                let code = target.code().unwrap_or("<uncoded>").to_owned();
+                let err = ExecError::NotMachineApplicable(code);
+                self.handle_error(Some(helps[0]), err, failures)?;
+                continue;
+            }
+            // Later spans first, so that the earlier ones stay where they are
+            helps.sort_by_key(|help| help.byte_start);
+            let mut new = helps[0].text[0].text.clone();
+            for help in helps.iter().rev() {
+                let replacement = help.suggested_replacement.as_deref().unwrap_or_default();
+                new.replace_range(help.text[0].highlighted_span(), replacement);
+            }
+            changes.push(SpanChange {
+                applicability: Some(applicability),
+                span: helps[0].clone(),
+                change: Change {
+                    file: PathBuf::from(file),
+                    patch: Patch {
+                        location: start..end,
+                        bytes: new.into_bytes(),
+                    },
                 },
             });
         }
-        Ok(changes)
+        Ok(())
     }
 
-    pub fn preview(&self, target: &message::CompilerMessage, changes: &[Change]) {
-        for (span, change) in target.spans.iter().zip(changes) {
-            print!("{}:{}:", span.file_name, span.line_start);
-            if let Some(label) = span.label.as_ref() {
-                print!(" {}", label);
+    /// Show the changes of the message, each labeled with the level and the code of the
+    /// diagnostic, and how certain the applied suggestions are
+    pub fn preview(
+        &self,
+        message: &message::CompilerMessage,
+        changes: &[SpanChange],
+        style: DiffStyle,
+    ) {
+        let level = match message.level.as_str() {
+            "error" => message.level.red(),
+            "warning" => message.level.yellow(),
+            level => level.normal(),
+        };
+        let mut kind = level.bold().to_string();
+        if let Some(code) = message.code() {
+            kind.push_str(&format!("[{}]", code));
+        }
+        for SpanChange {
+            span,
+            change,
+            applicability,
+        } in changes
+        {
+            let mut header = format!("{}:{}: {}", span.file_name, span.line_start, kind);
+            if let Some(applicability) = applicability {
+                let name = applicability.to_string();
+                let name = match applicability {
+                    SuggestionApplicability::MachineApplicable => name.green(),
+                    SuggestionApplicability::MaybeIncorrect => name.yellow(),
+                    _ => name.red(),
+                };
+                header.push_str(&format!(" ({})", name));
             }
-            println!();
-            show_text_diff(
-                &span.raw_text(),
-                &String::from_utf8_lossy(&change.patch.bytes),
-            );
+            if let Some(label) = &span.label {
+                header.push_str(&format!(": {}", label));
+            }
+            info!("{}", header);
+            let new = String::from_utf8_lossy(&change.patch.bytes);
+            info!("{}", diff::render(&span.raw_text(), &new, style));
         }
     }
 }
 
-fn show_text_diff(old: &str, new: &str) {
-    let diff = TextDiff::from_graphemes(old, new);
+#[cfg(test)]
+mod tests {
+    use strum::EnumProperty;
 
-    let before: String = diff
-        .iter_all_changes()
-        .filter_map(|c| match c.tag() {
-            ChangeTag::Equal => Some(c.value().to_string()),
-            ChangeTag::Delete => Some(c.value().white().on_red().to_string()),
-            ChangeTag::Insert => None,
-        })
-        .collect();
+    use super::{
+        file_changes, parse_ops, validate_ops, ExecError, FileEdit, Operation, RegexFlags,
+        Selection, State, TextOperation,
+    };
 
-    let after: String = diff
-        .iter_all_changes()
-        .filter_map(|c| match c.tag() {
-            ChangeTag::Equal => Some(c.value().to_string()),
-            ChangeTag::Insert => Some(c.value().black().on_green().to_string()),
-            ChangeTag::Delete => None,
-        })
-        .collect();
+    fn validate(ops: &[&str]) -> Result<(), ExecError> {
+        validate_ops(&parse_ops(ops)?, false, RegexFlags::default())
+    }
 
-    println!("{}{}\n{}{}\n", "-".red(), before, "+".green(), after);
+    #[test]
+    fn test_arithmetic() {
+        let eval = |text: &str, ops: &[&str]| {
+            let mut text = text.to_owned();
+            let ops = parse_ops(ops).unwrap();
+            Operation::run(&ops, &mut State::default(), &mut text, 0..1, false).map(|_| text)
+        };
+        assert_eq!(
+            eval(
+                "[u8; 4]",
+                &["first", "\\d+\\]", "narrow", "\\d+", "incr", "2"]
+            )
+            .unwrap(),
+            "[u8; 6]"
+        );
+        assert_eq!(eval("x.1", &["first", "\\d", "decr", "3"]).unwrap(), "x.-2");
+        for op in ["grow-start", "shrink-end", "shift"] {
+            let too_far = (isize::MAX as usize + 1).to_string();
+            assert!(matches!(
+                eval("x.1", &[op, &too_far]),
+                Err(ExecError::InvalidNumber(_))
+            ));
+        }
+        assert_eq!(
+            eval("x.1", &["shift", "2", "grow-start", "1", "delete"]).unwrap(),
+            "x"
+        );
+        assert_eq!(
+            eval("x.1", &["first", "\\d", "push", "replace", "${top+1}$pop"]).unwrap(),
+            "x.21"
+        );
+        assert!(matches!(
+            eval("x.1", &["incr", "1"]),
+            Err(ExecError::InvalidNumber(_))
+        ));
+        let max = i128::MAX.to_string();
+        assert!(matches!(
+            eval(&max, &["whole", "incr", "1"]),
+            Err(ExecError::InvalidNumber(_))
+        ));
+        assert!(matches!(
+            eval("-2", &["whole", "decr", &max]),
+            Err(ExecError::InvalidNumber(_))
+        ));
+        assert!(matches!(
+            eval(&max, &["whole", "push", "replace", "${top+1}"]),
+            Err(ExecError::InvalidNumber(_))
+        ));
+    }
+
+    #[test]
+    fn test_stack_strings() {
+        let eval = |ops: &[&str]| {
+            let mut text = "f(&foo::bar)".to_owned();
+            let ops = parse_ops(ops).unwrap();
+            Operation::run(&ops, &mut State::default(), &mut text, 2..11, false).map(|_| text)
+        };
+        assert_eq!(
+            eval(&["push", "stack-slice", "(\\w+)$", "replace", "$pop"]).unwrap(),
+            "f(bar)"
+        );
+        assert_eq!(
+            eval(&["push", "stack-apply", "^&|\\w+::", "", "replace", "$pop"]).unwrap(),
+            "f(bar)"
+        );
+        assert_eq!(
+            eval(&["push", "s-dup", "stack-concat", "replace", "$pop"]).unwrap(),
+            "f(&foo::bar&foo::bar)"
+        );
+        assert!(matches!(
+            eval(&["push", "stack-slice", "x", "replace", "$pop"]),
+            Err(ExecError::NoMatches(_))
+        ));
+        assert!(matches!(
+            validate(&["push", "stack-concat"]),
+            Err(ExecError::StackUnderflow(_))
+        ));
+    }
+
+    #[test]
+    fn test_registers() {
+        let eval = |ops: &[&str]| {
+            let mut text = "let (a, b) = f();".to_owned();
+            let ops = parse_ops(ops).unwrap();
+            Operation::run(&ops, &mut State::default(), &mut text, 5..6, false).map(|_| text)
+        };
+        assert_eq!(
+            eval(&[
+                "store", "x", "next", "\\w+", "store", "y", "replace", "$reg:x", "original",
+                "replace", "${reg:y}"
+            ])
+            .unwrap(),
+            "let (b, a) = f();"
+        );
+        assert_eq!(
+            eval(&["store", "x", "load", "x", "replace", "$pop$reg:x"]).unwrap(),
+            "let (aa, b) = f();"
+        );
+        assert!(matches!(
+            validate(&["replace", "$reg:x", "store", "x"]),
+            Err(ExecError::EmptyRegister(_))
+        ));
+        assert!(matches!(
+            validate(&["load", "x"]),
+            Err(ExecError::EmptyRegister(_))
+        ));
+        assert!(validate(&["store", "a-b"]).is_err());
+        assert!(matches!(
+            validate(&["replace", "${cmd:date}"]),
+            Err(ExecError::ShellNotAllowed)
+        ));
+    }
+
+    #[test]
+    fn test_marks() {
+        let eval = |ops: &[&str]| {
+            let mut text = "f(a, bb, c)".to_owned();
+            let ops = parse_ops(ops).unwrap();
+            Operation::run(&ops, &mut State::default(), &mut text, 2..3, false).map(|_| text)
+        };
+        assert_eq!(
+            eval(&["mark", "m", "next", "b+", "between", "m", "delete"]).unwrap(),
+            "f(, c)"
+        );
+        assert_eq!(
+            eval(&[
+                "next", "c", "mark", "m", "original", "replace", "xyz", "between", "m", "delete"
+            ])
+            .unwrap(),
+            "f()"
+        );
+        assert!(matches!(
+            validate(&["mark", "m", "item", "between", "m"]),
+            Err(ExecError::NoMark(_))
+        ));
+    }
+
+    #[test]
+    fn test_each() {
+        let eval = |ops: &[&str]| {
+            let mut text = "f(a, bb, c); g(d)".to_owned();
+            let ops = parse_ops(ops).unwrap();
+            Operation::run(&ops, &mut State::default(), &mut text, 0..11, false).map(|_| text)
+        };
+        assert_eq!(
+            eval(&[
+                "each",
+                "\\w+",
+                "push",
+                "replace",
+                "${pop|upper}x",
+                "end",
+                "replace",
+                "_"
+            ])
+            .unwrap(),
+            "_; g(d)"
+        );
+        assert_eq!(
+            eval(&["each", "\\w+", "push", "replace", "${pop|upper}x", "end"]).unwrap(),
+            "Fx(Ax, BBx, Cx); g(d)"
+        );
+        assert_eq!(
+            eval(&["each", "\\w+", "each", "b", "replace", "BBB", "end", "end"]).unwrap(),
+            "f(a, BBBBBB, c); g(d)"
+        );
+        assert_eq!(
+            eval(&["narrow", "\\(.*\\)", "each", "\\w+", "next", ",|\\)", "replace", ";", "end"])
+                .unwrap(),
+            "f(a; bb; c;; g(d)"
+        );
+        assert!(matches!(
+            validate(&["each", "x", "delete"]),
+            Err(ExecError::InvalidBlock(_))
+        ));
+        assert!(matches!(
+            validate(&["each", "x", "goto-span", "0", "end"]),
+            Err(ExecError::InvalidBlock(_))
+        ));
+        assert!(matches!(
+            validate(&["end"]),
+            Err(ExecError::InvalidBlock(_))
+        ));
+    }
+
+    #[test]
+    fn test_regex_flags() {
+        let eval = |flags: &str, ops: &[&str]| {
+            let mut text = "Foo(a,\nB)".to_owned();
+            let ops = parse_ops(ops).unwrap();
+            let mut state = State::new(false, flags.parse().unwrap());
+            Operation::run(&ops, &mut state, &mut text, 0..9, false).map(|_| text)
+        };
+        assert_eq!(eval("", &["sub", "f", "x"]).unwrap(), "Foo(a,\nB)");
+        assert_eq!(eval("i", &["sub", "f", "x"]).unwrap(), "xoo(a,\nB)");
+        assert_eq!(eval("m", &["sub", "^B", "C"]).unwrap(), "Foo(a,\nC)");
+        assert_eq!(eval("i,s", &["sub", "a.+b", "-"]).unwrap(), "Foo(-)");
+        // Literals only take `i`, so `x` doesn't drop their whitespace
+        assert_eq!(
+            eval("i,x", &["find-str", "a,\nb", "replace", "-"]).unwrap(),
+            "Foo(-)"
+        );
+        assert!("i,q".parse::<RegexFlags>().is_err());
+        assert!(matches!(
+            validate_ops(
+                &parse_ops(&["first", "a b"]).unwrap(),
+                false,
+                "x".parse().unwrap()
+            ),
+            Ok(())
+        ));
+    }
+
+    #[test]
+    fn test_literal() {
+        let eval = |ops: &[&str]| {
+            let mut text = "f(a[0]); f(a[0])".to_owned();
+            let ops = parse_ops(ops).unwrap();
+            Operation::run(&ops, &mut State::default(), &mut text, 0..1, false).map(|_| text)
+        };
+        assert_eq!(
+            eval(&["next-str", "(a[0])", "replace", "()"]).unwrap(),
+            "f(); f(a[0])"
+        );
+        assert_eq!(
+            eval(&["push", "ns", "$top(", "delete"]).unwrap(),
+            "f(a[0]); a[0])"
+        );
+        assert!(matches!(
+            eval(&["ps", "f("]),
+            Err(ExecError::NoMatches(TextOperation::PrevStr))
+        ));
+    }
+
+    #[test]
+    fn test_external() {
+        let ops = parse_ops(&["push", "x-snake-case", "replace", "$pop"]).unwrap();
+        assert!(matches!(ops[1], (TextOperation::External, ref args) if args == &["snake-case"]));
+        assert_eq!(
+            ops[1].0.word(&ops[1].1),
+            ("x-snake-case".to_owned(), &[][..])
+        );
+        assert!(matches!(
+            parse_ops(&["x-"]),
+            Err(ExecError::UnknownOp { .. })
+        ));
+
+        let mut text = "x".to_owned();
+        assert!(matches!(
+            Operation::run(&ops, &mut State::default(), &mut text, 0..1, false),
+            Err(ExecError::Command(command, _)) if command == "refix-op-snake-case"
+        ));
+
+        assert_eq!(Selection { start: 1, end: 2 }.range("aé"), None);
+        assert_eq!(Selection { start: 1, end: 3 }.range("aé"), Some(1..3));
+        assert_eq!(Selection { start: 3, end: 1 }.range("aé"), None);
+    }
+
+    #[test]
+    fn test_validate_ops() {
+        assert!(validate(&["push", "next", "x", "replace", "$pop"]).is_ok());
+        assert!(validate(&["push", "goto-span", "1", "replace", "${top}$pop"]).is_ok());
+        assert!(matches!(
+            validate(&["next", "(", "delete"]),
+            Err(ExecError::InvalidRegex(..))
+        ));
+        assert!(matches!(
+            validate(&["goto-help", "x"]),
+            Err(ExecError::InvalidNumber(_))
+        ));
+        assert!(matches!(
+            validate(&["push", "replace", "$pop$pop"]),
+            Err(ExecError::StackUnderflow(TextOperation::Replace))
+        ));
+        assert!(matches!(
+            validate(&["stack-dup"]),
+            Err(ExecError::StackUnderflow(TextOperation::StackDup))
+        ));
+        assert!(matches!(
+            validate(&["delete", "delte"]),
+            Err(ExecError::UnknownOp {
+                position: Some(1),
+                suggestion: Some("delete"),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        std::fs::write(&file, "use std::fmt;\nfn main() {}").unwrap();
+        let edits = [
+            FileEdit::Prepend("// a".to_owned()),
+            FileEdit::Prepend("// b".to_owned()),
+            FileEdit::Append("// end".to_owned()),
+        ];
+        let changes = file_changes(file.to_str().unwrap(), &edits).unwrap();
+        let patches: Vec<_> = changes
+            .iter()
+            .map(|c| {
+                (
+                    c.change.patch.location.clone(),
+                    c.change.patch.bytes.as_slice(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            patches,
+            [(0..0, &b"// b\n// a\n"[..]), (26..26, &b"\n// end\n"[..])]
+        );
+
+        let edits = [FileEdit::Prepend("use std::fmt;".to_owned())];
+        assert!(file_changes(file.to_str().unwrap(), &edits)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_file_sub_example() {
+        // Quoted arguments at odd indices
+        let example = TextOperation::FileSub.get_str("example").unwrap();
+        let words: Vec<&str> = example
+            .split('\'')
+            .enumerate()
+            .flat_map(|(i, part)| match i % 2 {
+                0 => part.split_whitespace().collect(),
+                _ => vec![part],
+            })
+            .collect();
+        let ops = parse_ops(&words).unwrap();
+        let mut state = State::default();
+        let mut text = "fn f(_: Foo) {}".to_owned();
+        Operation::run(&ops, &mut state, &mut text, 8..11, false).unwrap();
+        assert_eq!(text, "fn f(_: Bar) {}");
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        std::fs::write(&file, "use foo::Baz;\nuse foo::Foo;\n").unwrap();
+        let changes = file_changes(file.to_str().unwrap(), &state.file_edits).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].change.patch.location, 14..27);
+        assert_eq!(changes[0].change.patch.bytes, b"use foo::Bar;");
+    }
 }
@@ -0,0 +1,39 @@
+use std::{
+    env,
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+
+/// Pager command from `$PAGER`, or `None` if paging is disabled
+fn pager_command() -> Option<Vec<String>> {
+    let pager = env::var("PAGER").unwrap_or_else(|_| "less".to_owned());
+    let parts: Vec<String> = pager.split_whitespace().map(str::to_owned).collect();
+    if parts.is_empty() || parts[0] == "cat" {
+        return None;
+    }
+    Some(parts)
+}
+
+/// Show text in the pager, printing it directly if the pager cannot be used
+pub fn page(text: &str) {
+    if let Some(parts) = pager_command() {
+        let mut cmd = Command::new(&parts[0]);
+        cmd.args(&parts[1..]).stdin(Stdio::piped());
+        // Like git: quit if the text fits on one screen, keep colors, and don't clear the screen
+        if env::var_os("LESS").is_none() {
+            cmd.env("LESS", "FRX");
+        }
+
+        if let Ok(mut child) = cmd.spawn() {
+            let mut stdin = child.stdin.take().unwrap();
+            // The pager may exit before reading everything, which is fine
+            let _ = stdin.write_all(text.as_bytes());
+            drop(stdin);
+            let _ = child.wait();
+            return;
+        }
+    }
+
+    print!("{}", text);
+    io::stdout().flush().expect("Unable to flush stdout");
+}
@@ -0,0 +1,114 @@
+//! Regexes of operation sequences, compiled with the selected engine
+
+use std::ops;
+
+use regex::Regex;
+
+/// Regex engine used for the regexes of operations
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RegexEngine {
+    /// The `regex` crate, which runs in linear time but has no lookaround or backreferences
+    #[default]
+    Default,
+    /// The `fancy-regex` crate, which supports lookaround and backreferences by backtracking
+    #[cfg(feature = "fancy-regex")]
+    Fancy,
+}
+
+/// Compiled regex of either engine.
+/// Errors of the fancy engine while matching, such as running out of the backtracking limit,
+/// are treated as the regex not matching.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Default(Regex),
+    #[cfg(feature = "fancy-regex")]
+    Fancy(fancy_regex::Regex),
+}
+
+impl Pattern {
+    /// First match starting at or after `start`, seeing the text before it for lookbehind
+    pub fn find_at(&self, text: &str, start: usize) -> Option<ops::Range<usize>> {
+        match self {
+            Self::Default(re) => re.find_at(text, start).map(|m| m.range()),
+            #[cfg(feature = "fancy-regex")]
+            Self::Fancy(re) => re.find_from_pos(text, start).ok()?.map(|m| m.range()),
+        }
+    }
+
+    pub fn find(&self, text: &str) -> Option<ops::Range<usize>> {
+        self.find_at(text, 0)
+    }
+
+    /// All non-overlapping matches
+    pub fn find_iter(&self, text: &str) -> Vec<ops::Range<usize>> {
+        match self {
+            Self::Default(re) => re.find_iter(text).map(|m| m.range()).collect(),
+            #[cfg(feature = "fancy-regex")]
+            Self::Fancy(re) => re
+                .find_iter(text)
+                .map_while(Result::ok)
+                .map(|m| m.range())
+                .collect(),
+        }
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.find(text).is_some()
+    }
+
+    /// Range of the first capture group of the first match, or the whole match without groups
+    pub fn capture(&self, text: &str) -> Option<ops::Range<usize>> {
+        match self {
+            Self::Default(re) => {
+                let caps = re.captures(text)?;
+                caps.get(1).or(caps.get(0)).map(|m| m.range())
+            }
+            #[cfg(feature = "fancy-regex")]
+            Self::Fancy(re) => {
+                let caps = re.captures(text).ok()??;
+                caps.get(1).or(caps.get(0)).map(|m| m.range())
+            }
+        }
+    }
+
+    /// Replace the first match, expanding `$1` and `${name}` in the replacement
+    pub fn replace(&self, text: &str, replacement: &str) -> String {
+        self.replacen(text, 1, replacement)
+    }
+
+    /// Replace all matches, expanding `$1` and `${name}` in the replacement
+    pub fn replace_all(&self, text: &str, replacement: &str) -> String {
+        self.replacen(text, 0, replacement)
+    }
+
+    fn replacen(&self, text: &str, limit: usize, replacement: &str) -> String {
+        match self {
+            Self::Default(re) => re.replacen(text, limit, replacement).into_owned(),
+            #[cfg(feature = "fancy-regex")]
+            Self::Fancy(re) => re
+                .try_replacen(text, limit, replacement)
+                .map_or_else(|_| text.to_owned(), |replaced| replaced.into_owned()),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "fancy-regex"))]
+mod tests {
+    use super::Pattern;
+
+    #[test]
+    fn test_fancy() {
+        let re = Pattern::Fancy(fancy_regex::Regex::new(r"(?<=\.)(\w+)(?=\()").unwrap());
+        assert_eq!(re.find("x.len().max(y)"), Some(2..5));
+        assert_eq!(re.find_iter("x.len().max(y)"), vec![2..5, 8..11]);
+        assert_eq!(re.find_at("x.len().max(y)", 3), Some(8..11));
+        assert_eq!(
+            re.replace_all("x.len().max(y)", "${1}_"),
+            "x.len_().max_(y)"
+        );
+
+        let re = Pattern::Fancy(fancy_regex::Regex::new(r"(\w+) \1").unwrap());
+        assert_eq!(re.capture("a b b c"), Some(2..3));
+        assert!(!re.is_match("a b c"));
+    }
+}
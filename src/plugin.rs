@@ -0,0 +1,124 @@
+//! WebAssembly plugins implementing `x-<name>` operations in-process.
+//!
+//! A plugin is a module without imports, named after its file, that exports:
+//! - `memory`, its linear memory
+//! - `alloc(len: i32) -> i32`, returning the address of a buffer of `len` bytes
+//! - `refix_op(ptr: i32, len: i32) -> i64`, taking the JSON input of an external operation
+//!   from the buffer, and returning the address of the JSON output in the high 32 bits
+//!   and its length in the low 32 bits
+//!
+//! Each call gets a fresh instance with limited fuel, so plugins can't keep state between spans
+//! or run forever.
+
+use std::{fmt, fs, path::Path, str::FromStr, sync::Arc};
+
+use wasmi::{Config, Engine, Linker, Module, Store};
+
+/// Fuel of each call, roughly the number of instructions it can run
+const FUEL: u64 = 1_000_000_000;
+
+/// Compiled plugin module
+#[derive(Clone)]
+pub struct Plugin {
+    name: String,
+    engine: Engine,
+    module: Arc<Module>,
+}
+
+impl fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Plugin").field(&self.name).finish()
+    }
+}
+
+impl FromStr for Plugin {
+    type Err = String;
+
+    /// Load the plugin from the path of its module
+    fn from_str(path: &str) -> Result<Self, String> {
+        let path = Path::new(path);
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or("expected a path to a .wasm file")?
+            .to_owned();
+        let bytes = fs::read(path).map_err(|err| err.to_string())?;
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, &bytes).map_err(|err| err.to_string())?;
+        Ok(Self {
+            name,
+            engine,
+            module: Arc::new(module),
+        })
+    }
+}
+
+impl Plugin {
+    /// Name of the operation, `x-<name>`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Run the operation on the JSON input, returning its JSON output
+    pub fn call(&self, input: &[u8]) -> Result<Vec<u8>, String> {
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(FUEL).map_err(message)?;
+        let instance = Linker::new(&self.engine)
+            .instantiate(&mut store, &self.module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(message)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or("no exported memory")?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, "alloc")
+            .map_err(message)?;
+        let refix_op = instance
+            .get_typed_func::<(i32, i32), i64>(&store, "refix_op")
+            .map_err(message)?;
+
+        let len = i32::try_from(input.len()).map_err(message)?;
+        let ptr = alloc.call(&mut store, len).map_err(message)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, input)
+            .map_err(message)?;
+        let output = refix_op.call(&mut store, (ptr, len)).map_err(message)? as u64;
+        let mut buffer = vec![0; (output & 0xffff_ffff) as usize];
+        memory
+            .read(&store, (output >> 32) as usize, &mut buffer)
+            .map_err(message)?;
+        Ok(buffer)
+    }
+}
+
+fn message(err: impl fmt::Display) -> String {
+    err.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::Plugin;
+
+    /// Module ignoring its input and always setting the stack
+    const WAT: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (data (i32.const 0) "{\"stack\":[\"x\"]}")
+            (func (export "alloc") (param i32) (result i32) i32.const 1024)
+            (func (export "refix_op") (param i32 i32) (result i64) i64.const 15))
+    "#;
+
+    #[test]
+    fn test_call() {
+        let mut file = tempfile::Builder::new().suffix(".wasm").tempfile().unwrap();
+        file.write_all(&wat::parse_str(WAT).unwrap()).unwrap();
+        let plugin: Plugin = file.path().to_str().unwrap().parse().unwrap();
+        assert!(plugin.name().starts_with(".tmp"));
+        assert_eq!(plugin.call(b"{}").unwrap(), br#"{"stack":["x"]}"#);
+        assert!("nonexistent.wasm".parse::<Plugin>().is_err());
+    }
+}
@@ -0,0 +1,110 @@
+//! Previews grouped by file, shown after all changes are computed
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use colored::Colorize;
+
+use cargo_refix::{
+    diff::DiffStyle,
+    interactive, log,
+    message::{CompilerMessage, ShowRendered},
+    operation::{Operation, SpanChange},
+};
+
+/// Preview of the changes a diagnostic makes to a file
+struct Entry {
+    line: usize,
+    changes: usize,
+    text: String,
+}
+
+/// Previews by file, in the order of their lines
+#[derive(Default)]
+pub struct FilePreviews(BTreeMap<PathBuf, Vec<Entry>>);
+
+impl FilePreviews {
+    /// Render the previews of the message's changes, to be shown under their files
+    pub fn add(
+        &mut self,
+        operation: &Operation,
+        message: &CompilerMessage,
+        changes: &[SpanChange],
+        rendered: ShowRendered,
+        style: DiffStyle,
+    ) {
+        let mut by_file: BTreeMap<&PathBuf, Vec<SpanChange>> = BTreeMap::new();
+        for change in changes {
+            by_file
+                .entry(&change.change.file)
+                .or_default()
+                .push(change.clone());
+        }
+        for (file, changes) in by_file {
+            let ((), text) = log::capture(|| {
+                if let Some(rendered) = message.show(rendered) {
+                    log::info!("{}\n", rendered);
+                }
+                operation.preview(message, &changes, style);
+            });
+            let line = changes.iter().map(|c| c.span.line_start).min().unwrap_or(0);
+            let entries = self.0.entry(file.clone()).or_default();
+            let index = entries.partition_point(|entry| entry.line <= line);
+            entries.insert(
+                index,
+                Entry {
+                    line,
+                    changes: changes.len(),
+                    text,
+                },
+            );
+        }
+    }
+
+    /// Show the previews under a header for each file. If `collapsible`,
+    /// ask before showing each file, so that the uninteresting ones can be skipped.
+    pub fn print(&self, collapsible: bool) {
+        let mut ask = collapsible;
+        for (file, entries) in &self.0 {
+            let changes: usize = entries.iter().map(|entry| entry.changes).sum();
+            let header = format!(
+                "{} ({} {})",
+                file.display(),
+                changes,
+                if changes == 1 { "change" } else { "changes" }
+            );
+            log::info!("{}", header.bold());
+            if ask {
+                match prompt() {
+                    Answer::Show => {}
+                    Answer::Collapse => continue,
+                    Answer::All => ask = false,
+                    Answer::Quit => return,
+                }
+            }
+            for entry in entries {
+                log::write_captured(&entry.text);
+            }
+        }
+    }
+}
+
+enum Answer {
+    Show,
+    Collapse,
+    All,
+    Quit,
+}
+
+fn prompt() -> Answer {
+    loop {
+        let answer = interactive::prompt(" show? [Y]es, [n]o, [a]ll, [q]uit showing: ")
+            .expect("Unable to read from stdin");
+        match answer.as_str() {
+            "" | "y" | "yes" => return Answer::Show,
+            "n" | "no" => return Answer::Collapse,
+            "a" | "all" => return Answer::All,
+            "q" | "quit" => return Answer::Quit,
+            _ => {}
+        }
+    }
+}
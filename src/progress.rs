@@ -0,0 +1,30 @@
+//! Progress indicator while cargo is running
+
+use std::{
+    io::{self, Write},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+const FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+
+/// Whether the progress line is currently on the screen
+static SHOWN: AtomicBool = AtomicBool::new(false);
+static FRAME: AtomicUsize = AtomicUsize::new(0);
+
+/// Replace the progress line with a new status
+pub fn update(status: &str) {
+    let frame = FRAMES[FRAME.fetch_add(1, Ordering::Relaxed) % FRAMES.len()];
+    let mut stderr = io::stderr().lock();
+    let _ = write!(stderr, "\r\x1b[2K{} {}", frame, status);
+    let _ = stderr.flush();
+    SHOWN.store(true, Ordering::Relaxed);
+}
+
+/// Remove the progress line, so other output can be printed
+pub fn clear() {
+    if SHOWN.swap(false, Ordering::Relaxed) {
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[2K");
+        let _ = stderr.flush();
+    }
+}
@@ -0,0 +1,169 @@
+//! Built-in recipes: operation sequences for frequent fixes, selected with `--recipe`
+
+/// Named operation sequence fixing a diagnostic
+#[derive(Debug, PartialEq, Eq)]
+pub struct Recipe {
+    pub name: &'static str,
+    /// Selector of the diagnostics the recipe is meant for, a code with optional sub-selectors
+    pub selector: &'static str,
+    pub about: &'static str,
+    /// Whether rustc's suggestion is applied before the operations, like with `--auto`
+    pub suggestions: bool,
+    pub ops: &'static [&'static str],
+}
+
+pub const RECIPES: &[Recipe] = &[
+    Recipe {
+        name: "remove-unused-import",
+        selector: "unused_imports",
+        about: "Remove the import, along with its line if nothing else is imported there",
+        suggestions: false,
+        ops: &[
+            "delete-item",
+            "whole",
+            "each",
+            r"^\s*use\s*([\w:]*\{\s*\})?\s*;\s*$",
+            "delete-line",
+            "end",
+        ],
+    },
+    Recipe {
+        name: "prefix-underscore",
+        selector: "unused_variables",
+        about: "Prefix the unused variable with `_`",
+        suggestions: false,
+        ops: &["push", "replace", "_$pop"],
+    },
+    Recipe {
+        name: "unwrap-to-question",
+        selector: "E0277:message=^the `\\?` operator can only be used in a function that returns",
+        about: "Where `?` is used in a function that can't return an error, make it return a \
+                `Result` as rustc suggests, and replace the `.unwrap()` calls in it with `?`",
+        suggestions: true,
+        ops: &[
+            "scope",
+            "item",
+            "whole",
+            "each",
+            r"\.unwrap\(\)",
+            "replace",
+            "?",
+            "end",
+        ],
+    },
+];
+
+/// Find a recipe by name
+pub fn parse(name: &str) -> Result<&'static Recipe, String> {
+    RECIPES
+        .iter()
+        .find(|recipe| recipe.name == name)
+        .ok_or_else(|| {
+            let names: Vec<&str> = RECIPES.iter().map(|recipe| recipe.name).collect();
+            format!(
+                "unknown recipe {:?}, expected one of {}",
+                name,
+                names.join(", ")
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use clap::Parser;
+
+    use super::{parse, RECIPES};
+    use crate::{
+        apply::FileChangeSet,
+        message::Msg,
+        operation::{parse_ops, validate_ops, Operation},
+        selector::Selector,
+    };
+
+    const E0277_SOURCE: &str = "fn main() {\n    let n: u32 = \"1\".parse().unwrap();\n    let m: u32 = \"2\".parse()?;\n    println!(\"{}\", n + m);\n}\n";
+
+    /// rustc's message for the source
+    const E0277: &str = r#"{"$message_type":"diagnostic","message":"the `?` operator can only be used in a function that returns `Result` or `Option` (or another type that implements `FromResidual`)","code":{"code":"E0277","explanation":null},"level":"error","spans":[{"file_name":"main.rs","byte_start":79,"byte_end":80,"line_start":3,"line_end":3,"column_start":29,"column_end":30,"is_primary":true,"text":[{"text":"    let m: u32 = \"2\".parse()?;","highlight_start":29,"highlight_end":30}],"label":"cannot use the `?` operator in a function that returns `()`","suggested_replacement":null,"suggestion_applicability":null,"expansion":{"span":{"file_name":"main.rs","byte_start":79,"byte_end":80,"line_start":3,"line_end":3,"column_start":29,"column_end":30,"is_primary":false,"text":[{"text":"    let m: u32 = \"2\".parse()?;","highlight_start":29,"highlight_end":30}],"label":null,"suggested_replacement":null,"suggestion_applicability":null,"expansion":null},"macro_decl_name":"desugaring of operator `?`","def_site_span":{"file_name":"main.rs","byte_start":0,"byte_end":0,"line_start":1,"line_end":1,"column_start":1,"column_end":1,"is_primary":false,"text":[],"label":null,"suggested_replacement":null,"suggestion_applicability":null,"expansion":null}}},{"file_name":"main.rs","byte_start":0,"byte_end":9,"line_start":1,"line_end":1,"column_start":1,"column_end":10,"is_primary":false,"text":[{"text":"fn main() {","highlight_start":1,"highlight_end":10}],"label":"this function should return `Result` or `Option` to accept `?`","suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[{"message":"consider adding return type","code":null,"level":"help","spans":[{"file_name":"main.rs","byte_start":9,"byte_end":9,"line_start":1,"line_end":1,"column_start":10,"column_end":10,"is_primary":true,"text":[{"text":"fn main() {","highlight_start":10,"highlight_end":10}],"label":null,"suggested_replacement":" -> Result<(), Box<dyn std::error::Error>>","suggestion_applicability":"MaybeIncorrect","expansion":null},{"file_name":"main.rs","byte_start":109,"byte_end":109,"line_start":5,"line_end":5,"column_start":1,"column_end":1,"is_primary":true,"text":[{"text":"}","highlight_start":1,"highlight_end":1}],"label":null,"suggested_replacement":"    Ok(())\n","suggestion_applicability":"MaybeIncorrect","expansion":null}],"children":[],"rendered":null}],"rendered":""}"#;
+
+    const UNUSED_IMPORTS_SOURCE: &str =
+        "use std::{fmt, io};\nuse std::{env, fs};\n\nfn main() {\n    let _ = env::args();\n}\n";
+
+    /// rustc's message for the source, with both names on the first line
+    const UNUSED_IMPORTS: &str = r#"{"$message_type":"diagnostic","message":"unused imports: `fmt` and `io`","code":{"code":"unused_imports","explanation":null},"level":"warning","spans":[{"file_name":"main.rs","byte_start":10,"byte_end":13,"line_start":1,"line_end":1,"column_start":11,"column_end":14,"is_primary":true,"text":[{"text":"use std::{fmt, io};","highlight_start":11,"highlight_end":14}],"label":null,"suggested_replacement":null,"suggestion_applicability":null,"expansion":null},{"file_name":"main.rs","byte_start":15,"byte_end":17,"line_start":1,"line_end":1,"column_start":16,"column_end":18,"is_primary":true,"text":[{"text":"use std::{fmt, io};","highlight_start":16,"highlight_end":18}],"label":null,"suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[{"message":"`#[warn(unused_imports)]` (part of `#[warn(unused)]`) on by default","code":null,"level":"note","spans":[],"children":[],"rendered":null},{"message":"remove the whole `use` item","code":null,"level":"help","spans":[{"file_name":"main.rs","byte_start":0,"byte_end":20,"line_start":1,"line_end":2,"column_start":1,"column_end":1,"is_primary":true,"text":[{"text":"use std::{fmt, io};","highlight_start":1,"highlight_end":20},{"text":"use std::{env, fs};","highlight_start":1,"highlight_end":1}],"label":null,"suggested_replacement":"","suggestion_applicability":"MachineApplicable","expansion":null}],"children":[],"rendered":null}],"rendered":""}"#;
+
+    /// Run the recipe on the message for the source, returning the new source
+    fn fix(recipe: &str, message: &str, source: &str) -> String {
+        #[derive(Parser)]
+        struct Args {
+            #[command(flatten)]
+            operation: Operation,
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("main.rs");
+        fs::write(&file, source).unwrap();
+        let line = message.replace(r#""main.rs""#, &format!("{:?}", file.to_str().unwrap()));
+        let message = Msg::parse(line.as_bytes()).unwrap().message.unwrap();
+        let recipe = parse(recipe).unwrap();
+        let selector: Selector = recipe.selector.parse().unwrap();
+        assert!(selector.matches(&message));
+
+        let args = Args::parse_from(["refix", "--recipe", recipe.name]);
+        let changes = args
+            .operation
+            .compute_diffs(&message, &mut Vec::new())
+            .unwrap();
+        let mut fcs = FileChangeSet::group(changes.into_iter().map(|c| c.change).collect());
+        assert_eq!(fcs.len(), 1);
+        String::from_utf8(fcs.remove(0).apply(source.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_recipes() {
+        for recipe in RECIPES {
+            let ops = parse_ops(recipe.ops).unwrap();
+            validate_ops(&ops, false, Default::default()).unwrap();
+        }
+        assert!(parse("prefix-underscore").is_ok());
+        assert!(parse("nonexistent").is_err());
+
+        let mut text = "use a::{b, c};".to_owned();
+        let ops = parse_ops(parse("remove-unused-import").unwrap().ops).unwrap();
+        Operation::run(&ops, &mut Default::default(), &mut text, 8..9, false).unwrap();
+        assert_eq!(text, "use a::{c};");
+        let mut text = "use a::b;".to_owned();
+        Operation::run(&ops, &mut Default::default(), &mut text, 4..8, false).unwrap();
+        assert_eq!(text, "");
+
+        let mut text = "let x = 1;".to_owned();
+        let ops = parse_ops(parse("prefix-underscore").unwrap().ops).unwrap();
+        Operation::run(&ops, &mut Default::default(), &mut text, 4..5, false).unwrap();
+        assert_eq!(text, "let _x = 1;");
+    }
+
+    #[test]
+    fn test_unwrap_to_question() {
+        assert_eq!(
+            fix("unwrap-to-question", E0277, E0277_SOURCE),
+            "fn main() -> Result<(), Box<dyn std::error::Error>> {\n    \
+             let n: u32 = \"1\".parse()?;\n    \
+             let m: u32 = \"2\".parse()?;\n    \
+             println!(\"{}\", n + m);\n    \
+             Ok(())\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_remove_unused_imports_on_line() {
+        assert_eq!(
+            fix(
+                "remove-unused-import",
+                UNUSED_IMPORTS,
+                UNUSED_IMPORTS_SOURCE
+            ),
+            "use std::{env, fs};\n\nfn main() {\n    let _ = env::args();\n}\n"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Reference of the operations available in operation sequences
+
+use std::process;
+
+use clap::Parser;
+use colored::Colorize;
+use strum::{EnumMessage, EnumProperty, IntoEnumIterator};
+
+use crate::{
+    operation::{ExecError, TextOperation, EXTERNAL_PREFIX},
+    recipe::RECIPES,
+    text,
+};
+
+/// List the operations available in operation sequences
+#[derive(Parser, Debug)]
+#[command(bin_name = "cargo refix ops")]
+pub struct OpsArgs {
+    /// Only show this operation
+    #[arg(value_name = "OP")]
+    pub op: Option<String>,
+}
+
+pub fn print(args: &OpsArgs) {
+    let ops: Vec<TextOperation> = match &args.op {
+        Some(name) => match name.parse() {
+            Ok(op) => vec![op],
+            Err(_) if name.starts_with(EXTERNAL_PREFIX) => vec![TextOperation::External],
+            Err(_) => {
+                eprintln!("error: {}", ExecError::unknown_op(name, None));
+                process::exit(1);
+            }
+        },
+        None => TextOperation::iter().collect(),
+    };
+
+    for op in ops {
+        print_op(op);
+    }
+
+    if args.op.is_none() {
+        println!(
+            "Text arguments are templates, where $top or ${{top}} is the topmost stack element,"
+        );
+        println!("and $pop takes it from the stack. Other $names are left as-is.");
+        println!("$reg:name is the value stored to the register by `store name`.");
+        println!("$env:NAME is an environment variable, and ${{cmd:COMMAND}} is the output of a");
+        println!("shell command, which requires --allow-shell.");
+        println!("Integers can be adjusted with arithmetic, e.g. ${{top+1}} or ${{pop-2}}.");
+        println!(
+            "Values can be piped through filters, e.g. ${{pop|trim|snake_case}}: upper, lower,"
+        );
+        println!("trim, snake_case, camel_case, pascal_case, kebab_case, screaming_snake_case");
+        println!("and regex_replace:REGEX:REPLACEMENT. Write \\| for a | in a filter argument.");
+        println!("A ${{cmd:...}} template has no filters, so its pipes go to the shell.");
+        println!();
+        println!("Regexes can set flags inline, e.g. (?i) for case-insensitive matching, or for");
+        println!("all of them with --regex-flags, e.g. --regex-flags i,m.");
+        #[cfg(feature = "fancy-regex")]
+        println!("Lookaround and backreferences need --regex-engine fancy.");
+        println!();
+        print_recipes();
+    }
+}
+
+fn print_recipes() {
+    println!("Recipes, used with `--recipe NAME` instead of operations:");
+    println!();
+    for recipe in RECIPES {
+        println!("{}", recipe.name.bold());
+        println!("    for: {}", recipe.selector);
+        println!("    {}", recipe.about);
+        if recipe.suggestions {
+            println!("    applies rustc's suggestion before the operations");
+        }
+        let ops: Vec<String> = recipe.ops.iter().map(|op| text::quote_word(op)).collect();
+        println!("    ops: {}", ops.join(" "));
+        println!();
+    }
+}
+
+fn print_op(op: TextOperation) {
+    let mut usage = op.name().bold().to_string();
+    for arg in op.arg_names() {
+        usage.push(' ');
+        usage.push_str(&arg.to_uppercase());
+    }
+    println!("{}", usage);
+
+    let aliases: Vec<_> = op
+        .get_serializations()
+        .iter()
+        .filter(|name| **name != op.name())
+        .copied()
+        .collect();
+    if !aliases.is_empty() {
+        println!("    aliases: {}", aliases.join(", "));
+    }
+    for line in op.get_documentation().unwrap_or_default().lines() {
+        println!("    {}", line);
+    }
+    if let Some(example) = op.get_str("example") {
+        println!("    example: {}", example);
+    }
+    println!();
+}
@@ -0,0 +1,313 @@
+//! Reports of the proposed changes, for sharing them with reviewers
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    fs, io,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use clap::ValueEnum;
+use similar::{ChangeTag, TextDiff};
+
+use cargo_refix::{message::CompilerMessage, operation::SpanChange};
+
+/// File format of a report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    /// Standalone page with highlighted diffs
+    Html,
+    /// Summary table by code with an example diff of each, for pull request descriptions
+    Markdown,
+}
+
+/// Where and how to write the report, given as `FORMAT[:PATH]`
+#[derive(Debug, Clone)]
+pub struct ReportTarget {
+    pub format: ReportFormat,
+    /// Printed to stdout without a path
+    pub path: Option<PathBuf>,
+}
+
+impl FromStr for ReportTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (format, path) = match s.split_once(':') {
+            Some((format, "")) => (format, None),
+            Some((format, path)) => (format, Some(PathBuf::from(path))),
+            None => (s, None),
+        };
+        Ok(Self {
+            format: ReportFormat::from_str(format, true)?,
+            path,
+        })
+    }
+}
+
+/// Proposed change of one span
+struct Entry {
+    line: usize,
+    message: String,
+    old: String,
+    new: String,
+}
+
+/// Changes of a code in the markdown report
+struct CodeSummary<'a> {
+    count: usize,
+    files: BTreeSet<&'a str>,
+    /// First change, with its file
+    example: (&'a str, &'a Entry),
+}
+
+/// Proposed changes by file and diagnostic code
+#[derive(Default)]
+pub struct Report {
+    files: BTreeMap<String, BTreeMap<String, Vec<Entry>>>,
+    count: usize,
+}
+
+impl Report {
+    pub fn add(&mut self, message: &CompilerMessage, changes: &[SpanChange]) {
+        let code = message.code().unwrap_or("<uncoded>");
+        for SpanChange { span, change, .. } in changes {
+            self.files
+                .entry(span.file_name.clone())
+                .or_default()
+                .entry(code.to_owned())
+                .or_default()
+                .push(Entry {
+                    line: span.line_start,
+                    message: message.message.clone(),
+                    old: span.raw_text(),
+                    new: String::from_utf8_lossy(&change.patch.bytes).into_owned(),
+                });
+            self.count += 1;
+        }
+    }
+
+    pub fn write(&self, target: &ReportTarget) -> io::Result<()> {
+        let text = match target.format {
+            ReportFormat::Html => self.html(),
+            ReportFormat::Markdown => self.markdown(),
+        };
+        match &target.path {
+            Some(path) => fs::write(path, text),
+            None => {
+                print!("{}", text);
+                Ok(())
+            }
+        }
+    }
+
+    fn markdown(&self) -> String {
+        let mut by_code: BTreeMap<&str, CodeSummary> = BTreeMap::new();
+        for (file, codes) in &self.files {
+            for (code, entries) in codes {
+                let summary = by_code.entry(code).or_insert_with(|| CodeSummary {
+                    count: 0,
+                    files: BTreeSet::new(),
+                    example: (file, &entries[0]),
+                });
+                summary.count += entries.len();
+                summary.files.insert(file);
+            }
+        }
+
+        let mut md = String::new();
+        let _ = writeln!(
+            md,
+            "{} changes in {} files\n\n| Code | Count | Files |\n| --- | ---: | --- |",
+            self.count,
+            self.files.len()
+        );
+        for (code, summary) in &by_code {
+            let files: Vec<String> = summary.files.iter().map(|file| md_code(file)).collect();
+            let _ = writeln!(
+                md,
+                "| {} | {} | {} |",
+                md_code(code),
+                summary.count,
+                files.join(", ")
+            );
+        }
+        for (code, CodeSummary { example, .. }) in &by_code {
+            let (file, entry) = example;
+            let diff = plain_diff(&entry.old, &entry.new);
+            let fence = "`".repeat(longest_backtick_run(&diff).max(2) + 1);
+            let _ = write!(
+                md,
+                "\n### {}\n\n{}: {}\n\n{}diff\n{}{}\n",
+                md_code(code),
+                md_code(&format!("{}:{}", file, entry.line)),
+                entry.message.replace('\n', " "),
+                fence,
+                diff,
+                fence
+            );
+        }
+        md
+    }
+
+    fn html(&self) -> String {
+        let mut html = String::new();
+        html.push_str(HTML_HEAD);
+        let _ = writeln!(
+            html,
+            "<h1>cargo refix</h1>\n<p>{} changes in {} files</p>",
+            self.count,
+            self.files.len()
+        );
+        for (file, codes) in &self.files {
+            let _ = writeln!(html, "<h2>{}</h2>", escape(file));
+            for (code, entries) in codes {
+                let _ = writeln!(
+                    html,
+                    "<h3>{} <span class=\"count\">{}</span></h3>",
+                    escape(code),
+                    entries.len()
+                );
+                for entry in entries {
+                    let _ = writeln!(
+                        html,
+                        "<p class=\"location\">{}:{}: {}</p>",
+                        escape(file),
+                        entry.line,
+                        escape(&entry.message)
+                    );
+                    let _ = writeln!(html, "<pre>{}</pre>", html_diff(&entry.old, &entry.new));
+                }
+            }
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+const HTML_HEAD: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>cargo refix</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+h2 { font-family: monospace; border-bottom: 1px solid #ccc; }
+.count { color: #888; font-weight: normal; }
+.location { font-family: monospace; margin-bottom: 0.2em; }
+pre { background: #f6f8fa; padding: 0.5em; }
+.del { display: block; background: #ffebe9; }
+.ins { display: block; background: #e6ffec; }
+.del em { background: #ffc1bd; font-style: normal; }
+.ins em { background: #abf2bc; font-style: normal; }
+</style>
+</head>
+<body>
+"#;
+
+/// Inline code span, safe to use in a table cell
+fn md_code(text: &str) -> String {
+    let text = text.replace('|', "\\|");
+    let fence = "`".repeat(longest_backtick_run(&text) + 1);
+    if text.contains('`') {
+        format!("{} {} {}", fence, text, fence)
+    } else {
+        format!("{}{}{}", fence, text, fence)
+    }
+}
+
+fn longest_backtick_run(text: &str) -> usize {
+    text.split(|c| c != '`').map(str::len).max().unwrap_or(0)
+}
+
+/// Changed lines in a unified diff without highlighting
+fn plain_diff(old: &str, new: &str) -> String {
+    let mut diff = String::new();
+    for change in TextDiff::from_lines(old, new).iter_all_changes() {
+        let sign = match change.tag() {
+            ChangeTag::Equal => ' ',
+            ChangeTag::Delete => '-',
+            ChangeTag::Insert => '+',
+        };
+        let _ = writeln!(
+            diff,
+            "{}{}",
+            sign,
+            change.value().trim_end_matches(['\r', '\n'])
+        );
+    }
+    diff
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Changed lines in a unified diff, with the changed words emphasized
+fn html_diff(old: &str, new: &str) -> String {
+    let diff = TextDiff::from_lines(old, new);
+    let mut html = String::new();
+    for op in diff.ops() {
+        for change in diff.iter_inline_changes(op) {
+            let (class, sign) = match change.tag() {
+                ChangeTag::Equal => (None, ' '),
+                ChangeTag::Delete => (Some("del"), '-'),
+                ChangeTag::Insert => (Some("ins"), '+'),
+            };
+            let mut line = String::new();
+            line.push(sign);
+            for (emphasized, value) in change.iter_strings_lossy() {
+                let value = escape(value.trim_end_matches(['\r', '\n']));
+                if emphasized && class.is_some() {
+                    let _ = write!(line, "<em>{}</em>", value);
+                } else {
+                    line.push_str(&value);
+                }
+            }
+            match class {
+                Some(class) => {
+                    let _ = write!(html, "<span class=\"{}\">{}</span>", class, line);
+                }
+                None => {
+                    html.push_str(&line);
+                    html.push('\n');
+                }
+            }
+        }
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{html_diff, md_code, ReportFormat, ReportTarget};
+
+    #[test]
+    fn test_html_diff() {
+        assert_eq!(
+            html_diff("let x = a<b;\nfoo\n", "let _x = a<b;\nfoo\n"),
+            "<span class=\"del\">-let <em>x</em> = a&lt;b;</span>\
+             <span class=\"ins\">+let <em>_x</em> = a&lt;b;</span> foo\n"
+        );
+
+        let target: ReportTarget = "HTML:out/report.html".parse().unwrap();
+        assert_eq!(target.format, ReportFormat::Html);
+        assert_eq!(target.path.unwrap().to_str(), Some("out/report.html"));
+        assert!("markdown".parse::<ReportTarget>().unwrap().path.is_none());
+        assert!("pdf:x.pdf".parse::<ReportTarget>().is_err());
+
+        assert_eq!(md_code("a|b"), "`a\\|b`");
+        assert_eq!(md_code("`x`"), "`` `x` ``");
+    }
+}
@@ -0,0 +1,113 @@
+//! Running the test cases of the rules in the config file
+
+use std::{path::PathBuf, process};
+
+use clap::Parser;
+use colored::Colorize;
+
+use crate::{
+    config::{self, Rule, RuleTest},
+    diff::{self, DiffStyle},
+    eval,
+};
+
+/// Check that the rules in the config file pass their test cases
+#[derive(Parser, Debug)]
+#[command(bin_name = "cargo refix test")]
+pub struct TestArgs {
+    /// Config file with the rules
+    #[arg(long, value_name = "PATH", default_value = config::DEFAULT_PATH)]
+    pub config: PathBuf,
+
+    /// Only test the rule with this name
+    #[arg(value_name = "RULE")]
+    pub rule: Option<String>,
+}
+
+pub fn test(args: &TestArgs) {
+    let config = config::load(&args.config).unwrap_or_else(|err| {
+        eprintln!("error: unable to load {}: {}", args.config.display(), err);
+        process::exit(2);
+    });
+
+    let rules: Vec<&Rule> = config
+        .rules
+        .iter()
+        .filter(|rule| args.rule.as_ref().is_none_or(|name| *name == rule.name))
+        .collect();
+    if let (Some(name), true) = (&args.rule, rules.is_empty()) {
+        eprintln!("error: no rule named {:?}", name);
+        process::exit(2);
+    }
+
+    let mut passed = 0;
+    let mut failed = 0;
+    for rule in rules {
+        for (i, case) in rule.tests.iter().enumerate() {
+            match run_case(rule, case) {
+                Ok(()) => {
+                    passed += 1;
+                    println!("{} #{} ... {}", rule.name, i, "ok".green());
+                }
+                Err(err) => {
+                    failed += 1;
+                    println!("{} #{} ... {}", rule.name, i, "FAILED".red());
+                    for line in err.lines() {
+                        println!("    {}", line);
+                    }
+                }
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", passed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+/// Run the rule on the input of the test case, describing how it failed
+fn run_case(rule: &Rule, case: &RuleTest) -> Result<(), String> {
+    let span = match &case.highlight {
+        Some(range) => eval::parse_range(range)?,
+        None => 0..case.input.len(),
+    };
+    eval::check_span(&case.input, &span)?;
+
+    let mut text = case.input.clone();
+    eval::run(&rule.ops, &mut text, span, false, Default::default())
+        .map_err(|err| err.to_string())?;
+    if text != case.expected {
+        return Err(format!(
+            "expected and actual output differ:\n{}",
+            diff::render(&case.expected, &text, DiffStyle::Unified)
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run_case;
+    use crate::config::{Rule, RuleTest};
+
+    #[test]
+    fn test_run_case() {
+        let rule = Rule {
+            name: "underscore".to_owned(),
+            ops: vec!["push".to_owned(), "replace".to_owned(), "_$pop".to_owned()],
+            tests: Vec::new(),
+        };
+        let case = |highlight: Option<&str>, expected: &str| RuleTest {
+            input: "let x = 1;".to_owned(),
+            highlight: highlight.map(str::to_owned),
+            expected: expected.to_owned(),
+        };
+        assert!(run_case(&rule, &case(Some("4..5"), "let _x = 1;")).is_ok());
+        assert!(run_case(&rule, &case(None, "_let x = 1;")).is_ok());
+        assert!(run_case(&rule, &case(Some("4..5"), "let x = 1;"))
+            .unwrap_err()
+            .starts_with("expected and actual output differ"));
+        assert!(run_case(&rule, &case(Some("4..20"), "")).is_err());
+    }
+}
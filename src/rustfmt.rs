@@ -0,0 +1,67 @@
+//! Formatting the changed files with rustfmt
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::PathBuf,
+    process::Command,
+};
+
+use clap::ValueEnum;
+
+/// What to format after writing the changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FmtScope {
+    /// Whole files that were changed
+    Files,
+    /// Only the changed lines. Requires a nightly rustfmt
+    Lines,
+}
+
+/// A file that was written, with the inclusive line ranges that changed
+pub type ChangedFile = (PathBuf, Vec<(usize, usize)>);
+
+/// Settings for formatting the changed files
+#[derive(Debug, Clone)]
+pub struct Rustfmt {
+    pub scope: FmtScope,
+    /// Edition of the crate each file belongs to, when known
+    pub editions: HashMap<PathBuf, String>,
+}
+
+impl Rustfmt {
+    pub fn format(&self, changed: &[ChangedFile]) -> io::Result<()> {
+        // Files from crates of different editions must be formatted separately
+        let mut by_edition: BTreeMap<Option<&str>, Vec<&ChangedFile>> = BTreeMap::new();
+        for file in changed {
+            let edition = self.editions.get(&file.0).map(|e| e.as_str());
+            by_edition.entry(edition).or_default().push(file);
+        }
+
+        for (edition, files) in by_edition {
+            let mut cmd = Command::new("rustfmt");
+            if let Some(edition) = edition {
+                cmd.args(["--edition", edition]);
+            }
+            if self.scope == FmtScope::Lines {
+                let ranges: Vec<_> = files
+                    .iter()
+                    .flat_map(|(file, lines)| {
+                        lines.iter().map(move |(start, end)| {
+                            serde_json::json!({ "file": file, "range": [start, end] })
+                        })
+                    })
+                    .collect();
+                cmd.args(["--unstable-features", "--file-lines"])
+                    .arg(serde_json::to_string(&ranges)?);
+            }
+            cmd.args(files.iter().map(|(file, _)| file));
+
+            let status = cmd.status()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("rustfmt failed with {}", status)));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+//! Rhai scripts of the `script` operation, for transformations the operations can't express
+
+use std::{fs, ops, path::Path};
+
+use rhai::{Array, Dynamic, Engine, Scope, AST};
+
+/// Limit on the operations a script can run, so a runaway loop fails instead of hanging
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine
+}
+
+/// Compile the script, read from the file if the argument is the path of one
+pub fn compile(script: &str) -> Result<AST, String> {
+    let path = Path::new(script);
+    let source = if path.is_file() {
+        fs::read_to_string(path).map_err(|err| err.to_string())?
+    } else {
+        script.to_owned()
+    };
+    engine().compile(source).map_err(|err| err.to_string())
+}
+
+/// Run the script on the selected `span` of the text.
+/// The script sees `selection`, `text`, `start`, `end` and `stack`, and can change the stack.
+/// Unless it evaluates to `()`, its value replaces the selection.
+pub fn run(
+    script: &str,
+    text: &str,
+    span: ops::Range<usize>,
+    stack: &mut Vec<String>,
+) -> Result<Option<String>, String> {
+    let ast = compile(script)?;
+    let mut scope = Scope::new();
+    scope.push("selection", text[span.clone()].to_owned());
+    scope.push_constant("text", text.to_owned());
+    scope.push_constant("start", span.start as i64);
+    scope.push_constant("end", span.end as i64);
+    let values: Array = stack.iter().cloned().map(Dynamic::from).collect();
+    scope.push("stack", values);
+
+    let value: Dynamic = engine()
+        .eval_ast_with_scope(&mut scope, &ast)
+        .map_err(|err| err.to_string())?;
+
+    let values = scope
+        .get_value::<Array>("stack")
+        .ok_or("stack is no longer an array")?;
+    *stack = values.iter().map(value_to_string).collect();
+    Ok((!value.is_unit()).then(|| value_to_string(&value)))
+}
+
+/// Strings as they are, and other values such as numbers formatted
+fn value_to_string(value: &Dynamic) -> String {
+    match value.clone().into_string() {
+        Ok(string) => string,
+        Err(_) => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run;
+
+    #[test]
+    fn test_run() {
+        let mut stack = vec!["2".to_owned()];
+        let script = "let n = parse_int(stack.pop()); selection.to_upper() + n * 3";
+        assert_eq!(
+            run(script, "let x = 1;", 4..5, &mut stack).unwrap(),
+            Some("X6".to_owned())
+        );
+        assert!(stack.is_empty());
+
+        let script = "stack.push(text.sub_string(start, end - start)); stack.push(7);";
+        assert_eq!(run(script, "let x = 1;", 4..5, &mut stack).unwrap(), None);
+        assert_eq!(stack, ["x", "7"]);
+
+        assert!(run("loop {}", "", 0..0, &mut stack).is_err());
+        assert!(run("selection +", "", 0..0, &mut stack).is_err());
+    }
+}
@@ -1,47 +1,208 @@
-use std::str::FromStr;
+use std::{fmt, str::FromStr};
 
 use regex::Regex;
 
 use crate::message;
 
-#[derive(Debug, Clone, PartialEq)]
+/// Top-level selector, optionally followed by sub-selectors narrowing it down,
+/// e.g. `uncoded:message=^mismatched`
+#[derive(Debug, Clone)]
 pub struct Selector {
     pub top: TopLevelSelector,
+    pub filters: Vec<SubSelector>,
 }
 
 impl Selector {
     pub fn matches(&self, target: &message::CompilerMessage) -> bool {
-        self.top.matches(target)
+        self.top.matches(target) && self.filters.iter().all(|filter| filter.matches(target))
+    }
+
+    /// Limit the selection to that of a selector of one code, e.g. for operations meant for it.
+    /// Selectors including the code, like `all`, are narrowed to it, and others are an error.
+    /// The sub-selectors of both apply.
+    pub fn restrict_to(&mut self, restriction: &Selector) -> Result<(), String> {
+        let code = restriction.top.to_string();
+        let includes = match &self.top {
+            // Listing doesn't change anything
+            TopLevelSelector::List => return Ok(()),
+            TopLevelSelector::All | TopLevelSelector::ReallyAll => true,
+            TopLevelSelector::Preset(_, codes) => codes.contains(&code.as_str()),
+            top => *top == restriction.top,
+        };
+        if !includes {
+            return Err(format!("{} doesn't select {}", self.top, code));
+        }
+        self.top = restriction.top.clone();
+        self.filters.extend(restriction.filters.iter().cloned());
+        Ok(())
+    }
+
+    /// Keep only the spans matching the span-level sub-selectors primary,
+    /// so that the operations are run on them only
+    pub fn narrow(&self, target: &mut message::CompilerMessage) {
+        for span in target.spans.iter_mut().filter(|span| span.is_primary) {
+            span.is_primary = self.filters.iter().all(|filter| filter.matches_span(span));
+        }
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.top.fmt(f)?;
+        for filter in &self.filters {
+            write!(f, ":{}", filter)?;
+        }
+        Ok(())
     }
 }
 
 impl FromStr for Selector {
     type Err = String;
 
+    /// Sub-selectors are separated by `:`, and start with `key=`. Other parts are kept together,
+    /// so that lints like `clippy::len_zero` and regexes with colons work.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut s = s.split(":");
-        let top = s.next().unwrap().parse().unwrap();
-        Ok(Self { top })
+        let starts_filter = |part: &str| {
+            part.split_once('=').is_some_and(|(key, _)| {
+                !key.is_empty() && key.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+            })
+        };
+        let mut top = Vec::new();
+        let mut filters: Vec<String> = Vec::new();
+        for part in s.split(':') {
+            if starts_filter(part) {
+                filters.push(part.to_owned());
+            } else if let Some(filter) = filters.last_mut() {
+                filter.push(':');
+                filter.push_str(part);
+            } else {
+                top.push(part);
+            }
+        }
+        Ok(Self {
+            top: top.join(":").parse()?,
+            filters: filters
+                .iter()
+                .map(|filter| filter.parse())
+                .collect::<Result<_, _>>()?,
+        })
     }
 }
 
+/// Condition narrowing down a top-level selector
+#[derive(Debug, Clone)]
+pub enum SubSelector {
+    /// `message=REGEX`: the message of the diagnostic matches the regex
+    Message(Regex),
+    /// `label=REGEX`: the label of a primary span matches the regex.
+    /// Only the matching spans are operated on.
+    Label(Regex),
+}
+
+impl SubSelector {
+    pub fn matches(&self, target: &message::CompilerMessage) -> bool {
+        match self {
+            SubSelector::Message(re) => re.is_match(&target.message),
+            SubSelector::Label(_) => target.primary_spans().any(|span| self.matches_span(span)),
+        }
+    }
+
+    /// Whether the span of a matching diagnostic is selected
+    fn matches_span(&self, span: &message::Span) -> bool {
+        match self {
+            SubSelector::Message(_) => true,
+            SubSelector::Label(re) => span.label.as_ref().is_some_and(|label| re.is_match(label)),
+        }
+    }
+}
+
+impl fmt::Display for SubSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubSelector::Message(re) => write!(f, "message={}", re),
+            SubSelector::Label(re) => write!(f, "label={}", re),
+        }
+    }
+}
+
+impl FromStr for SubSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or("expected a sub-selector like `key=value`")?;
+        let regex = || Regex::new(value).map_err(|err| format!("invalid regex in {}: {}", s, err));
+        match key {
+            "message" => Ok(SubSelector::Message(regex()?)),
+            "label" => Ok(SubSelector::Label(regex()?)),
+            _ => Err(format!(
+                "unknown sub-selector {:?}, expected message or label",
+                key
+            )),
+        }
+    }
+}
+
+/// Built-in selector presets for common cleanups, by name, selecting any of the codes.
+/// Presets of clippy lints need `--clippy`.
+pub const PRESETS: &[(&str, &[&str])] = &[
+    (
+        "unused",
+        &["unused_imports", "unused_variables", "dead_code"],
+    ),
+    (
+        "clippy-style",
+        &[
+            "clippy::needless_return",
+            "clippy::let_and_return",
+            "clippy::redundant_field_names",
+            "clippy::redundant_static_lifetimes",
+            "clippy::len_zero",
+            "clippy::collapsible_else_if",
+            "clippy::single_char_pattern",
+            "clippy::needless_borrow",
+            "clippy::redundant_closure",
+        ],
+    ),
+    (
+        "idioms",
+        &[
+            "elided_lifetimes_in_paths",
+            "unused_extern_crates",
+            "bare_trait_objects",
+            "ellipsis_inclusive_range_patterns",
+        ],
+    ),
+];
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum TopLevelSelector {
-    /// Meta selector for listing possible selectors in compact form
+    /// Meta selector for listing possible selectors in compact form,
+    /// including issues without a code
     List,
-    /// Select all issues
+    /// Select all issues that have a code
     All,
+    /// Select all issues pointing to the code, even if they don't have a code
+    ReallyAll,
+    /// Select issues without a code, such as many hard errors
+    Uncoded,
     /// Error with a numeric code, such as `E0001`
     Error(u64),
     /// Named lint, such as `dead_code` or `clippy::needless_pass_by_value`
     Lint(String),
+    /// Any code of a built-in preset, such as `preset:unused`
+    Preset(&'static str, &'static [&'static str]),
 }
 
 impl TopLevelSelector {
     pub fn matches(&self, target: &message::CompilerMessage) -> bool {
         match self {
-            TopLevelSelector::List => target.code().is_some(),
+            TopLevelSelector::List => !target.spans.is_empty(),
             TopLevelSelector::All => target.code().is_some(),
+            // Summaries like "aborting due to 2 previous errors" have no spans
+            TopLevelSelector::ReallyAll => !target.spans.is_empty(),
+            TopLevelSelector::Uncoded => target.code().is_none() && !target.spans.is_empty(),
             TopLevelSelector::Error(err) => {
                 let re = Regex::new(r"^E(\d+)$").unwrap();
                 target
@@ -58,6 +219,21 @@ impl TopLevelSelector {
             TopLevelSelector::Lint(lint_name) => {
                 target.code().map(|code| code == lint_name).unwrap_or(false)
             }
+            TopLevelSelector::Preset(_, codes) => target.code().is_some_and(|c| codes.contains(&c)),
+        }
+    }
+}
+
+impl fmt::Display for TopLevelSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopLevelSelector::List => write!(f, "list"),
+            TopLevelSelector::All => write!(f, "all"),
+            TopLevelSelector::ReallyAll => write!(f, "really-all"),
+            TopLevelSelector::Uncoded => write!(f, "uncoded"),
+            TopLevelSelector::Error(err) => write!(f, "E{:04}", err),
+            TopLevelSelector::Lint(lint_name) => write!(f, "{}", lint_name),
+            TopLevelSelector::Preset(name, _) => write!(f, "preset:{}", name),
         }
     }
 }
@@ -70,6 +246,23 @@ impl FromStr for TopLevelSelector {
             return Ok(Self::List);
         } else if s == "all" {
             return Ok(Self::All);
+        } else if s == "really-all" {
+            return Ok(Self::ReallyAll);
+        } else if s == "uncoded" {
+            return Ok(Self::Uncoded);
+        } else if let Some(name) = s.strip_prefix("preset:") {
+            return PRESETS
+                .iter()
+                .find(|(preset, _)| *preset == name)
+                .map(|(preset, codes)| Self::Preset(preset, codes))
+                .ok_or_else(|| {
+                    let names: Vec<&str> = PRESETS.iter().map(|(name, _)| *name).collect();
+                    format!(
+                        "unknown preset {:?}, expected one of {}",
+                        name,
+                        names.join(", ")
+                    )
+                });
         }
 
         let re = Regex::new(r"^E(\d+)$").unwrap();
@@ -80,3 +273,68 @@ impl FromStr for TopLevelSelector {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Selector, SubSelector, TopLevelSelector};
+
+    #[test]
+    fn test_parse() {
+        let selector: Selector = "clippy::len_zero".parse().unwrap();
+        assert_eq!(
+            selector.top,
+            TopLevelSelector::Lint("clippy::len_zero".to_owned())
+        );
+        assert!(selector.filters.is_empty());
+
+        let selector: Selector = "uncoded:message=^expected: (a|b)".parse().unwrap();
+        assert_eq!(selector.top, TopLevelSelector::Uncoded);
+        assert!(matches!(
+            &selector.filters[..],
+            [SubSelector::Message(re)] if re.as_str() == "^expected: (a|b)"
+        ));
+        assert_eq!(selector.to_string(), "uncoded:message=^expected: (a|b)");
+
+        let selector: Selector = "E0308:label=expected `&str`:message=mismatched"
+            .parse()
+            .unwrap();
+        assert!(matches!(
+            &selector.filters[..],
+            [SubSelector::Label(_), SubSelector::Message(_)]
+        ));
+
+        let selector: Selector = "preset:unused:message=`_".parse().unwrap();
+        assert!(
+            matches!(selector.top, TopLevelSelector::Preset("unused", codes) if codes.contains(&"dead_code"))
+        );
+        assert_eq!(selector.to_string(), "preset:unused:message=`_");
+        assert!("preset:nonexistent".parse::<Selector>().is_err());
+
+        assert!("all:nothing=x".parse::<Selector>().is_err());
+        assert!("all:message=(".parse::<Selector>().is_err());
+    }
+
+    #[test]
+    fn test_restrict_to() {
+        let restrict = |selector: &str, restriction: &str| {
+            let mut selector: Selector = selector.parse().unwrap();
+            selector
+                .restrict_to(&restriction.parse().unwrap())
+                .map(|()| selector.to_string())
+        };
+        assert_eq!(
+            restrict("all", "unused_variables").unwrap(),
+            "unused_variables"
+        );
+        assert_eq!(
+            restrict("preset:unused:message=x", "unused_variables").unwrap(),
+            "unused_variables:message=x"
+        );
+        assert_eq!(
+            restrict("E0277:label=y", "E0277:message=x").unwrap(),
+            "E0277:label=y:message=x"
+        );
+        assert!(restrict("unused_imports", "unused_variables").is_err());
+        assert!(restrict("E0277", "unused_variables").is_err());
+    }
+}
@@ -0,0 +1,284 @@
+//! Persistent defaults for the command line options, from config files and environment variables.
+//! Later sources override earlier ones: the user config file, the `[settings]` table of the
+//! project's `refix.toml`, `REFIX_*` environment variables, and finally the command line itself.
+//! The project is the directory given with `-C` or `--manifest-path`, so its settings are read
+//! once the command line has been parsed without them.
+//! Flags turned on by the settings can be turned off on the command line with `--no-<flag>`.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsString,
+    fmt, fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser};
+
+use crate::{args::Args, config};
+
+/// Prefix of the environment variables setting options, e.g. `REFIX_DIFF_STYLE`
+const ENV_PREFIX: &str = "REFIX_";
+
+/// Show the effective settings and where they come from
+#[derive(Parser, Debug)]
+#[command(bin_name = "cargo refix config")]
+pub struct ConfigArgs {}
+
+/// Where a setting was read from
+#[derive(Debug, Clone)]
+pub enum Source {
+    File(PathBuf),
+    Env(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Env(var) => write!(f, "{}", var),
+        }
+    }
+}
+
+/// Option values by the long name of the option
+#[derive(Debug, Default)]
+pub struct Settings(BTreeMap<String, (toml::Value, Source)>);
+
+/// Config file of the user, in the XDG config directory
+fn user_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("refix").join("config.toml"))
+}
+
+impl Settings {
+    /// Read the settings from the user config file and the environment, and with `project`
+    /// also from the config file in the current directory
+    pub fn load(project: bool) -> Result<Self, String> {
+        let mut settings = Self::default();
+        if let Some(path) = user_path().filter(|path| path.is_file()) {
+            let error =
+                |err: &dyn fmt::Display| format!("unable to load {}: {}", path.display(), err);
+            let text = fs::read_to_string(&path).map_err(|err| error(&err))?;
+            let table = toml::from_str(&text).map_err(|err| error(&err))?;
+            settings.read_table(table, &path, false)?;
+        }
+        let path = Path::new(config::DEFAULT_PATH);
+        if project && path.is_file() {
+            let config = config::load(path)
+                .map_err(|err| format!("unable to load {}: {}", path.display(), err))?;
+            settings.read_table(config.settings, path, true)?;
+        }
+        let vars = env::vars_os()
+            .filter_map(|(var, value)| Some((var.into_string().ok()?, value.into_string().ok()?)));
+        for (var, value) in vars {
+            if let Some(name) = var.strip_prefix(ENV_PREFIX) {
+                let name = name.to_lowercase().replace('_', "-");
+                // Other tools may use the prefix too, so unknown names are left alone
+                if option(&name).is_some() {
+                    let value = toml::Value::String(value);
+                    settings.set(name, value, Source::Env(var))?;
+                }
+            }
+        }
+        Ok(settings)
+    }
+
+    fn read_table(&mut self, table: toml::Table, path: &Path, project: bool) -> Result<(), String> {
+        let error = |err: &dyn fmt::Display| format!("unable to load {}: {}", path.display(), err);
+        for (key, value) in table {
+            let name = key.replace('_', "-");
+            // The project's settings are read from its directory, so they can't move to another
+            if project && matches!(name.as_str(), "directory" | "manifest-path") {
+                return Err(error(&format!("{} can't be set by the project", name)));
+            }
+            self.set(name, value, Source::File(path.to_owned()))
+                .map_err(|err| error(&err))?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, name: String, value: toml::Value, source: Source) -> Result<(), String> {
+        if option(&name).is_none() {
+            return Err(format!("unknown option {:?}", name));
+        }
+        // Check the value now, to report the source of mistakes
+        arguments(&name, &value).map_err(|err| format!("{}: {}", name, err))?;
+        self.0.insert(name, (value, source));
+        Ok(())
+    }
+
+    /// Command line arguments setting the options, to be given before the user's own
+    pub fn arguments(&self) -> Vec<OsString> {
+        self.0
+            .iter()
+            .flat_map(|(name, (value, _))| arguments(name, value).unwrap())
+            .map(OsString::from)
+            .collect()
+    }
+
+    pub fn print(&self) {
+        if self.0.is_empty() {
+            println!("no settings, using the defaults");
+        }
+        for (name, (value, source)) in &self.0 {
+            println!("{} = {}  # {}", name, value, source);
+        }
+    }
+
+    /// Parse the command line, with the settings before it as defaults
+    pub fn parse(&self, bin_path: &OsString, command_line: &[OsString]) -> Args {
+        let arguments = [bin_path.clone()]
+            .into_iter()
+            .chain(self.arguments())
+            .chain(command_line.iter().cloned());
+        let matches = command().get_matches_from(arguments);
+        Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit())
+    }
+}
+
+/// The main command, with a hidden `--no-<flag>` for each flag, turning off the flag
+/// if it was set before, e.g. by the settings
+fn command() -> clap::Command {
+    let command = Args::command();
+    let flags: Vec<(clap::Id, String)> = command
+        .get_arguments()
+        .filter(|arg| matches!(arg.get_action(), ArgAction::SetTrue))
+        .filter_map(|arg| Some((arg.get_id().clone(), format!("no-{}", arg.get_long()?))))
+        .filter(|(_, negation)| option(negation).is_none())
+        .collect();
+    flags.into_iter().fold(command, |command, (id, negation)| {
+        command.arg(
+            clap::Arg::new(negation.clone())
+                .long(negation)
+                .action(ArgAction::SetTrue)
+                .overrides_with(id)
+                .hide(true),
+        )
+    })
+}
+
+/// Named option of the main command, not including `--help` and `--version`
+fn option(name: &str) -> Option<clap::Arg> {
+    Args::command()
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(name) && !matches!(name, "help" | "version"))
+        .cloned()
+}
+
+/// Arguments setting the option to the value, which can also be a list for repeatable options
+fn arguments(name: &str, value: &toml::Value) -> Result<Vec<String>, String> {
+    let option = option(name).ok_or_else(|| format!("unknown option {:?}", name))?;
+    let flag = format!("--{}", name);
+    let values = match value {
+        toml::Value::Array(values) if matches!(option.get_action(), ArgAction::Append) => {
+            values.iter().collect()
+        }
+        value => vec![value],
+    };
+
+    let mut arguments = Vec::new();
+    for value in values {
+        match (option.get_action(), value) {
+            (ArgAction::SetTrue, value) => {
+                if parse_bool(value)? {
+                    arguments.push(flag.clone());
+                }
+            }
+            (ArgAction::Count, value) => {
+                let count = match value {
+                    toml::Value::Integer(count) => usize::try_from(*count).ok(),
+                    toml::Value::String(count) => count.parse().ok(),
+                    _ => None,
+                };
+                let count = count.ok_or("expected a count")?;
+                arguments.extend(std::iter::repeat_n(flag.clone(), count));
+            }
+            (_, toml::Value::String(value)) => arguments.push(format!("{}={}", flag, value)),
+            (_, toml::Value::Integer(value)) => arguments.push(format!("{}={}", flag, value)),
+            (_, toml::Value::Float(value)) => arguments.push(format!("{}={}", flag, value)),
+            (_, toml::Value::Boolean(value)) => arguments.push(format!("{}={}", flag, value)),
+            _ => return Err("expected a string or a number".to_owned()),
+        }
+    }
+    Ok(arguments)
+}
+
+fn parse_bool(value: &toml::Value) -> Result<bool, String> {
+    match value {
+        toml::Value::Boolean(value) => Ok(*value),
+        toml::Value::String(value) => match value.to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" | "" => Ok(false),
+            _ => Err(format!("expected true or false, not {:?}", value)),
+        },
+        _ => Err("expected true or false".to_owned()),
+    }
+}
+
+/// Settings, exiting with an error if they are invalid
+pub fn load_or_exit(project: bool) -> Settings {
+    Settings::load(project).unwrap_or_else(|err| {
+        eprintln!("error: {}", err);
+        process::exit(2);
+    })
+}
+
+pub fn show(_args: &ConfigArgs) {
+    load_or_exit(true).print();
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::FromArgMatches;
+
+    use super::{arguments, command};
+    use crate::args::Args;
+
+    #[test]
+    fn test_arguments() {
+        let value =
+            |text: &str| -> toml::Value { text.parse::<toml::Table>().unwrap()["x"].clone() };
+        assert_eq!(
+            arguments("clippy", &value("x = true")).unwrap(),
+            ["--clippy"]
+        );
+        assert!(arguments("clippy", &value("x = false")).unwrap().is_empty());
+        assert_eq!(
+            arguments("clippy", &value("x = 'yes'")).unwrap(),
+            ["--clippy"]
+        );
+        assert_eq!(
+            arguments("exclude", &value("x = ['a/**', 'b']")).unwrap(),
+            ["--exclude=a/**", "--exclude=b"]
+        );
+        assert_eq!(
+            arguments("diff-style", &value("x = 'unified'")).unwrap(),
+            ["--diff-style=unified"]
+        );
+        assert_eq!(
+            arguments("verbose", &value("x = 2")).unwrap(),
+            ["--verbose", "--verbose"]
+        );
+        assert!(arguments("nonexistent", &value("x = 1")).is_err());
+        assert!(arguments("clippy", &value("x = 'maybe'")).is_err());
+    }
+
+    #[test]
+    fn test_negation() {
+        let parse = |arguments: &[&str]| {
+            let matches =
+                command().get_matches_from(["cargo-refix", "all"].iter().chain(arguments));
+            Args::from_arg_matches(&matches).unwrap()
+        };
+        assert!(parse(&["--quiet"]).quiet);
+        assert!(!parse(&["--quiet", "--no-quiet"]).quiet);
+        assert!(parse(&["--no-quiet", "--quiet"]).quiet);
+        // Existing options are kept as they are
+        assert!(parse(&["--no-pager"]).no_pager);
+    }
+}
@@ -0,0 +1,462 @@
+//! Structural search and replace. Patterns are Rust tokens with `$name` placeholders,
+//! matched against tokens of the text, so whitespace and line breaks don't matter.
+//! A placeholder matches an expression-like run of balanced token trees, stopping
+//! at statement boundaries and binary operators.
+
+use std::ops::Range;
+
+use crate::text::template;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Ident,
+    Literal,
+    Punct,
+    Open,
+    Close,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: Kind,
+    range: Range<usize>,
+    space_before: bool,
+}
+
+/// Punctuation consisting of multiple characters, longest first.
+/// Shifts are left out, as `>>` usually closes nested generics.
+const PUNCTS: [&str; 20] = [
+    "..=", "...", "::", "->", "=>", "==", "!=", "<=", ">=", "&&", "||", "+=", "-=", "*=", "/=",
+    "%=", "^=", "&=", "|=", "..",
+];
+
+/// Keywords that can't be a part of an expression bound to a placeholder
+const STOP_KEYWORDS: [&str; 20] = [
+    "let", "return", "break", "continue", "if", "else", "match", "while", "for", "in", "loop",
+    "as", "fn", "struct", "enum", "impl", "trait", "use", "pub", "where",
+];
+
+/// Split Rust code into tokens, skipping whitespace and comments
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut space_before = false;
+    let mut i = 0;
+    while let Some(c) = text[i..].chars().next() {
+        let rest = &text[i..];
+        let start = i;
+        let kind = if c.is_whitespace() {
+            i += c.len_utf8();
+            space_before = true;
+            continue;
+        } else if rest.starts_with("//") {
+            i += rest.find('\n').unwrap_or(rest.len());
+            space_before = true;
+            continue;
+        } else if rest.starts_with("/*") {
+            i += block_comment_len(rest);
+            space_before = true;
+            continue;
+        } else if let Some(len) = string_len(rest) {
+            i += len;
+            Kind::Literal
+        } else if c == '\'' {
+            let after = &rest[1..];
+            match after.chars().next() {
+                Some('\\') => {
+                    i += 1 + after.find('\'').map_or(after.len(), |end| end + 1);
+                    Kind::Literal
+                }
+                Some(ch) if after[ch.len_utf8()..].starts_with('\'') => {
+                    i += 2 + ch.len_utf8();
+                    Kind::Literal
+                }
+                // Lifetime or label
+                _ => {
+                    i += 1 + ident_len(after);
+                    Kind::Ident
+                }
+            }
+        } else if c.is_ascii_digit() {
+            let bytes = rest.as_bytes();
+            let mut len = 0;
+            while len < bytes.len()
+                && (bytes[len].is_ascii_alphanumeric()
+                    || bytes[len] == b'_'
+                    || (bytes[len] == b'.' && bytes.get(len + 1).is_some_and(u8::is_ascii_digit)))
+            {
+                len += 1;
+            }
+            i += len;
+            Kind::Literal
+        } else if c.is_alphanumeric() || c == '_' {
+            i += ident_len(rest);
+            Kind::Ident
+        } else if "([{".contains(c) {
+            i += 1;
+            Kind::Open
+        } else if ")]}".contains(c) {
+            i += 1;
+            Kind::Close
+        } else {
+            i += PUNCTS
+                .iter()
+                .find(|punct| rest.starts_with(**punct))
+                .map_or(c.len_utf8(), |punct| punct.len());
+            Kind::Punct
+        };
+        tokens.push(Token {
+            kind,
+            range: start..i,
+            space_before,
+        });
+        space_before = false;
+    }
+    tokens
+}
+
+fn ident_len(text: &str) -> usize {
+    text.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(text.len())
+}
+
+/// Length of a possibly nested block comment at the start of the text
+fn block_comment_len(text: &str) -> usize {
+    let mut depth = 0;
+    let mut i = 0;
+    while i < text.len() {
+        if text[i..].starts_with("/*") {
+            depth += 1;
+            i += 2;
+        } else if text[i..].starts_with("*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return i;
+            }
+        } else {
+            i += text[i..].chars().next().unwrap().len_utf8();
+        }
+    }
+    text.len()
+}
+
+/// Length of a string literal at the start of the text, including byte, C and raw strings
+fn string_len(text: &str) -> Option<usize> {
+    let (prefix, rest) = ["br", "cr", "b", "c", "r", ""]
+        .into_iter()
+        .filter_map(|prefix| Some((prefix, text.strip_prefix(prefix)?)))
+        .find(|(prefix, rest)| {
+            rest.starts_with('"') || (prefix.ends_with('r') && rest.starts_with('#'))
+        })?;
+    if prefix.ends_with('r') {
+        let hashes = rest.len() - rest.trim_start_matches('#').len();
+        if !rest[hashes..].starts_with('"') {
+            return None;
+        }
+        let terminator = format!("\"{}", "#".repeat(hashes));
+        let body = &rest[hashes + 1..];
+        let end = body
+            .find(&terminator)
+            .map_or(body.len(), |end| end + terminator.len());
+        return Some(prefix.len() + hashes + 1 + end);
+    }
+
+    let mut chars = rest.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => return Some(prefix.len() + i + 1),
+            _ => {}
+        }
+    }
+    Some(text.len())
+}
+
+#[derive(Debug, Clone)]
+enum Element {
+    Token(String),
+    Placeholder(String),
+}
+
+/// A parsed search pattern
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    elements: Vec<Element>,
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let tokens = tokenize(pattern);
+        let mut elements = Vec::new();
+        let mut depth: Vec<char> = Vec::new();
+        let mut tokens = tokens.iter().peekable();
+        while let Some(token) = tokens.next() {
+            let text = &pattern[token.range.clone()];
+            if text == "$" {
+                match tokens.peek() {
+                    Some(next) if next.kind == Kind::Ident && !next.space_before => {
+                        elements.push(Element::Placeholder(pattern[next.range.clone()].to_owned()));
+                        tokens.next();
+                        continue;
+                    }
+                    _ => return Err("`$` must be followed by a placeholder name".to_owned()),
+                }
+            }
+            match token.kind {
+                Kind::Open => depth.push(text.chars().next().unwrap()),
+                Kind::Close => {
+                    let open = depth.pop().ok_or("unbalanced delimiters")?;
+                    if !matches!((open, text), ('(', ")") | ('[', "]") | ('{', "}")) {
+                        return Err("unbalanced delimiters".to_owned());
+                    }
+                }
+                _ => {}
+            }
+            elements.push(Element::Token(text.to_owned()));
+        }
+        if !depth.is_empty() {
+            return Err("unbalanced delimiters".to_owned());
+        }
+        if elements.is_empty() {
+            return Err("empty pattern".to_owned());
+        }
+        Ok(Self { elements })
+    }
+}
+
+struct Matcher<'a> {
+    text: &'a str,
+    tokens: &'a [Token],
+    elements: &'a [Element],
+}
+
+impl<'a> Matcher<'a> {
+    fn token_text(&self, index: usize) -> &'a str {
+        &self.text[self.tokens[index].range.clone()]
+    }
+
+    /// Whether the token ends an expression bound to a placeholder.
+    /// Prefix operators are only allowed at its start, with `prefix`.
+    fn is_stop(&self, index: usize, prefix: bool) -> bool {
+        let token = &self.tokens[index];
+        let text = self.token_text(index);
+        match token.kind {
+            Kind::Close => true,
+            Kind::Ident => STOP_KEYWORDS.contains(&text),
+            Kind::Punct => match text {
+                "-" | "*" | "&" => !prefix,
+                "<" => token.space_before || index == 0,
+                "." | "::" | "?" | "!" => false,
+                _ => true,
+            },
+            Kind::Literal | Kind::Open => false,
+        }
+    }
+
+    /// End of the token tree starting at the index, if a placeholder can include it
+    fn tree_end(&self, index: usize, prefix: bool) -> Option<usize> {
+        if index >= self.tokens.len() || self.is_stop(index, prefix) {
+            return None;
+        }
+        let text = self.token_text(index);
+        let (open, close) = match (self.tokens[index].kind, text) {
+            (Kind::Open, _) => (Kind::Open, Kind::Close),
+            // Generic arguments, which are written without a space before
+            (Kind::Punct, "<") => (Kind::Punct, Kind::Punct),
+            _ => return Some(index + 1),
+        };
+
+        let mut depth = 0;
+        for i in index..self.tokens.len() {
+            let token = &self.tokens[i];
+            let text = self.token_text(i);
+            if open == Kind::Punct {
+                match text {
+                    "<" => depth += 1,
+                    ">" => depth -= 1,
+                    ";" | "{" | "}" => return None,
+                    _ => {}
+                }
+            } else if token.kind == open {
+                depth += 1;
+            } else if token.kind == close {
+                depth -= 1;
+            }
+            if depth == 0 {
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+
+    fn bound_text(&self, tokens: &Range<usize>) -> &'a str {
+        &self.text[self.tokens[tokens.start].range.start..self.tokens[tokens.end - 1].range.end]
+    }
+
+    /// Match the pattern elements from `element` on at the token index,
+    /// returning the index after the last matched token
+    fn match_at(
+        &self,
+        element: usize,
+        index: usize,
+        bindings: &mut Vec<(&'a str, Range<usize>)>,
+    ) -> Option<usize> {
+        let Some(current) = self.elements.get(element) else {
+            return Some(index);
+        };
+        match current {
+            Element::Token(text) => {
+                if index < self.tokens.len() && self.token_text(index) == text {
+                    self.match_at(element + 1, index + 1, bindings)
+                } else {
+                    None
+                }
+            }
+            Element::Placeholder(name) => {
+                let previous = bindings
+                    .iter()
+                    .find(|(bound, _)| bound == name)
+                    .map(|(_, tokens)| tokens.clone());
+                // Postfix operations in the pattern bind tighter than prefix operators,
+                // so `-$a.len()` can't bind `-n` to `$a`
+                let postfix = matches!(
+                    self.elements.get(element + 1),
+                    Some(Element::Token(next)) if [".", "?", "(", "[", "::"].contains(&next.as_str())
+                );
+                let mut end = index;
+                // Shortest match first
+                while let Some(tree_end) = self.tree_end(end, end == index && !postfix) {
+                    end = tree_end;
+                    if let Some(previous) = &previous {
+                        let same = previous.len() == end - index
+                            && previous
+                                .clone()
+                                .zip(index..end)
+                                .all(|(a, b)| self.token_text(a) == self.token_text(b));
+                        if !same {
+                            continue;
+                        }
+                    }
+                    bindings.push((name, index..end));
+                    if let Some(matched) = self.match_at(element + 1, end, bindings) {
+                        return Some(matched);
+                    }
+                    bindings.pop();
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Replace all non-overlapping matches of the pattern in the text with the template,
+/// where `$name` is replaced by the text bound to the placeholder
+pub fn replace_all(text: &str, pattern: &Pattern, replacement: &str) -> String {
+    let tokens = tokenize(text);
+    let matcher = Matcher {
+        text,
+        tokens: &tokens,
+        elements: &pattern.elements,
+    };
+
+    let mut result = String::new();
+    let mut copied = 0;
+    let mut index = 0;
+    while index < tokens.len() {
+        let mut bindings = Vec::new();
+        match matcher.match_at(0, index, &mut bindings) {
+            Some(end) if end > index => {
+                let resolved = template(replacement, |name| {
+                    Ok(bindings
+                        .iter()
+                        .find(|(bound, _)| *bound == name)
+                        .map(|(_, tokens)| matcher.bound_text(tokens).to_owned()))
+                })
+                .expect("resolver doesn't fail");
+                result.push_str(&text[copied..tokens[index].range.start]);
+                result.push_str(&resolved);
+                copied = tokens[end - 1].range.end;
+                index = end;
+            }
+            _ => index += 1,
+        }
+    }
+    result.push_str(&text[copied..]);
+    result
+}
+
+/// Byte ranges of the identifier in the text, not counting strings or comments
+pub fn identifier_ranges(text: &str, name: &str) -> Vec<Range<usize>> {
+    tokenize(text)
+        .into_iter()
+        .filter(|token| token.kind == Kind::Ident && &text[token.range.clone()] == name)
+        .map(|token| token.range)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{identifier_ranges, replace_all, Pattern};
+
+    fn ssr(text: &str, pattern: &str, replacement: &str) -> String {
+        replace_all(text, &Pattern::parse(pattern).unwrap(), replacement)
+    }
+
+    #[test]
+    fn test_ssr() {
+        assert_eq!(
+            ssr("let x = self.map.get(k).unwrap();", "$a.unwrap()", "$a?"),
+            "let x = self.map.get(k)?;"
+        );
+        assert_eq!(
+            ssr("a + b.unwrap() * f(c.unwrap())", "$a.unwrap()", "$a?"),
+            "a + b? * f(c?)"
+        );
+        assert_eq!(
+            ssr(
+                "let m: HashMap<String, Vec<u8>> = HashMap :: new ( );",
+                "HashMap::new()",
+                "HashMap::default()"
+            ),
+            "let m: HashMap<String, Vec<u8>> = HashMap::default();"
+        );
+        assert_eq!(
+            ssr(
+                "foo(\n    Vec::<u8>::new(),\n    \"a, b\",\n)",
+                "foo($x, $y,)",
+                "bar($y, $x)"
+            ),
+            "bar(\"a, b\", Vec::<u8>::new())"
+        );
+        assert_eq!(
+            ssr("x == x && x == y", "$a == $a", "true"),
+            "true && x == y"
+        );
+        assert_eq!(
+            ssr("return -n.len();", "$a.len()", "len($a)"),
+            "return -len(n);"
+        );
+        // Strings and comments are not searched
+        assert_eq!(
+            ssr("\"a.unwrap()\" // b.unwrap()", "$a.unwrap()", "$a?"),
+            "\"a.unwrap()\" // b.unwrap()"
+        );
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(Pattern::parse("foo($a").is_err());
+        assert!(Pattern::parse("$ a").is_err());
+        assert!(Pattern::parse("  ").is_err());
+    }
+
+    #[test]
+    fn test_identifier_ranges() {
+        assert_eq!(
+            identifier_ranges("x + x_1 + \"x\" /* x */ + f(x)", "x"),
+            [0..1, 26..27]
+        );
+    }
+}
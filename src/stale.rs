@@ -0,0 +1,72 @@
+//! Detecting files edited after their diagnostics were read, e.g. during a long clippy run,
+//! whose changes would no longer apply to the right places
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+use cargo_refix::message::CompilerMessage;
+
+/// Hashes of the files as they were when their diagnostics were read
+#[derive(Default)]
+pub struct Snapshots(HashMap<PathBuf, Option<u64>>);
+
+/// Hash of the contents of the file, if it can be read
+fn hash_file(path: &Path) -> Option<u64> {
+    let contents = fs::read(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+impl Snapshots {
+    /// Remember the files of the message's spans, unless seen already
+    pub fn record(&mut self, message: &CompilerMessage) {
+        for span in &message.spans {
+            self.record_file(Path::new(&span.file_name));
+        }
+        for child in &message.children {
+            self.record(child);
+        }
+    }
+
+    pub fn record_file(&mut self, path: &Path) {
+        if !self.0.contains_key(path) {
+            self.0.insert(path.to_owned(), hash_file(path));
+        }
+    }
+
+    /// Whether the file has changed since it was recorded
+    pub fn is_stale(&self, path: &Path) -> bool {
+        self.0
+            .get(path)
+            .is_some_and(|hash| *hash != hash_file(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::Snapshots;
+
+    #[test]
+    fn test_is_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.rs");
+        fs::write(&path, "fn main() {}").unwrap();
+
+        let mut snapshots = Snapshots::default();
+        snapshots.record_file(&path);
+        assert!(!snapshots.is_stale(&path));
+        fs::write(&path, "fn main() { }").unwrap();
+        assert!(snapshots.is_stale(&path));
+        // Later records keep the first snapshot
+        snapshots.record_file(&path);
+        assert!(snapshots.is_stale(&path));
+        assert!(!snapshots.is_stale(&dir.path().join("b.rs")));
+    }
+}
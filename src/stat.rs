@@ -0,0 +1,116 @@
+//! Git-style summary of the changes by file, for checking large runs at a glance
+
+use std::{fs, path::PathBuf};
+
+use colored::Colorize;
+use similar::{ChangeTag, TextDiff};
+
+use cargo_refix::apply::FileChangeSet;
+
+/// Widest the bar of `+` and `-` gets, longer ones are scaled down
+const BAR_WIDTH: usize = 50;
+
+/// Changed lines of a file
+#[derive(Debug, PartialEq, Eq)]
+struct FileStat {
+    file: PathBuf,
+    insertions: usize,
+    deletions: usize,
+    hunks: usize,
+}
+
+impl FileStat {
+    /// Counts of the lines that differ between the contents, with hunks grouped like `git diff`
+    fn new(file: PathBuf, old: &str, new: &str) -> Self {
+        let diff = TextDiff::from_lines(old, new);
+        let mut stat = Self {
+            file,
+            insertions: 0,
+            deletions: 0,
+            hunks: diff.grouped_ops(3).len(),
+        };
+        for change in diff.iter_all_changes() {
+            match change.tag() {
+                ChangeTag::Insert => stat.insertions += 1,
+                ChangeTag::Delete => stat.deletions += 1,
+                ChangeTag::Equal => {}
+            }
+        }
+        stat
+    }
+
+    fn total(&self) -> usize {
+        self.insertions + self.deletions
+    }
+}
+
+/// Print how many lines the changes insert and delete in each file, and in total
+pub fn print(fcs: &[FileChangeSet]) {
+    let mut stats: Vec<FileStat> = fcs
+        .iter()
+        .filter_map(|fc| {
+            let original = fs::read(fc.file()).ok()?;
+            let new = fc.apply(&original);
+            Some(FileStat::new(
+                fc.file().to_owned(),
+                &String::from_utf8_lossy(&original),
+                &String::from_utf8_lossy(&new),
+            ))
+        })
+        .collect();
+    stats.sort_by(|a, b| a.file.cmp(&b.file));
+
+    let names: Vec<String> = stats.iter().map(|s| s.file.display().to_string()).collect();
+    let name_width = names
+        .iter()
+        .map(|name| name.chars().count())
+        .max()
+        .unwrap_or(0);
+    let max_total = stats.iter().map(FileStat::total).max().unwrap_or(0);
+    let count_width = max_total.to_string().len();
+    let scale = |n: usize| match max_total > BAR_WIDTH {
+        // Like git, keep at least one character for any change
+        true if n > 0 => (n * BAR_WIDTH / max_total).max(1),
+        true => 0,
+        false => n,
+    };
+    for (stat, name) in stats.iter().zip(&names) {
+        println!(
+            " {:name_width$} | {:>count_width$} {}{} ({} {})",
+            name,
+            stat.total(),
+            "+".repeat(scale(stat.insertions)).green(),
+            "-".repeat(scale(stat.deletions)).red(),
+            stat.hunks,
+            if stat.hunks == 1 { "hunk" } else { "hunks" },
+        );
+    }
+    println!(
+        " {} files changed, {} insertions(+), {} deletions(-)",
+        stats.len(),
+        stats.iter().map(|s| s.insertions).sum::<usize>(),
+        stats.iter().map(|s| s.deletions).sum::<usize>()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::FileStat;
+
+    #[test]
+    fn test_file_stat() {
+        let old = "use a;\nfn f() {\n    let x = 1;\n}\n\n\n\n\n\n\nfn g() {}\n";
+        let new = "fn f() {\n    let _x = 1;\n}\n\n\n\n\n\n\nfn g() {}\nfn h() {}\n";
+        assert_eq!(
+            FileStat::new(PathBuf::from("a.rs"), old, new),
+            FileStat {
+                file: PathBuf::from("a.rs"),
+                insertions: 2,
+                deletions: 2,
+                hunks: 2,
+            }
+        );
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+
+use crate::operation::Failure;
+
+/// Statistics collected during a run, reported at the end
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Number of processed diagnostics per code
+    pub per_code: BTreeMap<String, usize>,
+    /// Number of spans with a computed change
+    pub changes: usize,
+    /// Items that were skipped or failed
+    pub failures: Vec<Failure>,
+}
+
+impl Summary {
+    pub fn add_diagnostic(&mut self, code: Option<&str>) {
+        *self
+            .per_code
+            .entry(code.unwrap_or("<none>").to_owned())
+            .or_default() += 1;
+    }
+
+    pub fn print(&self, files: usize) {
+        let matched: usize = self.per_code.values().sum();
+        println!("summary:");
+        println!(" diagnostics matched: {}", matched);
+        let mut per_code: Vec<_> = self.per_code.iter().collect();
+        per_code.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+        for (code, count) in per_code {
+            println!("  {}: {}", code, count);
+        }
+
+        println!(" spans changed: {}", self.changes);
+
+        println!(" spans skipped: {}", self.failures.len());
+        let mut reasons: BTreeMap<String, Vec<_>> = BTreeMap::new();
+        for failure in &self.failures {
+            reasons
+                .entry(failure.error.to_string())
+                .or_default()
+                .push(failure);
+        }
+        for (reason, failures) in reasons {
+            println!("  {}: {}", reason, failures.len());
+            for failure in failures {
+                if let (Some(file), Some(line)) = (&failure.file, failure.line) {
+                    println!("   {}:{}", file, line);
+                }
+            }
+        }
+
+        println!(" files touched: {}", files);
+    }
+}
@@ -0,0 +1,189 @@
+//! Finding syntactic structures around a span, by parsing the whole file with syn
+
+use std::ops;
+
+use proc_macro2::LineColumn;
+use syn::{spanned::Spanned, visit::Visit};
+
+/// Converts line-column positions of syn into byte offsets
+struct Offsets<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> Offsets<'a> {
+    fn new(source: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            source,
+            line_starts,
+        }
+    }
+
+    fn offset(&self, position: LineColumn) -> usize {
+        let start = self.line_starts[position.line - 1];
+        self.source[start..]
+            .char_indices()
+            .nth(position.column)
+            .map_or(self.source.len(), |(i, _)| start + i)
+    }
+
+    fn range(&self, span: proc_macro2::Span) -> ops::Range<usize> {
+        self.offset(span.start())..self.offset(span.end())
+    }
+}
+
+/// Innermost node enclosing a byte range
+struct Innermost<'a> {
+    offsets: Offsets<'a>,
+    target: ops::Range<usize>,
+    found: Option<ops::Range<usize>>,
+}
+
+impl Innermost<'_> {
+    /// Record the node if it encloses the target. Nodes are visited from the outside in,
+    /// so the last one recorded is the innermost.
+    fn consider(&mut self, span: proc_macro2::Span) {
+        let range = self.offsets.range(span);
+        if range.start <= self.target.start && self.target.end <= range.end {
+            self.found = Some(range);
+        }
+    }
+}
+
+/// Kind of syntax node to look for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum Node {
+    /// Item such as a fn, struct or impl block, including associated items in impl blocks
+    /// and traits. Attributes and doc comments are included.
+    Item,
+    /// Expression
+    Expr,
+    /// Statement, including its semicolon
+    Stmt,
+    /// Block delimited by braces
+    Block,
+}
+
+struct Visitor<'a> {
+    node: Node,
+    innermost: Innermost<'a>,
+}
+
+impl Visitor<'_> {
+    fn consider(&mut self, node: Node, span: proc_macro2::Span) {
+        if node == self.node {
+            self.innermost.consider(span);
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for Visitor<'_> {
+    fn visit_item(&mut self, node: &'ast syn::Item) {
+        self.consider(Node::Item, node.span());
+        syn::visit::visit_item(self, node);
+    }
+
+    fn visit_impl_item(&mut self, node: &'ast syn::ImplItem) {
+        self.consider(Node::Item, node.span());
+        syn::visit::visit_impl_item(self, node);
+    }
+
+    fn visit_trait_item(&mut self, node: &'ast syn::TraitItem) {
+        self.consider(Node::Item, node.span());
+        syn::visit::visit_trait_item(self, node);
+    }
+
+    fn visit_foreign_item(&mut self, node: &'ast syn::ForeignItem) {
+        self.consider(Node::Item, node.span());
+        syn::visit::visit_foreign_item(self, node);
+    }
+
+    fn visit_expr(&mut self, node: &'ast syn::Expr) {
+        self.consider(Node::Expr, node.span());
+        syn::visit::visit_expr(self, node);
+    }
+
+    fn visit_stmt(&mut self, node: &'ast syn::Stmt) {
+        self.consider(Node::Stmt, node.span());
+        syn::visit::visit_stmt(self, node);
+    }
+
+    fn visit_block(&mut self, node: &'ast syn::Block) {
+        self.consider(Node::Block, node.span());
+        syn::visit::visit_block(self, node);
+    }
+}
+
+/// Byte range of the innermost node of the given kind enclosing the range
+pub fn enclosing(
+    source: &str,
+    range: ops::Range<usize>,
+    node: Node,
+) -> Result<Option<ops::Range<usize>>, syn::Error> {
+    // syn skips a byte order mark, so positions are relative to the text after it
+    let bom = if source.starts_with('\u{feff}') { 3 } else { 0 };
+    let source = &source[bom..];
+    let file = syn::parse_file(source)?;
+    let mut visitor = Visitor {
+        node,
+        innermost: Innermost {
+            offsets: Offsets::new(source),
+            target: range.start.saturating_sub(bom)..range.end.saturating_sub(bom),
+            found: None,
+        },
+    };
+    visitor.visit_file(&file);
+    Ok(visitor
+        .innermost
+        .found
+        .map(|found| found.start + bom..found.end + bom))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{enclosing, Node};
+
+    #[test]
+    fn test_enclosing() {
+        let source = "use std::fmt;\n\n/// Doc\n#[derive(Debug)]\nstruct S {\n    x: u8,\n}\n\nimpl S {\n    fn f(&self) -> u8 {\n        self.x\n    }\n}\n";
+        let find = |needle: &str, node| {
+            let start = source.find(needle).unwrap();
+            enclosing(source, start..start + needle.len(), node)
+                .unwrap()
+                .map(|range| &source[range])
+        };
+        let item = |needle| find(needle, Node::Item);
+        assert_eq!(item("fmt"), Some("use std::fmt;"));
+        assert_eq!(
+            item("x: u8"),
+            Some("/// Doc\n#[derive(Debug)]\nstruct S {\n    x: u8,\n}")
+        );
+        assert_eq!(
+            item("self.x"),
+            Some("fn f(&self) -> u8 {\n        self.x\n    }")
+        );
+        assert_eq!(item("\n\n"), None);
+
+        let source = "fn f() {\n    let v = Box::new(g(1, 2));\n    if v { h() }\n}";
+        let find = |needle: &str, node| {
+            let start = source.find(needle).unwrap();
+            enclosing(source, start..start + needle.len(), node)
+                .unwrap()
+                .map(|range| &source[range])
+        };
+        assert_eq!(find("1", Node::Expr), Some("1"));
+        assert_eq!(find("1, 2", Node::Expr), Some("g(1, 2)"));
+        assert_eq!(find("g(", Node::Stmt), Some("let v = Box::new(g(1, 2));"));
+        assert_eq!(find("h()", Node::Block), Some("{ h() }"));
+        assert_eq!(find("let", Node::Expr), None);
+
+        let source = "\u{feff}fn f() { g(1) }";
+        let start = source.find("1").unwrap();
+        let range = enclosing(source, start..start + 1, Node::Expr).unwrap();
+        assert_eq!(range.map(|range| &source[range]), Some("1"));
+    }
+}
@@ -1,6 +1,7 @@
 use std::ops;
 
 use colored::Colorize;
+use heck::{ToKebabCase, ToLowerCamelCase, ToShoutySnakeCase, ToSnakeCase, ToUpperCamelCase};
 use regex::Regex;
 
 use crate::operation::ExecError;
@@ -9,7 +10,7 @@ pub fn underline_span(text: &str, span: ops::Range<usize>) -> String {
     format!(
         "{}{}{}",
         &text[..span.start],
-        &text[span.start..span.end].underline(),
+        text[span.start..span.end].underline(),
         &text[span.end..],
     )
 }
@@ -44,51 +45,415 @@ pub fn find_matching_paren(context: &str, index: usize) -> Option<usize> {
             if let Some((other, opens)) = other_paren(c) {
                 if opens {
                     stack.push(other);
-                } else if let Some(top) = stack.pop() {
+                } else {
+                    let top = stack.pop()?; // None on mismatched paren count
                     if top != c {
                         return None; // Mismatched paren type
                     }
                     if stack.is_empty() {
                         return Some(index + 1 + i);
                     }
-                } else {
-                    return None; // Mismatched paren count
                 }
             }
         }
-        return None; // No closing paren in scope
+        None // No closing paren in scope
     } else {
         for (i, c) in context[..index].char_indices().rev() {
             if let Some((other, opens)) = other_paren(c) {
                 if !opens {
                     stack.push(other);
-                } else if let Some(top) = stack.pop() {
+                } else {
+                    let top = stack.pop()?; // None on mismatched paren count
                     if top != c {
                         return None; // Mismatched paren type
                     }
                     if stack.is_empty() {
                         return Some(i);
                     }
-                } else {
-                    return None; // Mismatched paren count
                 }
             }
         }
-        return None; // No opening paren in scope
+        None // No opening paren in scope
+    }
+}
+
+/// Number of single-character insertions, deletions and substitutions to turn `a` into `b`
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Split a line into words like a shell would, handling quotes and backslash escapes.
+/// Returns `None` if a quote is left open.
+pub fn split_words(line: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\\' => word.get_or_insert_default().push(chars.next()?),
+            '\'' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => word.push(chars.next()?),
+                        c => word.push(c),
+                    }
+                }
+            }
+            c => word.get_or_insert_default().push(c),
+        }
+    }
+    words.extend(word);
+    Some(words)
+}
+
+/// Quote a word so that [`split_words`] reads it back as-is
+pub fn quote_word(word: &str) -> String {
+    let plain = |c: char| c.is_alphanumeric() || "-_.,:/@%+=".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        word.to_owned()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// Move the position by the amount of characters, backwards if negative.
+/// Stops at the ends of the text.
+pub fn move_chars(text: &str, position: usize, amount: isize) -> usize {
+    if amount < 0 {
+        text[..position]
+            .char_indices()
+            .rev()
+            .nth(amount.unsigned_abs() - 1)
+            .map_or(0, |(i, _)| i)
+    } else {
+        text[position..]
+            .char_indices()
+            .nth(amount as usize)
+            .map_or(text.len(), |(i, _)| position + i)
     }
 }
 
-/// Replaces templates in form `$name` or `${name}`, using a resolver function.
+/// Single changed region between two versions of a text, for moving positions along with it
+pub struct Edit {
+    start: usize,
+    old_end: usize,
+    new_end: usize,
+}
+
+impl Edit {
+    /// Find the changed region by trimming the common prefix and suffix
+    pub fn between(old: &str, new: &str) -> Self {
+        let mut start = old
+            .bytes()
+            .zip(new.bytes())
+            .take_while(|(a, b)| a == b)
+            .count();
+        while !old.is_char_boundary(start) || !new.is_char_boundary(start) {
+            start -= 1;
+        }
+        let mut suffix = old[start..]
+            .bytes()
+            .rev()
+            .zip(new[start..].bytes().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        while !old.is_char_boundary(old.len() - suffix) {
+            suffix -= 1;
+        }
+        Self {
+            start,
+            old_end: old.len() - suffix,
+            new_end: new.len() - suffix,
+        }
+    }
+
+    /// Position in the new text. Positions inside the changed region are clamped to it.
+    pub fn adjust(&self, position: usize) -> usize {
+        if position <= self.start {
+            position
+        } else if position >= self.old_end {
+            position - self.old_end + self.new_end
+        } else {
+            position.min(self.new_end)
+        }
+    }
+
+    pub fn adjust_range(&self, range: ops::Range<usize>) -> ops::Range<usize> {
+        self.adjust(range.start)..self.adjust(range.end)
+    }
+}
+
+/// Fit the range inside the text, widening it to the nearest char boundaries.
+/// Empty ranges stay empty.
+pub fn snap_range(text: &str, range: ops::Range<usize>) -> ops::Range<usize> {
+    let end = range.end.min(text.len());
+    let mut start = range.start.min(end);
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    if range.start >= end {
+        return start..start;
+    }
+    let end = (end..=text.len())
+        .find(|i| text.is_char_boundary(*i))
+        .unwrap();
+    start..end
+}
+
+/// Identifier characters, which words in word motions consist of
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Extend the span to cover the words it starts and ends in
+pub fn word_bounds(text: &str, span: ops::Range<usize>) -> ops::Range<usize> {
+    let start = text[..span.start]
+        .rfind(|c| !is_word_char(c))
+        .map_or(0, |i| i + text[i..].chars().next().unwrap().len_utf8());
+    let end = text[span.end..]
+        .find(|c| !is_word_char(c))
+        .map_or(text.len(), |i| span.end + i);
+    start..end
+}
+
+/// Whether the position is between two word characters
+fn inside_word(text: &str, position: usize) -> bool {
+    text[..position].ends_with(is_word_char) && text[position..].starts_with(is_word_char)
+}
+
+/// First word that starts at or after the position, skipping the rest of a word it's in
+pub fn next_word(text: &str, position: usize) -> Option<ops::Range<usize>> {
+    let rest = if inside_word(text, position) {
+        word_bounds(text, position..position).end
+    } else {
+        position
+    };
+    let start = rest + text[rest..].find(is_word_char)?;
+    Some(word_bounds(text, start..start))
+}
+
+/// Last word that ends at or before the position, skipping the start of a word it's in
+pub fn prev_word(text: &str, position: usize) -> Option<ops::Range<usize>> {
+    let before = if inside_word(text, position) {
+        word_bounds(text, position..position).start
+    } else {
+        position
+    };
+    let end = text[..before].rfind(is_word_char)?;
+    Some(word_bounds(text, end..end))
+}
+
+/// Range to delete for removing the list element at the span along with one comma.
+/// The following comma is used unless the element is the last one, so that a trailing comma
+/// after the list is kept.
+pub fn list_item_range(text: &str, span: ops::Range<usize>) -> ops::Range<usize> {
+    let after = |i: usize| i + text[i..].len() - text[i..].trim_start().len();
+    let closer = |i: usize| text[i..].starts_with([')', ']', '}', '>']) || i == text.len();
+
+    let next = after(span.end);
+    let next_comma = text[next..].starts_with(',').then_some(next);
+    if let Some(comma) = next_comma {
+        if !closer(after(comma + 1)) {
+            return span.start..after(comma + 1);
+        }
+    }
+
+    let before = text[..span.start].trim_end();
+    if before.ends_with(',') {
+        match next_comma {
+            Some(comma) => before.len()..comma + 1,
+            None => before.len() - 1..span.end,
+        }
+    } else {
+        span.start..next_comma.map_or(span.end, |comma| comma + 1)
+    }
+}
+
+/// Extend the span to cover the full lines it touches, without the final newline
+pub fn line_bounds(text: &str, span: ops::Range<usize>) -> ops::Range<usize> {
+    let mut end = span.end;
+    if span.start < end && text[..end].ends_with('\n') {
+        end -= 1;
+    }
+    let start = text[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let end = text[end..].find('\n').map_or(text.len(), |i| end + i);
+    start..end.max(start)
+}
+
+fn indent(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// Prefix the lines with `// `, aligned to the smallest indentation. Blank lines are kept as-is.
+pub fn comment_lines(lines: &str) -> String {
+    let column = lines
+        .split('\n')
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| indent(line).len())
+        .min()
+        .unwrap_or(0);
+    lines
+        .split('\n')
+        .map(|line| {
+            if line.trim().is_empty() {
+                line.to_owned()
+            } else {
+                format!("{}// {}", &line[..column], &line[column..])
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Remove `//` and a following space from the lines that start with one.
+/// Returns `None` if none of the lines were commented out.
+pub fn uncomment_lines(lines: &str) -> Option<String> {
+    let mut changed = false;
+    let result = lines
+        .split('\n')
+        .map(|line| {
+            let indent = indent(line);
+            let rest = &line[indent.len()..];
+            match rest.strip_prefix("//") {
+                Some(rest) => {
+                    changed = true;
+                    format!("{}{}", indent, rest.strip_prefix(' ').unwrap_or(rest))
+                }
+                None => line.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    changed.then_some(result)
+}
+
+/// Register names have to be usable in `$reg:name` templates
+pub fn is_register_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Transformation applied to a template value, written after a pipe like `${top|upper}`
+enum Filter {
+    Upper,
+    Lower,
+    Trim,
+    SnakeCase,
+    CamelCase,
+    PascalCase,
+    KebabCase,
+    ScreamingSnakeCase,
+    RegexReplace(Regex, String),
+}
+
+impl Filter {
+    /// Parse a filter with its `:`-separated arguments, like `regex_replace:foo:bar`
+    fn parse(spec: &str) -> Result<Self, ExecError> {
+        let (name, args) = spec.split_once(':').unwrap_or((spec, ""));
+        Ok(match name.trim() {
+            "upper" => Self::Upper,
+            "lower" => Self::Lower,
+            "trim" => Self::Trim,
+            "snake_case" => Self::SnakeCase,
+            "camel_case" => Self::CamelCase,
+            "pascal_case" => Self::PascalCase,
+            "kebab_case" => Self::KebabCase,
+            "screaming_snake_case" => Self::ScreamingSnakeCase,
+            "regex_replace" => {
+                let (re, replacement) = args.split_once(':').unwrap_or((args, ""));
+                let re =
+                    Regex::new(re).map_err(|err| ExecError::InvalidRegex(re.to_owned(), err))?;
+                Self::RegexReplace(re, replacement.to_owned())
+            }
+            _ => return Err(ExecError::UnknownFilter(spec.to_owned())),
+        })
+    }
+
+    fn apply(&self, value: &str) -> String {
+        match self {
+            Self::Upper => value.to_uppercase(),
+            Self::Lower => value.to_lowercase(),
+            Self::Trim => value.trim().to_owned(),
+            Self::SnakeCase => value.to_snake_case(),
+            Self::CamelCase => value.to_lower_camel_case(),
+            Self::PascalCase => value.to_upper_camel_case(),
+            Self::KebabCase => value.to_kebab_case(),
+            Self::ScreamingSnakeCase => value.to_shouty_snake_case(),
+            Self::RegexReplace(re, replacement) => {
+                re.replace_all(value, replacement.as_str()).into_owned()
+            }
+        }
+    }
+}
+
+/// Split a braced template on `|`, keeping `\|` as a literal `|` in the part
+fn split_filters(value: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'|') => {
+                parts.last_mut().unwrap().push(chars.next().unwrap());
+            }
+            '|' => parts.push(String::new()),
+            _ => parts.last_mut().unwrap().push(c),
+        }
+    }
+    parts
+}
+
+/// Replaces templates in form `$name`, `$reg:name`, `$env:NAME` or `${name}`, using a resolver
+/// function. Other colons end an unbraced name, so `$pop:u8` is `$pop` followed by `:u8`.
 /// If resolver returns `Ok(None)`, the template is left as-is.
+/// Braced templates can pipe the value through filters, e.g. `${top|trim|snake_case}`.
+/// A `\|` in a filter argument is a literal `|`, and `${cmd:...}` takes everything up to the
+/// closing brace as the command, so its pipes go to the shell.
+/// Unknown filters are errors even if the template is left as-is.
 pub fn template<F>(template: &str, mut resolver: F) -> Result<String, ExecError>
 where
     F: FnMut(&str) -> Result<Option<String>, ExecError>,
 {
-    let re = Regex::new(r"\$([A-Za-z][A-Za-z0-9_]*)|\$\{([^\}]+)\}").unwrap();
+    let re =
+        Regex::new(r"\$((?:reg|env):[A-Za-z0-9_]+|[A-Za-z][A-Za-z0-9_]*)|\$\{([^\}]+)\}").unwrap();
     let mut replacements = Vec::new();
-    for m in re.captures_iter(&template) {
+    for m in re.captures_iter(template) {
         let value = m.get(1).or(m.get(2)).unwrap().as_str();
-        if let Some(replacement) = resolver(&value)? {
+        let (name, filters) = if value.starts_with("cmd:") {
+            (value.to_owned(), Vec::new())
+        } else {
+            let mut parts = split_filters(value).into_iter();
+            let name = parts.next().unwrap();
+            let filters = parts
+                .map(|part| Filter::parse(&part))
+                .collect::<Result<Vec<_>, _>>()?;
+            (name, filters)
+        };
+        if let Some(mut replacement) = resolver(&name)? {
+            for filter in &filters {
+                replacement = filter.apply(&replacement);
+            }
             replacements.push((m.get(0).unwrap().range(), replacement));
         }
     }
@@ -102,12 +467,13 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::{
-        operation::{ExecError, TextOperation},
-        text::template,
-    };
+    use crate::{operation::ExecError, text::template};
 
-    use super::find_matching_paren;
+    use super::{
+        comment_lines, edit_distance, find_matching_paren, line_bounds, list_item_range,
+        move_chars, next_word, prev_word, quote_word, snap_range, split_words, uncomment_lines,
+        word_bounds,
+    };
 
     #[test]
     fn test_find_matching_paren() {
@@ -158,5 +524,126 @@ mod tests {
             "$3$$".to_owned()
         );
         assert_eq!(template("${b2}", increment_a).unwrap(), "${b2}".to_owned());
+        assert_eq!(template("$a1:u8", increment_a).unwrap(), "2:u8".to_owned());
+
+        let prefixed = |name: &str| Ok(name.split_once(':').map(|(_, name)| name.to_owned()));
+        assert_eq!(template("$reg:x.$env:Y", prefixed).unwrap(), "x.Y");
+
+        let top = |name: &str| Ok((name == "top").then(|| " fooBar ".to_owned()));
+        assert_eq!(template("${top|trim|upper}", top).unwrap(), "FOOBAR");
+        assert_eq!(template("${top|snake_case}", top).unwrap(), "foo_bar");
+        assert_eq!(template("${top|pascal_case}!", top).unwrap(), "FooBar!");
+        assert_eq!(
+            template("${top|regex_replace:o+:0}", top).unwrap(),
+            " f0Bar "
+        );
+        assert_eq!(
+            template(r"${top|regex_replace:f\|B:_}", top).unwrap(),
+            " _oo_ar "
+        );
+        assert_eq!(template("${x|lower}", top).unwrap(), "${x|lower}");
+        let cmd = |name: &str| Ok(name.strip_prefix("cmd:").map(str::to_owned));
+        assert_eq!(template("${cmd:a | b}", cmd).unwrap(), "a | b");
+        assert!(matches!(
+            template("${x|nope}", top),
+            Err(ExecError::UnknownFilter(_))
+        ));
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("next", "next"), 0);
+        assert_eq!(edit_distance("nxt", "next"), 1);
+        assert_eq!(edit_distance("delte", "delete"), 1);
+        assert_eq!(edit_distance("", "zero"), 4);
+        assert_eq!(edit_distance("first", "fst"), 2);
+    }
+
+    #[test]
+    fn test_split_words() {
+        let words = |line: &str| split_words(line).unwrap();
+        assert_eq!(words("  next  '\\w+' "), ["next", "\\w+"]);
+        assert_eq!(
+            words(r#"replace "a \"b\"" x\ y"#),
+            ["replace", "a \"b\"", "x y"]
+        );
+        assert_eq!(words("replace ''"), ["replace", ""]);
+        assert_eq!(split_words("next '("), None);
+
+        for word in ["", "it's", "$pop", "a b", "\\d+"] {
+            assert_eq!(words(&quote_word(word)), [word]);
+        }
+    }
+
+    #[test]
+    fn test_comment_lines() {
+        let text = "fn f() {\n    let x = 1;\n\n        y();\n}\n";
+        let lines = line_bounds(text, 14..30);
+        assert_eq!(&text[lines.clone()], "    let x = 1;\n\n        y();");
+        let commented = comment_lines(&text[lines]);
+        assert_eq!(commented, "    // let x = 1;\n\n    //     y();");
+        assert_eq!(
+            uncomment_lines(&commented).unwrap(),
+            "    let x = 1;\n\n        y();"
+        );
+        assert_eq!(uncomment_lines("    let x = 1;"), None);
+        assert_eq!(line_bounds(text, 9..24), 9..23);
+    }
+
+    #[test]
+    fn test_list_item_range() {
+        let delete = |text: &str, item: &str| {
+            let start = text.find(item).unwrap();
+            let mut text = text.to_owned();
+            text.replace_range(list_item_range(&text, start..start + item.len()), "");
+            text
+        };
+        assert_eq!(delete("f(a, b, c)", "a"), "f(b, c)");
+        assert_eq!(delete("f(a, b, c)", "b"), "f(a, c)");
+        assert_eq!(delete("f(a, b, c)", "c"), "f(a, b)");
+        assert_eq!(delete("f(a)", "a"), "f()");
+        assert_eq!(delete("f(a,)", "a"), "f()");
+        assert_eq!(
+            delete("#[derive(Debug, Clone)]", "Clone"),
+            "#[derive(Debug)]"
+        );
+        assert_eq!(delete("use x::{a, b};", "a"), "use x::{b};");
+        assert_eq!(delete("[\n    a,\n    b,\n]", "b"), "[\n    a,\n]");
+        assert_eq!(delete("[\n    a,\n    b,\n]", "a"), "[\n    b,\n]");
+    }
+
+    #[test]
+    fn test_words() {
+        let text = "let r#type = foo_bar(x, ölmö);";
+        let word = |range: Option<std::ops::Range<usize>>| &text[range.unwrap()];
+        assert_eq!(&text[word_bounds(text, 15..15)], "foo_bar");
+        assert_eq!(&text[word_bounds(text, 14..18)], "foo_bar");
+        assert_eq!(&text[word_bounds(text, 12..12)], "");
+        assert_eq!(word(next_word(text, 15)), "x");
+        assert_eq!(word(next_word(text, 21)), "x");
+        assert_eq!(word(next_word(text, 22)), "ölmö");
+        assert_eq!(next_word(text, 28), None);
+        assert_eq!(word(prev_word(text, 15)), "type");
+        assert_eq!(word(prev_word(text, 4)), "let");
+        assert_eq!(prev_word(text, 2), None);
+    }
+
+    #[test]
+    fn test_move_chars() {
+        let text = "aöb";
+        assert_eq!(move_chars(text, 0, 2), 3);
+        assert_eq!(move_chars(text, 0, 9), 4);
+        assert_eq!(move_chars(text, 3, -1), 1);
+        assert_eq!(move_chars(text, 3, -9), 0);
+        assert_eq!(move_chars(text, 1, 0), 1);
+    }
+
+    #[test]
+    fn test_snap_range() {
+        let text = "aéb";
+        assert_eq!(snap_range(text, 2..2), 1..1);
+        assert_eq!(snap_range(text, 0..2), 0..3);
+        assert_eq!(snap_range(text, 2..9), 1..4);
+        assert_eq!(snap_range(text, 7..9), 4..4);
     }
 }
@@ -0,0 +1,102 @@
+//! Finding syntax nodes around a span with tree-sitter, which tolerates syntax errors
+
+use std::ops;
+
+use streaming_iterator::StreamingIterator;
+use tree_sitter::{Language, Parser, Query, QueryCursor, QueryError, Tree};
+
+fn language() -> Language {
+    tree_sitter_rust::LANGUAGE.into()
+}
+
+fn parse(source: &str) -> Tree {
+    let mut parser = Parser::new();
+    parser
+        .set_language(&language())
+        .expect("Incompatible tree-sitter-rust version");
+    parser.parse(source, None).expect("Parsing was cancelled")
+}
+
+/// Whether the grammar has named nodes of the kind, e.g. `call_expression`
+pub fn is_node_kind(kind: &str) -> bool {
+    language().id_for_node_kind(kind, true) != 0
+}
+
+/// Compile a query, checking its syntax
+pub fn query(source: &str) -> Result<Query, QueryError> {
+    Query::new(&language(), source)
+}
+
+/// Byte range of the nearest node of the kind enclosing the range
+pub fn enclosing_node(
+    source: &str,
+    range: ops::Range<usize>,
+    kind: &str,
+) -> Option<ops::Range<usize>> {
+    let tree = parse(source);
+    let mut node = tree
+        .root_node()
+        .descendant_for_byte_range(range.start, range.end)?;
+    loop {
+        if node.kind() == kind {
+            return Some(node.byte_range());
+        }
+        node = node.parent()?;
+    }
+}
+
+/// Byte range of the first capture of the query inside the range
+pub fn first_capture(
+    source: &str,
+    range: ops::Range<usize>,
+    query: &Query,
+) -> Option<ops::Range<usize>> {
+    let tree = parse(source);
+    let mut cursor = QueryCursor::new();
+    cursor.set_byte_range(range.clone());
+    let mut captures = cursor.captures(query, tree.root_node(), source.as_bytes());
+    while let Some((found, index)) = captures.next() {
+        let capture = found.captures[*index].node.byte_range();
+        // Matches only need to intersect the range
+        if range.start <= capture.start && capture.end <= range.end {
+            return Some(capture);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{enclosing_node, first_capture, is_node_kind, query};
+
+    #[test]
+    fn test_tree_sitter() {
+        let source = "fn f() {\n    let v = g(h(1), 2);\n}\n";
+        let at = |needle: &str| {
+            let start = source.find(needle).unwrap();
+            start..start + needle.len()
+        };
+        let text = |range: Option<std::ops::Range<usize>>| range.map(|range| &source[range]);
+
+        assert_eq!(
+            text(enclosing_node(source, at("1"), "call_expression")),
+            Some("h(1)")
+        );
+        assert_eq!(
+            text(enclosing_node(source, at("h"), "let_declaration")),
+            Some("let v = g(h(1), 2);")
+        );
+        assert_eq!(text(enclosing_node(source, at("1"), "struct_item")), None);
+
+        let calls = query("(call_expression function: (identifier) @name)").unwrap();
+        assert_eq!(
+            text(first_capture(source, at("g(h(1), 2)"), &calls)),
+            Some("g")
+        );
+        assert_eq!(text(first_capture(source, at("h(1)"), &calls)), Some("h"));
+
+        assert!(is_node_kind("call_expression"));
+        assert!(!is_node_kind("call_expresion"));
+        assert!(query("(call_expression").is_err());
+    }
+}
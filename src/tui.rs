@@ -0,0 +1,235 @@
+//! Full-screen review of proposed changes
+
+use std::io;
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span, Text},
+    widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+use similar::{ChangeTag, TextDiff};
+
+use crate::{apply::Change, interactive, operation::SpanChange};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Decision {
+    Pending,
+    Accepted,
+    Rejected,
+}
+
+/// A proposed change under review
+#[derive(Debug, Clone)]
+pub struct Item {
+    /// Code of the diagnostic
+    pub code: String,
+    /// Short description of the diagnostic
+    pub title: String,
+    pub change: SpanChange,
+    decision: Decision,
+}
+
+impl Item {
+    pub fn new(code: String, title: String, change: SpanChange) -> Self {
+        Self {
+            code,
+            title,
+            change,
+            decision: Decision::Pending,
+        }
+    }
+
+    fn replacement(&self) -> String {
+        String::from_utf8_lossy(&self.change.change.patch.bytes).into_owned()
+    }
+}
+
+struct Review {
+    items: Vec<Item>,
+    state: ListState,
+}
+
+impl Review {
+    fn selected(&mut self) -> Option<&mut Item> {
+        self.state.selected().map(|i| &mut self.items[i])
+    }
+
+    fn decide(&mut self, decision: Decision) {
+        if let Some(item) = self.selected() {
+            item.decision = decision;
+        }
+        self.state.select_next();
+    }
+
+    /// Reject all pending items in the same file as the selected one
+    fn skip_file(&mut self) {
+        let Some(index) = self.state.selected() else {
+            return;
+        };
+        let file = self.items[index].change.change.file.clone();
+        for item in &mut self.items {
+            if item.change.change.file == file && item.decision == Decision::Pending {
+                item.decision = Decision::Rejected;
+            }
+        }
+        self.state.select_next();
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [list_area, diff_area, help_area] = Layout::vertical([
+            Constraint::Percentage(40),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let list_items: Vec<ListItem> = self
+            .items
+            .iter()
+            .map(|item| {
+                let (mark, color) = match item.decision {
+                    Decision::Pending => ("[ ]", Color::Reset),
+                    Decision::Accepted => ("[+]", Color::Green),
+                    Decision::Rejected => ("[-]", Color::Red),
+                };
+                let span = &item.change.span;
+                ListItem::new(Line::from(vec![
+                    Span::styled(mark, Style::default().fg(color)),
+                    Span::raw(format!(
+                        " {}:{}: {}",
+                        span.file_name, span.line_start, item.title
+                    )),
+                ]))
+            })
+            .collect();
+        let list = List::new(list_items)
+            .block(Block::bordered().title(" Proposed changes "))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, list_area, &mut self.state);
+
+        let diff = match self.state.selected() {
+            Some(index) => {
+                let item = &self.items[index];
+                diff_text(&item.change.span.raw_text(), &item.replacement())
+            }
+            None => Text::default(),
+        };
+        frame.render_widget(
+            Paragraph::new(diff)
+                .block(Block::bordered().title(" Diff "))
+                .wrap(Wrap { trim: false }),
+            diff_area,
+        );
+
+        frame.render_widget(
+            Line::from(
+                "j/k: move  a: accept  r: reject  s: skip file  e: edit  o: open in editor  A: accept all  q: done  esc: cancel",
+            )
+            .dim(),
+            help_area,
+        );
+    }
+}
+
+/// Old and new text with changed characters highlighted
+fn diff_text(old: &str, new: &str) -> Text<'static> {
+    let diff = TextDiff::from_graphemes(old, new);
+    let mut before = vec![Span::raw("-").red()];
+    let mut after = vec![Span::raw("+").green()];
+    for change in diff.iter_all_changes() {
+        let value = change.value().to_owned();
+        match change.tag() {
+            ChangeTag::Equal => {
+                before.push(Span::raw(value.clone()));
+                after.push(Span::raw(value));
+            }
+            ChangeTag::Delete => before.push(Span::raw(value).white().on_red()),
+            ChangeTag::Insert => after.push(Span::raw(value).black().on_green()),
+        }
+    }
+    Text::from(vec![Line::from(before), Line::from(after)])
+}
+
+fn run(terminal: &mut DefaultTerminal, review: &mut Review) -> io::Result<bool> {
+    loop {
+        terminal.draw(|frame| review.draw(frame))?;
+
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+
+        match key.code {
+            KeyCode::Char('j') | KeyCode::Down => review.state.select_next(),
+            KeyCode::Char('k') | KeyCode::Up => review.state.select_previous(),
+            KeyCode::Char('a') => review.decide(Decision::Accepted),
+            KeyCode::Char('r') => review.decide(Decision::Rejected),
+            KeyCode::Char('s') => review.skip_file(),
+            KeyCode::Char('o') => {
+                if let Some(item) = review.selected() {
+                    // The patch would be stale after editing the file by hand
+                    item.decision = Decision::Rejected;
+                    let span = item.change.span.clone();
+                    ratatui::restore();
+                    let result = interactive::open_in_editor(
+                        &span.file_name,
+                        span.line_start,
+                        span.column_start,
+                    );
+                    *terminal = ratatui::init();
+                    result?;
+                }
+            }
+            KeyCode::Char('e') => {
+                if let Some(item) = review.selected() {
+                    ratatui::restore();
+                    let result = interactive::edit_text(&item.replacement());
+                    *terminal = ratatui::init();
+                    item.change.change.patch.bytes = result?.into_bytes();
+                    review.decide(Decision::Accepted);
+                }
+            }
+            KeyCode::Char('A') => {
+                for item in &mut review.items {
+                    if item.decision == Decision::Pending {
+                        item.decision = Decision::Accepted;
+                    }
+                }
+            }
+            KeyCode::Char('q') => return Ok(true),
+            KeyCode::Esc => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+/// Let the user review the changes, returning the accepted ones with their codes
+pub fn review(items: Vec<Item>) -> io::Result<Vec<(String, Change)>> {
+    if items.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut review = Review {
+        items,
+        state: ListState::default().with_selected(Some(0)),
+    };
+
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, &mut review);
+    ratatui::restore();
+
+    if !result? {
+        return Ok(Vec::new());
+    }
+    Ok(review
+        .items
+        .into_iter()
+        .filter(|item| item.decision == Decision::Accepted)
+        .map(|item| (item.code, item.change.change))
+        .collect())
+}
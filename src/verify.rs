@@ -0,0 +1,70 @@
+//! Checking that the written changes don't break the build
+
+use std::{
+    collections::HashMap,
+    io,
+    process::{Command, ExitStatus},
+};
+
+use crate::message::{CompilerMessage, Msg};
+
+/// Error diagnostics in cargo or rustc JSON output
+fn errors(output: &[u8]) -> Vec<CompilerMessage> {
+    output
+        .split(|c| *c == b'\n')
+        .filter(|line| !line.trim_ascii().is_empty())
+        .filter_map(|line| Msg::parse(line).ok())
+        .filter_map(|msg| msg.message)
+        // Summaries like "aborting due to 2 previous errors" have no spans
+        .filter(|message| message.level == "error" && !message.spans.is_empty())
+        .collect()
+}
+
+/// Errors present after the changes that weren't present before.
+/// Errors are compared by code and message, as their locations move when files change.
+pub fn new_errors(before: &[u8], after: &[u8]) -> Vec<CompilerMessage> {
+    let mut known: HashMap<(Option<String>, String), usize> = HashMap::new();
+    for error in errors(before) {
+        let key = (error.code().map(str::to_owned), error.message);
+        *known.entry(key).or_default() += 1;
+    }
+
+    errors(after)
+        .into_iter()
+        .filter(|error| {
+            let key = (error.code().map(str::to_owned), error.message.clone());
+            match known.get_mut(&key) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    false
+                }
+                _ => true,
+            }
+        })
+        .collect()
+}
+
+/// Run a command through the shell
+pub fn run_shell(command: &str) -> io::Result<ExitStatus> {
+    if cfg!(windows) {
+        Command::new("cmd").arg("/C").arg(command).status()
+    } else {
+        Command::new("sh").arg("-c").arg(command).status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::new_errors;
+
+    const ERROR: &str = r#"{"$message_type":"diagnostic","message":"mismatched types","code":{"code":"E0308","explanation":null},"level":"error","spans":[{"file_name":"src/main.rs","byte_start":4,"byte_end":5,"line_start":1,"line_end":1,"column_start":5,"column_end":6,"is_primary":true,"text":[],"label":null,"suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[],"rendered":""}"#;
+    const ABORTING: &str = r#"{"$message_type":"diagnostic","message":"aborting due to 1 previous error","code":null,"level":"error","spans":[],"children":[],"rendered":""}"#;
+
+    #[test]
+    fn test_new_errors() {
+        let before = format!("{}\n", ERROR);
+        let after = format!("{}\n{}\n{}\n", ERROR, ERROR, ABORTING);
+        assert!(new_errors(after.as_bytes(), before.as_bytes()).is_empty());
+        assert_eq!(new_errors(before.as_bytes(), after.as_bytes()).len(), 1);
+    }
+}
@@ -0,0 +1,56 @@
+//! Running again whenever the source files change
+
+use std::{
+    env,
+    path::{Component, Path},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::journal;
+
+/// Time to wait for more changes after the first one, so saving many files runs only once
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Run `f`, and again whenever files under the current directory change
+pub fn watch(mut f: impl FnMut()) -> notify::Result<()> {
+    let root = env::current_dir()?;
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+
+    loop {
+        f();
+
+        // Changes written by the run itself don't need another run
+        thread::sleep(DEBOUNCE);
+        while rx.try_recv().is_ok() {}
+
+        println!("watching for changes...");
+        for event in &rx {
+            if is_relevant(&root, &event?) {
+                break;
+            }
+        }
+        thread::sleep(DEBOUNCE);
+        while rx.try_recv().is_ok() {}
+    }
+}
+
+/// Whether the event changes the sources, instead of build output, git metadata or refix's own files
+fn is_relevant(root: &Path, event: &Event) -> bool {
+    if matches!(event.kind, EventKind::Access(_)) {
+        return false;
+    }
+    event.paths.iter().any(|path| {
+        let path = path.strip_prefix(root).unwrap_or(path);
+        !matches!(
+            path.components().next(),
+            Some(Component::Normal(name))
+                if name == "target" || name == ".git" || name == journal::REFIX_DIR
+        )
+    })
+}